@@ -0,0 +1,69 @@
+use std::{f32::consts::FRAC_PI_2, time::Duration};
+
+/// Shape of the gain ramps used when two tracks overlap.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FadeCurve {
+    Linear,
+    EqualPower,
+    Logarithmic,
+}
+
+impl FadeCurve {
+    /// Gain of a fade-in after `progress` (0..=1) of its length. A fade-out
+    /// uses the same curve mirrored.
+    pub fn fade_in_gain(self, progress: f32) -> f32 {
+        let x = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => x,
+            FadeCurve::EqualPower => (x * FRAC_PI_2).sin(),
+            // Linear in decibels over a 60 dB range, snapped to silence at the start.
+            FadeCurve::Logarithmic if x == 0.0 => 0.0,
+            FadeCurve::Logarithmic => 10f32.powf(3.0 * (x - 1.0)),
+        }
+    }
+
+    pub fn fade_out_gain(self, progress: f32) -> f32 {
+        self.fade_in_gain(1.0 - progress)
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            FadeCurve::Linear => 0,
+            FadeCurve::EqualPower => 1,
+            FadeCurve::Logarithmic => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => FadeCurve::Linear,
+            2 => FadeCurve::Logarithmic,
+            _ => FadeCurve::EqualPower,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrossfadeSettings {
+    pub enabled: bool,
+    pub duration_ms: u64,
+    pub curve: FadeCurve,
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            duration_ms: 6000,
+            curve: FadeCurve::EqualPower,
+        }
+    }
+}
+
+impl CrossfadeSettings {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
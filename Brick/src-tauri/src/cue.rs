@@ -0,0 +1,419 @@
+//! CUE sheets splitting a single-file album image into virtual tracks. A
+//! virtual track is addressed as `<file>#t=<start>,<end>` (seconds, as in
+//! media fragment URIs) and plays only that range of the file.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// CUE timestamps count frames of 1/75 s, as on a CD.
+const FRAMES_PER_SECOND: u64 = 75;
+const FRAGMENT: &str = "#t=";
+
+/// Range of a file played as a track of its own. Without an end it runs to
+/// the end of the file.
+#[derive(Clone, Copy, PartialEq)]
+pub struct TrackRange {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+impl TrackRange {
+    /// Length of the range in a file lasting `total`.
+    pub fn length(&self, total: Option<Duration>) -> Option<Duration> {
+        Some(self.end.or(total)?.saturating_sub(self.start))
+    }
+}
+
+/// Splits `<file>#t=<start>,<end>` into the file and the range. Paths without
+/// a fragment are returned whole.
+pub fn split_range(file_path: &str) -> (&str, Option<TrackRange>) {
+    match file_path.rsplit_once(FRAGMENT) {
+        Some((base, fragment)) => match parse_range(fragment) {
+            Some(range) => (base, Some(range)),
+            None => (file_path, None),
+        },
+        None => (file_path, None),
+    }
+}
+
+fn parse_range(fragment: &str) -> Option<TrackRange> {
+    let parse = |value: &str| {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(Duration::from_secs_f64)
+    };
+    let (start, end) = fragment.split_once(',').unwrap_or((fragment, ""));
+    let start = parse(start)?;
+    let end = match end {
+        "" => None,
+        end => Some(parse(end).filter(|end| *end > start)?),
+    };
+    Some(TrackRange { start, end })
+}
+
+/// Path of the virtual track playing `range` of `file_path`.
+pub fn range_path(file_path: &str, range: TrackRange) -> String {
+    match range.end {
+        Some(end) => format!(
+            "{}{}{:.6},{:.6}",
+            file_path,
+            FRAGMENT,
+            range.start.as_secs_f64(),
+            end.as_secs_f64()
+        ),
+        None => format!("{}{}{:.6}", file_path, FRAGMENT, range.start.as_secs_f64()),
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Clone, Default)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Clone, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Position of `INDEX 01`; the pregap before it belongs to the track before.
+    pub start: Duration,
+}
+
+/// A track of a cue sheet resolved against its audio file.
+pub struct VirtualTrack {
+    /// Path of the virtual track, see `range_path`.
+    pub file_path: String,
+    pub range: TrackRange,
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub album: Option<String>,
+}
+
+impl CueSheet {
+    /// Parses the commands Brick uses; everything else, `REM` included, is
+    /// skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut sheet = CueSheet::default();
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => sheet.files.push(CueFile {
+                    name: file_name(rest),
+                    tracks: Vec::new(),
+                }),
+                "TRACK" => {
+                    let file = sheet
+                        .files
+                        .last_mut()
+                        .ok_or_else(|| "Cue sheet error: TRACK before FILE".to_string())?;
+                    let number = rest
+                        .split_whitespace()
+                        .next()
+                        .and_then(|number| number.parse().ok())
+                        .ok_or_else(|| format!("Cue sheet error: bad track number in {}", line))?;
+                    file.tracks.push(CueTrack {
+                        number,
+                        ..CueTrack::default()
+                    });
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(unquote(rest).to_string()).filter(|value| !value.is_empty());
+                    let track = sheet.files.last_mut().and_then(|file| file.tracks.last_mut());
+                    let field = match (track, command.eq_ignore_ascii_case("TITLE")) {
+                        (Some(track), true) => &mut track.title,
+                        (Some(track), false) => &mut track.performer,
+                        (None, true) => &mut sheet.title,
+                        (None, false) => &mut sheet.performer,
+                    };
+                    *field = value;
+                }
+                "INDEX" => {
+                    let mut parts = rest.split_whitespace();
+                    if parts.next().and_then(|index| index.parse::<u32>().ok()) != Some(1) {
+                        continue;
+                    }
+                    let start = parts
+                        .next()
+                        .and_then(parse_timestamp)
+                        .ok_or_else(|| format!("Cue sheet error: bad timestamp in {}", line))?;
+                    if let Some(track) = sheet.files.last_mut().and_then(|file| file.tracks.last_mut()) {
+                        track.start = start;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if sheet.files.iter().all(|file| file.tracks.is_empty()) {
+            return Err("Cue sheet error: no tracks".to_string());
+        }
+        Ok(sheet)
+    }
+
+    /// The virtual tracks of the audio file at `file_path`. An embedded sheet
+    /// describes its own file whatever name it gives it. A file holding one
+    /// whole track, as in per-track rips with one `FILE` per track, has none
+    /// and plays as it is.
+    pub fn tracks_of(&self, file_path: &str, embedded: bool) -> Vec<VirtualTrack> {
+        let name = Path::new(file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file = if embedded && self.files.len() == 1 {
+            self.files.first()
+        } else {
+            self.files.iter().find(|file| same_file_name(&file.name, &name))
+        };
+        let Some(file) = file else {
+            return Vec::new();
+        };
+        if let [track] = &file.tracks[..] {
+            if track.start.is_zero() {
+                return Vec::new();
+            }
+        }
+
+        file.tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| {
+                let range = TrackRange {
+                    start: track.start,
+                    end: file.tracks.get(index + 1).map(|next| next.start),
+                };
+                VirtualTrack {
+                    file_path: range_path(file_path, range),
+                    range,
+                    number: track.number,
+                    title: track.title.clone(),
+                    performer: track.performer.clone().or_else(|| self.performer.clone()),
+                    album: self.title.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The virtual tracks of `file_path`, from the sheet embedded in its tags or
+/// else from one next to it. Empty for files without a sheet.
+pub fn tracks_for(file_path: &str, embedded: Option<&str>) -> Vec<VirtualTrack> {
+    if let Some(sheet) = embedded.and_then(|text| CueSheet::parse(text).ok()) {
+        let tracks = sheet.tracks_of(file_path, true);
+        if !tracks.is_empty() {
+            return tracks;
+        }
+    }
+    find_external(file_path).map_or_else(Vec::new, |sheet| sheet.tracks_of(file_path, false))
+}
+
+/// Reads a cue sheet next to `file_path` describing it: `<name>.cue`,
+/// `<stem>.cue`, or any sheet in the folder whose `FILE` names it.
+pub fn find_external(file_path: &str) -> Option<CueSheet> {
+    let path = Path::new(file_path);
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let folder = path.parent()?;
+
+    let mut candidates: Vec<PathBuf> = vec![
+        folder.join(format!("{}.cue", name)),
+        path.with_extension("cue"),
+    ];
+    if let Ok(entries) = std::fs::read_dir(folder) {
+        candidates.extend(
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|candidate| {
+                    candidate
+                        .extension()
+                        .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+                }),
+        );
+    }
+
+    candidates
+        .iter()
+        .filter_map(|candidate| std::fs::read(candidate).ok())
+        .filter_map(|bytes| CueSheet::parse(&decode_text(&bytes)).ok())
+        .find(|sheet| sheet.files.iter().any(|file| same_file_name(&file.name, &name)))
+}
+
+/// Cue sheets predate Unicode; those that aren't UTF-8 are read as Latin-1.
+pub fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+/// `FILE "name.flac" WAVE` gives `name.flac`.
+fn file_name(rest: &str) -> String {
+    let name = if let Some(quoted) = rest.strip_prefix('"') {
+        quoted.split('"').next().unwrap_or_default()
+    } else {
+        rest.rsplit_once(char::is_whitespace).map_or(rest, |(name, _)| name)
+    };
+    // Sheets written on Windows may carry a relative path with backslashes.
+    name.rsplit(['/', '\\']).next().unwrap_or(name).to_string()
+}
+
+/// Sheets often still name the WAV the image was ripped to, so the extension
+/// is ignored when matching.
+fn same_file_name(cue_name: &str, file_name: &str) -> bool {
+    let stem = |name: &str| {
+        Path::new(name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase())
+    };
+    cue_name.eq_ignore_ascii_case(file_name) || (stem(cue_name).is_some() && stem(cue_name) == stem(file_name))
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Parses `mm:ss:ff`.
+fn parse_timestamp(value: &str) -> Option<Duration> {
+    let mut parts = value.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        return None;
+    }
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Some(Duration::from_nanos(frames * 1_000_000_000 / FRAMES_PER_SECOND))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Jazz
+PERFORMER \"The Band\"
+TITLE \"Live Album\"
+FILE \"C:\\Rips\\live.wav\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"Opening\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:58:70
+    INDEX 01 04:00:15
+  TRACK 03 AUDIO
+    TITLE \"\"
+    INDEX 01 09:30:74
+";
+
+    #[test]
+    fn parses_tracks_and_metadata() {
+        let sheet = CueSheet::parse(SHEET).unwrap();
+
+        assert_eq!(sheet.title.as_deref(), Some("Live Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.files.len(), 1);
+        let file = &sheet.files[0];
+        assert_eq!(file.name, "live.wav");
+        assert_eq!(file.tracks.len(), 3);
+        assert_eq!(file.tracks[1].title.as_deref(), Some("Second"));
+        assert_eq!(file.tracks[1].performer.as_deref(), Some("Guest"));
+        // The pregap of INDEX 00 belongs to the track before.
+        assert_eq!(file.tracks[1].start, Duration::from_millis(240_200));
+        assert_eq!(file.tracks[2].title, None);
+        assert_eq!(file.tracks[2].start.as_nanos(), 570_986_666_666);
+    }
+
+    #[test]
+    fn rejects_broken_sheets() {
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+        assert!(CueSheet::parse("FILE \"a.flac\" WAVE\nTRACK xx AUDIO").is_err());
+        let bad_index = "FILE \"a.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:61:00";
+        assert!(CueSheet::parse(bad_index).is_err());
+        assert!(CueSheet::parse("FILE \"a.flac\" WAVE").is_err());
+    }
+
+    #[test]
+    fn tracks_cover_the_file_without_gaps() {
+        let sheet = CueSheet::parse(SHEET).unwrap();
+        let tracks = sheet.tracks_of("/music/live.flac", false);
+
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(tracks[0].range.end, Some(tracks[1].range.start));
+        assert_eq!(tracks[1].range.end, Some(tracks[2].range.start));
+        assert_eq!(tracks[2].range.end, None);
+        assert_eq!(tracks[0].performer.as_deref(), Some("The Band"));
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+        assert_eq!(tracks[2].album.as_deref(), Some("Live Album"));
+        // Another file isn't described by the sheet unless it is embedded.
+        assert!(sheet.tracks_of("/music/other.flac", false).is_empty());
+        assert_eq!(sheet.tracks_of("/music/other.flac", true).len(), 3);
+    }
+
+    #[test]
+    fn per_track_files_stay_whole() {
+        let sheet = CueSheet::parse(
+            "FILE \"01 Intro.flac\" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+FILE \"02 Hidden.flac\" WAVE
+  TRACK 02 AUDIO
+    INDEX 01 00:00:00
+  TRACK 03 AUDIO
+    INDEX 01 02:00:00
+FILE \"04 Pregap.flac\" WAVE
+  TRACK 04 AUDIO
+    INDEX 01 00:01:00
+",
+        )
+        .unwrap();
+
+        assert!(sheet.tracks_of("/music/01 Intro.flac", false).is_empty());
+        assert_eq!(sheet.tracks_of("/music/02 Hidden.flac", false).len(), 2);
+        // A track starting late still skips the start of its file.
+        assert_eq!(sheet.tracks_of("/music/04 Pregap.flac", false).len(), 1);
+    }
+
+    #[test]
+    fn range_paths_round_trip() {
+        let range = TrackRange {
+            start: Duration::from_millis(240_200),
+            end: Some(Duration::from_millis(570_990)),
+        };
+        let path = range_path("/music/live #1.flac", range);
+
+        assert_eq!(path, "/music/live #1.flac#t=240.200000,570.990000");
+        assert!(split_range(&path) == ("/music/live #1.flac", Some(range)));
+        let open_ended = TrackRange { start: range.start, end: None };
+        assert!(split_range(&range_path("a.flac", open_ended)) == ("a.flac", Some(open_ended)));
+    }
+
+    #[test]
+    fn paths_without_a_valid_range_stay_whole() {
+        let paths = ["/music/a.flac", "/music/a.flac#t=", "/music/a.flac#t=5,2", "/music/a.flac#t=-1"];
+        for path in paths {
+            assert!(split_range(path) == (path, None), "{}", path);
+        }
+    }
+
+    #[test]
+    fn latin1_sheets_are_decoded() {
+        assert_eq!(decode_text(b"TITLE \"Caf\xe9\""), "TITLE \"Café\"");
+        assert_eq!(decode_text("TITLE \"Café\"".as_bytes()), "TITLE \"Café\"");
+    }
+}
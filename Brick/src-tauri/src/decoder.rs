@@ -0,0 +1,476 @@
+use rodio::Source;
+use std::{
+    fs::File,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use symphonia::{
+    core::{
+        audio::{AudioBufferRef, SampleBuffer, SignalSpec},
+        codecs::{CodecRegistry, Decoder, DecoderOptions},
+        errors::Error,
+        formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+        io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
+        meta::MetadataOptions,
+        probe::Hint,
+        units::{self, Time, TimeBase},
+    },
+    default::{get_probe, register_enabled_codecs},
+};
+use symphonia_adapter_libopus::OpusDecoder;
+
+use crate::{
+    cue::{self, TrackRange},
+    stream::{self, HttpReader, StreamStatus},
+    track::Seekable,
+};
+
+/// What the file holds, before any conversion for the output.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceFormat {
+    /// Short name of the codec, e.g. `flac` or `mp3`.
+    pub codec: String,
+    pub sample_rate: u32,
+    /// Bits per sample of lossless codecs; lossy codecs have none.
+    pub bits_per_sample: Option<u32>,
+    pub channels: u16,
+}
+
+/// Containers the decoder reads, by file extension.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "flac", "mp3", "m4a", "m4b", "mp4", "aac", "ogg", "oga", "opus", "wav", "wave", "aif", "aiff",
+    "aifc", "caf", "mka", "mkv", "webm",
+];
+/// Codecs the decoder handles. AAC is limited to the LC profile.
+pub const SUPPORTED_CODECS: &[&str] = &[
+    "flac", "alac", "aac", "opus", "vorbis", "mp3", "mp2", "mp1", "pcm", "adpcm",
+];
+
+/// Payload of `get_supported_formats`.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedFormats {
+    /// Lowercase file extensions, without the dot.
+    pub extensions: Vec<String>,
+    pub codecs: Vec<String>,
+}
+
+pub fn supported_formats() -> SupportedFormats {
+    SupportedFormats {
+        extensions: SUPPORTED_EXTENSIONS.iter().map(|s| s.to_string()).collect(),
+        codecs: SUPPORTED_CODECS.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+/// Symphonia's codecs plus libopus, which symphonia lacks.
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+// A corrupt packet is skipped, but several in a row mean the stream is unusable.
+const MAX_DECODE_ERRORS: usize = 3;
+
+/// Track decoder built directly on symphonia so it can seek through the
+/// container's own index (FLAC SEEKTABLE, MP4 sample tables, Ogg bisection)
+/// instead of decoding from the start of the file.
+pub struct SymphoniaDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    total_duration: Option<Duration>,
+    buffer: Option<SampleBuffer<f32>>,
+    offset: usize,
+    spec: SignalSpec,
+    codec: String,
+    bits_per_sample: Option<u32>,
+    // Frames still to drop after a seek landed before the requested timestamp.
+    skip_frames: u64,
+    // Part of the file played, for the tracks of a cue sheet; positions are
+    // relative to its start.
+    range: Option<TrackRange>,
+    // Samples left before the end of the range.
+    samples_left: Option<u64>,
+    // Set when playing from a URL.
+    stream: Option<Arc<StreamStatus>>,
+    // Why decoding stopped before the end of the file.
+    error: Option<String>,
+}
+
+impl SymphoniaDecoder {
+    /// Opens `file_path`, or the range of the file it names if it is the path
+    /// of a virtual track (see `cue::split_range`). HTTP(S) URLs are streamed.
+    pub fn open(file_path: &str) -> Result<Self, String> {
+        let (file_path, range) = cue::split_range(file_path);
+        let (source, extension, stream): (Box<dyn MediaSource>, _, _) = if stream::is_url(file_path) {
+            let reader = HttpReader::open(file_path)?;
+            let status = Arc::new(StreamStatus::default());
+            (Box::new(reader), stream::url_extension(file_path), Some(status))
+        } else {
+            let file = File::open(file_path).map_err(|e| format!("File opening error: {}", e))?;
+            let extension = Path::new(file_path).extension().and_then(|ext| ext.to_str());
+            (Box::new(file), extension, None)
+        };
+        let mss = MediaSourceStream::new(source, MediaSourceStreamOptions::default());
+
+        let mut hint = Hint::new();
+        if let Some(extension) = extension {
+            hint.with_extension(extension);
+        }
+
+        let mut decoder = Self::new(mss, &hint).map_err(|e| match e {
+            Error::Unsupported(what) => format!("Unsupported format error: {}", what),
+            e => format!("Decoder error: {}", e),
+        })?;
+        decoder.stream = stream;
+        if let Some(range) = range {
+            decoder.range = Some(range);
+            decoder.total_duration = range.length(decoder.total_duration);
+            decoder.seek(Duration::ZERO)?;
+        }
+        Ok(decoder)
+    }
+
+    fn new(mss: MediaSourceStream, hint: &Hint) -> symphonia::core::errors::Result<Self> {
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = get_probe().format(hint, mss, &format_opts, &MetadataOptions::default())?;
+        let format = probed.format;
+
+        let track = format
+            .default_track()
+            .ok_or(Error::Unsupported("no playable track"))?;
+        let decoder = codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+        let time_base = track.codec_params.time_base;
+        let total_duration = match (time_base, track.codec_params.n_frames) {
+            (Some(time_base), Some(n_frames)) => Some(to_duration(time_base.calc_time(n_frames))),
+            _ => None,
+        };
+        let track_id = track.id;
+        let codec = codecs()
+            .get_codec(track.codec_params.codec)
+            .map_or("unknown", |descriptor| descriptor.short_name)
+            .to_string();
+        let bits_per_sample = track.codec_params.bits_per_sample;
+
+        let mut this = Self {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            total_duration,
+            buffer: None,
+            offset: 0,
+            spec: SignalSpec::new(44_100, Default::default()),
+            codec,
+            bits_per_sample,
+            skip_frames: 0,
+            range: None,
+            samples_left: None,
+            stream: None,
+            error: None,
+        };
+
+        // Decode the first packet up front so channels and sample rate are known
+        // before the output asks for them.
+        this.decode_next()?;
+        Ok(this)
+    }
+
+    /// Status of the download, for tracks played from a URL.
+    pub fn stream_status(&self) -> Option<Arc<StreamStatus>> {
+        self.stream.clone()
+    }
+
+    /// Why the decoder ran out of samples early, e.g. a dropped connection.
+    /// `None` when it reached the end of the file.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn source_format(&self) -> SourceFormat {
+        SourceFormat {
+            codec: self.codec.clone(),
+            sample_rate: self.spec.rate,
+            bits_per_sample: self.bits_per_sample,
+            channels: self.spec.channels.count() as u16,
+        }
+    }
+
+    /// Decodes packets until one yields samples. Returns `Ok(false)` at the end
+    /// of the stream.
+    fn decode_next(&mut self) -> symphonia::core::errors::Result<bool> {
+        let mut decode_errors = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.spec = *decoded.spec();
+                    copy_into(&mut self.buffer, decoded, self.spec);
+                    self.offset = self.skip_leading_frames();
+                    if self.offset < self.buffered_len() {
+                        return Ok(true);
+                    }
+                }
+                Err(Error::DecodeError(_)) if decode_errors < MAX_DECODE_ERRORS => {
+                    decode_errors += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Decodes the next packet into the buffer, keeping the error if that
+    /// fails. Returns whether there are samples to play.
+    fn fill(&mut self) -> bool {
+        match self.decode_next() {
+            Ok(more) => more,
+            Err(e) => {
+                self.error = Some(format!("Decoder error: {}", e));
+                false
+            }
+        }
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.buffer.as_ref().map_or(0, |buffer| buffer.len())
+    }
+
+    /// Consumes pending seek padding from the freshly decoded buffer and
+    /// returns the offset of the first sample to play.
+    fn skip_leading_frames(&mut self) -> usize {
+        let channels = self.spec.channels.count().max(1);
+        let frames = (self.buffered_len() / channels) as u64;
+        let skipped = self.skip_frames.min(frames);
+        self.skip_frames -= skipped;
+        skipped as usize * channels
+    }
+}
+
+fn copy_into(buffer: &mut Option<SampleBuffer<f32>>, decoded: AudioBufferRef, spec: SignalSpec) {
+    let needed = decoded.capacity() * spec.channels.count();
+    let buffer = match buffer {
+        Some(buffer) if buffer.capacity() >= needed => buffer,
+        _ => buffer.insert(SampleBuffer::new(
+            units::Duration::from(decoded.capacity() as u64),
+            spec,
+        )),
+    };
+    buffer.copy_interleaved_ref(decoded);
+}
+
+impl Seekable for SymphoniaDecoder {
+    fn seek(&mut self, position: Duration) -> Result<Duration, String> {
+        let start = self.range.map_or(Duration::ZERO, |range| range.start);
+        let position = start + position;
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(position.as_secs_f64()),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| format!("Seek error: {}", e))?;
+
+        self.decoder.reset();
+        self.error = None;
+        self.offset = self.buffered_len();
+        // The reader lands on the packet containing the target; drop the frames
+        // before it so the seek is sample-accurate. Timestamps are in time base
+        // units, which aren't frames for every container.
+        let skip_ts = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.skip_frames = match self.time_base {
+            Some(time_base) => {
+                let skip = time_base.calc_time(skip_ts);
+                ((skip.seconds as f64 + skip.frac) * self.spec.rate as f64).round() as u64
+            }
+            None => skip_ts,
+        };
+
+        let reached = match self.time_base {
+            Some(time_base) => to_duration(time_base.calc_time(seeked.required_ts)),
+            None => position,
+        };
+        self.samples_left = self
+            .range
+            .and_then(|range| range.end)
+            .map(|end| self.samples_until(end, seeked.required_ts, reached));
+        self.fill();
+        Ok(reached.saturating_sub(start))
+    }
+}
+
+impl SymphoniaDecoder {
+    /// Samples from the seek target to `end`. Both ends go through the same
+    /// timestamp conversion as the seek of the track starting at `end`, so
+    /// consecutive ranges neither overlap nor leave a gap.
+    fn samples_until(&self, end: Duration, reached_ts: u64, reached: Duration) -> u64 {
+        let span = match self.time_base {
+            Some(time_base) => {
+                let end_ts = time_base.calc_timestamp(Time::from(end.as_secs_f64()));
+                to_duration(time_base.calc_time(end_ts.saturating_sub(reached_ts)))
+            }
+            None => end.saturating_sub(reached),
+        };
+        let frames = (span.as_secs_f64() * self.spec.rate as f64).round() as u64;
+        frames * self.spec.channels.count().max(1) as u64
+    }
+}
+
+fn to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+impl Iterator for SymphoniaDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.samples_left == Some(0) || self.error.is_some() {
+            return None;
+        }
+        if self.offset >= self.buffered_len() && !self.fill() {
+            return None;
+        }
+
+        let sample = *self.buffer.as_ref()?.samples().get(self.offset)?;
+        self.offset += 1;
+        if let Some(left) = self.samples_left.as_mut() {
+            *left -= 1;
+        }
+        // Decode the next packet right away so `current_frame_len` only drops
+        // to zero at the end of the track.
+        if self.offset >= self.buffered_len() && self.samples_left != Some(0) {
+            self.fill();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        let remaining = self.buffered_len().saturating_sub(self.offset);
+        Some(self.samples_left.map_or(remaining, |left| remaining.min(left as usize)))
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a mono 16-bit WAV file at 8 kHz whose samples count up from 0.
+    fn counting_wav(name: &str, frames: usize) -> String {
+        let data_len = (frames * 2) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(8000u32.to_le_bytes());
+        out.extend(16000u32.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for index in 0..frames {
+            out.extend((index as i16).to_le_bytes());
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("brick-decoder-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn value(sample: f32) -> i16 {
+        (sample * 32768.0).round() as i16
+    }
+
+    #[test]
+    fn frame_len_counts_what_is_left_of_the_packet() {
+        let path = counting_wav("frames", 20_000);
+        let mut decoder = SymphoniaDecoder::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut played = 0;
+        while let Some(len) = decoder.current_frame_len().filter(|&len| len > 0) {
+            for _ in 0..len - 1 {
+                decoder.next().unwrap();
+            }
+            assert_eq!(decoder.current_frame_len(), Some(1));
+            decoder.next().unwrap();
+            played += len;
+        }
+
+        assert_eq!(played, 20_000);
+        assert_eq!(decoder.next(), None);
+    }
+
+    #[test]
+    fn seek_lands_on_the_exact_sample() {
+        let path = counting_wav("seek", 20_000);
+        let mut decoder = SymphoniaDecoder::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let reached = decoder.seek(Duration::from_millis(1234)).unwrap();
+
+        assert_eq!(reached, Duration::from_micros(1_234_000));
+        assert_eq!(decoder.next().map(value), Some(9872));
+        assert_eq!(decoder.next().map(value), Some(9873));
+    }
+
+    #[test]
+    fn consecutive_ranges_neither_overlap_nor_leave_a_gap() {
+        let path = counting_wav("ranges", 20_000);
+        let starts = [0.0, 0.333_333, 1.7];
+
+        let mut samples = Vec::new();
+        for (index, &start) in starts.iter().enumerate() {
+            let range = TrackRange {
+                start: Duration::from_secs_f64(start),
+                end: starts.get(index + 1).map(|&end| Duration::from_secs_f64(end)),
+            };
+            let decoder = SymphoniaDecoder::open(&cue::range_path(&path, range)).unwrap();
+            samples.extend(decoder.map(value));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(samples, (0..20_000).map(|index| index as i16).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,196 @@
+use std::f64::consts::PI;
+
+/// Normalised biquad coefficients (`a0` divided out), computed with the RBJ
+/// audio EQ cookbook formulas.
+#[derive(Clone, Copy)]
+pub struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    pub const IDENTITY: Coefficients = Coefficients {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    fn normalised(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// Coefficients given directly as `[b0, b1, b2]` and `[a0, a1, a2]`.
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self::normalised(b[0], b[1], b[2], a[0], a[1], a[2])
+    }
+
+    /// Filters at or above Nyquist can't be realised; they pass audio through.
+    fn out_of_range(sample_rate: u32, frequency: f64) -> bool {
+        frequency <= 0.0 || frequency >= sample_rate as f64 * 0.49
+    }
+
+    pub fn peaking(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        if Self::out_of_range(sample_rate, frequency) || gain_db == 0.0 {
+            return Self::IDENTITY;
+        }
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+
+        Self::normalised(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        )
+    }
+
+    /// Low shelf with a shelf slope of 1, matching Web Audio's `lowshelf`.
+    pub fn low_shelf(sample_rate: u32, frequency: f64, gain_db: f64) -> Self {
+        if Self::out_of_range(sample_rate, frequency) || gain_db == 0.0 {
+            return Self::IDENTITY;
+        }
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let cos = w0.cos();
+        let alpha = w0.sin() / 2.0 * 2f64.sqrt();
+        let beta = 2.0 * a.sqrt() * alpha;
+
+        Self::normalised(
+            a * ((a + 1.0) - (a - 1.0) * cos + beta),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - beta),
+            (a + 1.0) + (a - 1.0) * cos + beta,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - beta,
+        )
+    }
+
+    /// High shelf with a shelf slope of 1, matching Web Audio's `highshelf`.
+    pub fn high_shelf(sample_rate: u32, frequency: f64, gain_db: f64) -> Self {
+        if Self::out_of_range(sample_rate, frequency) || gain_db == 0.0 {
+            return Self::IDENTITY;
+        }
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let cos = w0.cos();
+        let alpha = w0.sin() / 2.0 * 2f64.sqrt();
+        let beta = 2.0 * a.sqrt() * alpha;
+
+        Self::normalised(
+            a * ((a + 1.0) + (a - 1.0) * cos + beta),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - beta),
+            (a + 1.0) - (a - 1.0) * cos + beta,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - beta,
+        )
+    }
+}
+
+/// A single biquad section in transposed direct form II. Coefficients can be
+/// swapped while running; the state is kept so the change is smooth.
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    coefficients: Coefficients,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn new(coefficients: Coefficients) -> Self {
+        Self {
+            coefficients,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn set_coefficients(&mut self, coefficients: Coefficients) {
+        self.coefficients = coefficients;
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let c = &self.coefficients;
+        let output = c.b0 * input + self.z1;
+        self.z1 = c.b1 * input - c.a1 * output + self.z2;
+        self.z2 = c.b2 * input - c.a2 * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// Magnitude response of `c` at `frequency`, in dB.
+    fn response_db(c: Coefficients, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / RATE as f64;
+        // H(e^jw) = (b0 + b1 z^-1 + b2 z^-2) / (1 + a1 z^-1 + a2 z^-2)
+        let part = |x0: f64, x1: f64, x2: f64| {
+            let re = x0 + x1 * w.cos() + x2 * (2.0 * w).cos();
+            let im = -x1 * w.sin() - x2 * (2.0 * w).sin();
+            (re * re + im * im).sqrt()
+        };
+        20.0 * (part(c.b0, c.b1, c.b2) / part(1.0, c.a1, c.a2)).log10()
+    }
+
+    fn assert_near(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 0.01, "{} is not {}", value, expected);
+    }
+
+    #[test]
+    fn peaking_gain_is_reached_at_the_centre() {
+        let c = Coefficients::peaking(RATE, 1000.0, 1.0, 6.0);
+        assert_near(response_db(c, 1000.0), 6.0);
+        assert_near(response_db(c, 0.0), 0.0);
+        assert_near(response_db(c, RATE as f64 / 2.0), 0.0);
+
+        let cut = Coefficients::peaking(RATE, 1000.0, 1.0, -6.0);
+        assert_near(response_db(cut, 1000.0), -6.0);
+    }
+
+    #[test]
+    fn shelves_reach_their_gain_at_the_far_end() {
+        let low = Coefficients::low_shelf(RATE, 200.0, 8.0);
+        assert_near(response_db(low, 0.0), 8.0);
+        assert_near(response_db(low, 200.0), 4.0);
+        assert_near(response_db(low, RATE as f64 / 2.0), 0.0);
+
+        let high = Coefficients::high_shelf(RATE, 5000.0, -8.0);
+        assert_near(response_db(high, 0.0), 0.0);
+        assert_near(response_db(high, 5000.0), -4.0);
+        assert_near(response_db(high, RATE as f64 / 2.0), -8.0);
+    }
+
+    #[test]
+    fn unrealisable_filters_pass_audio_through() {
+        for c in [
+            Coefficients::peaking(RATE, 1000.0, 1.0, 0.0),
+            Coefficients::peaking(RATE, 30_000.0, 1.0, 6.0),
+            Coefficients::low_shelf(RATE, 0.0, 6.0),
+            Coefficients::high_shelf(RATE, 24_000.0, 6.0),
+        ] {
+            let mut biquad = Biquad::new(c);
+            for input in [1.0, -0.5, 0.25, 0.0] {
+                assert_eq!(biquad.process(input), input);
+            }
+        }
+    }
+}
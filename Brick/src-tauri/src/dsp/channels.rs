@@ -0,0 +1,453 @@
+use rodio::Source;
+use std::{
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+// Time the gains take to reach new settings, so edits don't click.
+const SMOOTHING: Duration = Duration::from_millis(20);
+
+/// Strength of the headphone crossfeed, after the bs2b presets.
+#[derive(Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Crossfeed {
+    #[default]
+    Off,
+    /// Jan Meier's preset: 650 Hz, 9.5 dB.
+    Low,
+    /// Chu Moy's preset: 700 Hz, 6 dB.
+    Medium,
+    /// The bs2b default: 700 Hz, 4.5 dB.
+    High,
+}
+
+impl Crossfeed {
+    /// Cut frequency in Hz and feed level in dB.
+    fn parameters(self) -> Option<(f64, f64)> {
+        match self {
+            Crossfeed::Off => None,
+            Crossfeed::Low => Some((650.0, 9.5)),
+            Crossfeed::Medium => Some((700.0, 6.0)),
+            Crossfeed::High => Some((700.0, 4.5)),
+        }
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ChannelSettings {
+    /// -1 plays the left channel only, 1 the right one only.
+    pub balance: f32,
+    /// Mixes every channel down to the same signal.
+    pub mono: bool,
+    pub crossfeed: Crossfeed,
+}
+
+impl ChannelSettings {
+    fn normalized(mut self) -> Self {
+        self.balance = if self.balance.is_finite() {
+            self.balance.clamp(-1.0, 1.0)
+        } else {
+            0.0
+        };
+        self
+    }
+
+    /// Gains of the left and right channels. The louder side stays at unity.
+    fn balance_gains(&self) -> [f32; 2] {
+        [
+            (1.0 - self.balance).min(1.0),
+            (1.0 + self.balance).min(1.0),
+        ]
+    }
+}
+
+/// Channel settings shared by every track's `ChannelMixer` stage. Each edit
+/// bumps `version` so running stages pick it up without restarting the sink.
+#[derive(Default)]
+pub struct ChannelControl {
+    settings: Mutex<ChannelSettings>,
+    version: AtomicU64,
+}
+
+impl ChannelControl {
+    pub fn settings(&self) -> Result<ChannelSettings, String> {
+        self.settings
+            .lock()
+            .map(|settings| settings.clone())
+            .map_err(|e| format!("Mutex lock error: {}", e))
+    }
+
+    fn update(&self, edit: impl FnOnce(&mut ChannelSettings)) -> Result<(), String> {
+        let mut settings = self
+            .settings
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        edit(&mut settings);
+        *settings = settings.clone().normalized();
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn set(&self, settings: ChannelSettings) -> Result<(), String> {
+        self.update(|current| *current = settings)
+    }
+
+    pub fn set_balance(&self, balance: f32) -> Result<(), String> {
+        self.update(|settings| settings.balance = balance)
+    }
+
+    pub fn set_mono(&self, mono: bool) -> Result<(), String> {
+        self.update(|settings| settings.mono = mono)
+    }
+
+    pub fn set_crossfeed(&self, crossfeed: Crossfeed) -> Result<(), String> {
+        self.update(|settings| settings.crossfeed = crossfeed)
+    }
+}
+
+/// Bauer stereophonic-to-binaural crossfeed as done by libbs2b: each ear gets
+/// the other channel low-passed and attenuated, while its own channel is
+/// high-shelved so the overall tone stays flat.
+#[derive(Clone, Copy)]
+struct Bs2b {
+    a0_lo: f64,
+    b1_lo: f64,
+    a0_hi: f64,
+    a1_hi: f64,
+    b1_hi: f64,
+    gain: f64,
+    // Last input and filter outputs of each channel.
+    input: [f64; 2],
+    lo: [f64; 2],
+    hi: [f64; 2],
+}
+
+impl Bs2b {
+    fn new(sample_rate: u32, cut_frequency: f64, feed_db: f64) -> Self {
+        let sample_rate = sample_rate.max(1) as f64;
+        let gb_lo = feed_db * -5.0 / 6.0 - 3.0;
+        let gb_hi = feed_db / 6.0 - 3.0;
+        let g_lo = 10f64.powf(gb_lo / 20.0);
+        let g_hi = 1.0 - 10f64.powf(gb_hi / 20.0);
+        let fc_hi = cut_frequency * 2f64.powf((gb_lo - 20.0 * g_hi.log10()) / 12.0);
+
+        let x_lo = (-2.0 * PI * cut_frequency / sample_rate).exp();
+        let x_hi = (-2.0 * PI * fc_hi / sample_rate).exp();
+
+        Self {
+            a0_lo: g_lo * (1.0 - x_lo),
+            b1_lo: x_lo,
+            a0_hi: 1.0 - g_hi * (1.0 - x_hi),
+            a1_hi: -x_hi,
+            b1_hi: x_hi,
+            gain: 1.0 / (1.0 - g_hi + g_lo),
+            input: [0.0; 2],
+            lo: [0.0; 2],
+            hi: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, frame: [f64; 2]) -> [f64; 2] {
+        for (channel, &sample) in frame.iter().enumerate() {
+            self.lo[channel] = self.a0_lo * sample + self.b1_lo * self.lo[channel];
+            self.hi[channel] =
+                self.a0_hi * sample + self.a1_hi * self.input[channel] + self.b1_hi * self.hi[channel];
+        }
+        self.input = frame;
+        [
+            (self.hi[0] + self.lo[1]) * self.gain,
+            (self.hi[1] + self.lo[0]) * self.gain,
+        ]
+    }
+}
+
+/// Source stage applying balance, mono downmix and crossfeed. Works on whole
+/// frames; balance and crossfeed only apply to stereo tracks.
+pub struct ChannelMixer<S> {
+    inner: S,
+    control: Arc<ChannelControl>,
+    version: u64,
+    sample_rate: u32,
+    settings: ChannelSettings,
+    crossfeed: Option<Bs2b>,
+    // Current gains, moving towards the settings a step per frame.
+    balance: [f32; 2],
+    mono: f32,
+    crossfeed_mix: f32,
+    frame: Vec<f32>,
+    offset: usize,
+}
+
+impl<S> ChannelMixer<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<ChannelControl>) -> Self {
+        // `refresh` retries a lock that fails here.
+        let settings = control.settings().unwrap_or_default();
+        let mut mixer = Self {
+            sample_rate: inner.sample_rate(),
+            balance: settings.balance_gains(),
+            mono: if settings.mono { 1.0 } else { 0.0 },
+            crossfeed_mix: if settings.crossfeed == Crossfeed::Off { 0.0 } else { 1.0 },
+            frame: Vec::with_capacity(inner.channels() as usize),
+            inner,
+            control,
+            version: u64::MAX,
+            settings,
+            crossfeed: None,
+            offset: 0,
+        };
+        mixer.refresh();
+        mixer
+    }
+
+    /// Picks up edits and format changes. Uses `try_lock` so the audio
+    /// thread never waits on a command.
+    fn refresh(&mut self) {
+        let version = self.control.version.load(Ordering::SeqCst);
+        let rate_changed = self.sample_rate != self.inner.sample_rate();
+        if version == self.version && !rate_changed {
+            return;
+        }
+        let Ok(settings) = self.control.settings.try_lock() else {
+            return;
+        };
+
+        let crossfeed_changed = self.version == u64::MAX
+            || rate_changed
+            || settings.crossfeed != self.settings.crossfeed;
+        self.version = version;
+        self.sample_rate = self.inner.sample_rate();
+        self.settings = settings.clone();
+        if crossfeed_changed {
+            // Switching off keeps the filters so the mix can fade them out.
+            if let Some((cut_frequency, feed_db)) = self.settings.crossfeed.parameters() {
+                self.crossfeed = Some(Bs2b::new(self.sample_rate, cut_frequency, feed_db));
+            }
+        }
+    }
+
+    fn is_neutral(&self) -> bool {
+        self.balance == [1.0, 1.0]
+            && self.mono == 0.0
+            && self.crossfeed_mix == 0.0
+            && self.settings.balance == 0.0
+            && !self.settings.mono
+            && self.settings.crossfeed == Crossfeed::Off
+    }
+
+    /// Moves the smoothed gains one frame towards the settings.
+    fn step(&mut self) {
+        let step = 1.0 / (SMOOTHING.as_secs_f32() * self.sample_rate.max(1) as f32).max(1.0);
+        let approach = |value: &mut f32, target: f32| {
+            *value = if *value < target {
+                (*value + step).min(target)
+            } else {
+                (*value - step).max(target)
+            };
+        };
+
+        let target = self.settings.balance_gains();
+        approach(&mut self.balance[0], target[0]);
+        approach(&mut self.balance[1], target[1]);
+        approach(&mut self.mono, if self.settings.mono { 1.0 } else { 0.0 });
+        let crossfeed = self.settings.crossfeed != Crossfeed::Off;
+        approach(&mut self.crossfeed_mix, if crossfeed { 1.0 } else { 0.0 });
+    }
+
+    /// Reads and processes the next frame; returns `false` at the end.
+    fn next_frame(&mut self) -> bool {
+        let channels = self.inner.channels().max(1) as usize;
+        self.frame.clear();
+        self.frame.extend(self.inner.by_ref().take(channels));
+        self.offset = 0;
+        if self.frame.len() < channels {
+            // A truncated last frame is passed on untouched.
+            return !self.frame.is_empty();
+        }
+
+        self.refresh();
+        if self.is_neutral() {
+            return true;
+        }
+        self.step();
+
+        if self.mono > 0.0 {
+            let mean = self.frame.iter().sum::<f32>() / channels as f32;
+            for sample in &mut self.frame {
+                *sample += (mean - *sample) * self.mono;
+            }
+        }
+        if channels != 2 {
+            return true;
+        }
+
+        if let Some(crossfeed) = self.crossfeed.as_mut() {
+            let dry = [self.frame[0] as f64, self.frame[1] as f64];
+            let wet = crossfeed.process(dry);
+            let mix = self.crossfeed_mix as f64;
+            for channel in 0..2 {
+                self.frame[channel] = (dry[channel] + (wet[channel] - dry[channel]) * mix) as f32;
+            }
+            if self.crossfeed_mix == 0.0 && self.settings.crossfeed == Crossfeed::Off {
+                self.crossfeed = None;
+            }
+        }
+        self.frame[0] *= self.balance[0];
+        self.frame[1] *= self.balance[1];
+        true
+    }
+}
+
+impl<S> Iterator for ChannelMixer<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.offset >= self.frame.len() && !self.next_frame() {
+            return None;
+        }
+        let sample = self.frame.get(self.offset).copied();
+        self.offset += 1;
+        sample
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for ChannelMixer<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        // The frame read ahead belongs to the inner source's current frame.
+        self.inner
+            .current_frame_len()
+            .map(|len| len + self.frame.len() - self.offset.min(self.frame.len()))
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const RATE: u32 = 48_000;
+
+    fn stereo(frames: usize, frame: [f32; 2]) -> SamplesBuffer<f32> {
+        SamplesBuffer::new(2, RATE, frame.repeat(frames))
+    }
+
+    fn control(settings: ChannelSettings) -> Arc<ChannelControl> {
+        let control = Arc::new(ChannelControl::default());
+        control.set(settings).unwrap();
+        control
+    }
+
+    fn mix(source: SamplesBuffer<f32>, settings: ChannelSettings) -> Vec<f32> {
+        ChannelMixer::new(source, control(settings)).collect()
+    }
+
+    #[test]
+    fn neutral_settings_pass_samples_through() {
+        let input: Vec<f32> = [0.1, -0.7, 0.3, 0.9].repeat(100);
+        let source = SamplesBuffer::new(2, RATE, input.clone());
+
+        assert_eq!(mix(source, ChannelSettings::default()), input);
+    }
+
+    #[test]
+    fn balance_turns_down_the_other_side() {
+        let settings = ChannelSettings {
+            balance: -0.5,
+            ..Default::default()
+        };
+
+        let output = mix(stereo(10, [0.8, 0.8]), settings);
+
+        assert_eq!(&output[..4], [0.8, 0.4, 0.8, 0.4]);
+    }
+
+    #[test]
+    fn mono_gives_both_channels_their_mean() {
+        let settings = ChannelSettings {
+            mono: true,
+            ..Default::default()
+        };
+
+        let output = mix(stereo(10, [1.0, 0.0]), settings);
+
+        assert!(output.iter().all(|&sample| sample == 0.5));
+    }
+
+    #[test]
+    fn edits_fade_in_over_the_smoothing_time() {
+        let control = control(ChannelSettings::default());
+        let mut mixer = ChannelMixer::new(stereo(2 * RATE as usize, [1.0, 0.0]), control.clone());
+        mixer.next();
+        mixer.next();
+
+        control.set_mono(true).unwrap();
+        let output: Vec<f32> = mixer.collect();
+
+        let smoothing_frames = (SMOOTHING.as_secs_f32() * RATE as f32) as usize;
+        let right = |frame: usize| output[2 * frame + 1];
+        assert!(right(0) > 0.0 && right(0) < 0.01, "{}", right(0));
+        assert!(right(smoothing_frames / 2) > 0.2 && right(smoothing_frames / 2) < 0.3);
+        assert_eq!(right(smoothing_frames), 0.5);
+    }
+
+    #[test]
+    fn crossfeed_feeds_each_ear_the_other_channel() {
+        let settings = ChannelSettings {
+            crossfeed: Crossfeed::Medium,
+            ..Default::default()
+        };
+
+        let left_only = mix(stereo(RATE as usize, [0.5, 0.0]), settings.clone());
+        let centred = mix(stereo(RATE as usize, [0.5, 0.5]), settings);
+
+        let (left, right) = (left_only[left_only.len() - 2], left_only[left_only.len() - 1]);
+        assert!(right > 0.05 && right < left, "{} {}", left, right);
+        // Sound in the centre keeps its level.
+        for sample in &centred[centred.len() - 2..] {
+            assert!((sample - 0.5).abs() < 1e-3, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn balance_and_crossfeed_leave_other_layouts_alone() {
+        let settings = ChannelSettings {
+            balance: -1.0,
+            mono: false,
+            crossfeed: Crossfeed::High,
+        };
+        let input: Vec<f32> = [0.2, 0.4, 0.6].repeat(50);
+
+        let output = mix(SamplesBuffer::new(3, RATE, input.clone()), settings);
+
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,179 @@
+use rodio::Source;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::track::TrackControl;
+
+pub const MIN_RAMP_MS: u32 = 5;
+pub const MAX_RAMP_MS: u32 = 50;
+const DEFAULT_RAMP_MS: u32 = 10;
+// Longest wait for a seek to land before the stage opens again regardless.
+const MAX_SEEK_WAIT: Duration = Duration::from_millis(250);
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeclickSettings {
+    pub ramp_ms: u32,
+}
+
+/// Length of the ramps every track's `Declick` stage uses.
+pub struct DeclickControl {
+    ramp_ms: AtomicU32,
+}
+
+impl Default for DeclickControl {
+    fn default() -> Self {
+        Self {
+            ramp_ms: AtomicU32::new(DEFAULT_RAMP_MS),
+        }
+    }
+}
+
+impl DeclickControl {
+    pub fn settings(&self) -> DeclickSettings {
+        DeclickSettings {
+            ramp_ms: self.ramp_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_ramp_ms(&self, ramp_ms: u32) {
+        self.ramp_ms
+            .store(ramp_ms.clamp(MIN_RAMP_MS, MAX_RAMP_MS), Ordering::Relaxed);
+    }
+
+    pub fn ramp(&self) -> Duration {
+        Duration::from_millis(self.ramp_ms.load(Ordering::Relaxed) as u64)
+    }
+}
+
+/// Last stage of the chain. Ramps the track down before it is paused, stopped
+/// or seeks (see `TrackControl::set_gate` and `seek_smoothly`) and back up
+/// afterwards, so the output never jumps between two samples. While the gate
+/// is closed the stage holds at silence without pulling its input.
+pub struct Declick<S> {
+    inner: S,
+    control: Arc<DeclickControl>,
+    track: Arc<TrackControl>,
+    // `None` until the first frame, which starts silent if the track begins
+    // paused or mid-way.
+    gain: Option<f32>,
+    sample_in_frame: u16,
+    // Seek count before the pending seek and frames waited for it to land.
+    seek_wait: Option<(u64, u64)>,
+}
+
+impl<S> Declick<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<DeclickControl>, track: Arc<TrackControl>) -> Self {
+        Self {
+            inner,
+            control,
+            track,
+            gain: None,
+            sample_in_frame: 0,
+            seek_wait: None,
+        }
+    }
+
+    fn ramp_frames(&self) -> f32 {
+        (self.control.ramp().as_secs_f32() * self.inner.sample_rate() as f32).max(1.0)
+    }
+
+    /// Moves the gain one frame towards where it should be; runs between frames.
+    fn advance(&mut self) {
+        let gain = *self.gain.get_or_insert_with(|| {
+            if self.track.is_gate_open() && !self.track.has_smooth_seek() {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        if gain == 0.0 {
+            if let Some(position) = self.track.take_smooth_seek() {
+                self.seek_wait = Some((self.track.seek_count(), 0));
+                self.track.request_seek(position);
+            }
+        }
+        if let Some((seeks, waited)) = self.seek_wait {
+            let limit = MAX_SEEK_WAIT.as_secs_f32() * self.inner.sample_rate() as f32;
+            self.seek_wait = (self.track.seek_count() == seeks && (waited as f32) < limit)
+                .then_some((seeks, waited + 1));
+        }
+
+        let open = self.track.is_gate_open()
+            && !self.track.has_smooth_seek()
+            && self.seek_wait.is_none();
+        let step = 1.0 / self.ramp_frames();
+        self.gain = Some(if open {
+            (gain + step).min(1.0)
+        } else {
+            (gain - step).max(0.0)
+        });
+    }
+
+    fn is_holding(&self) -> bool {
+        self.gain == Some(0.0) && self.seek_wait.is_none() && !self.track.is_gate_open()
+    }
+}
+
+impl<S> Iterator for Declick<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.sample_in_frame == 0 {
+            self.advance();
+        }
+        let channels = self.inner.channels().max(1);
+
+        let sample = if self.is_holding() {
+            // A cancelled track must still end while held.
+            if self.inner.current_frame_len() == Some(0) {
+                return None;
+            }
+            0.0
+        } else {
+            self.inner.next()? * self.gain.unwrap_or(1.0)
+        };
+
+        self.sample_in_frame += 1;
+        if self.sample_in_frame >= channels {
+            self.sample_in_frame = 0;
+        }
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Declick<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
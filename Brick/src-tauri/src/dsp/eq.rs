@@ -0,0 +1,438 @@
+use rodio::Source;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use super::biquad::{Biquad, Coefficients};
+
+const MAX_GAIN_DB: f32 = 12.0;
+const GRAPHIC_Q: f64 = std::f64::consts::SQRT_2;
+const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+// Same corner frequencies as the Web Audio filters in `gaplessAudio.ts`.
+const THREE_BAND_FREQUENCIES: [f32; 3] = [200.0, 1000.0, 3200.0];
+
+/// `(name, three-band gains, graphic gains)` in dB.
+const PRESETS: &[(&str, [f32; 3], [f32; 10])] = &[
+    ("flat", [0.0; 3], [0.0; 10]),
+    (
+        "bassBoost",
+        [6.0, 0.0, 0.0],
+        [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    ),
+    (
+        "trebleBoost",
+        [0.0, 0.0, 6.0],
+        [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 4.0, 5.0, 6.0],
+    ),
+    (
+        "vocal",
+        [-2.0, 3.0, 0.0],
+        [-2.0, -2.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0],
+    ),
+    (
+        "rock",
+        [4.0, -1.0, 4.0],
+        [5.0, 4.0, 3.0, 1.0, -1.0, -1.0, 1.0, 3.0, 4.0, 5.0],
+    ),
+    (
+        "pop",
+        [-1.0, 4.0, -1.0],
+        [-1.0, 0.0, 2.0, 4.0, 5.0, 4.0, 2.0, 0.0, -1.0, -1.0],
+    ),
+    (
+        "jazz",
+        [3.0, -1.0, 2.0],
+        [3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0],
+    ),
+    (
+        "classical",
+        [3.0, -1.0, 3.0],
+        [4.0, 3.0, 2.0, 1.0, -1.0, -1.0, 0.0, 2.0, 3.0, 4.0],
+    ),
+    (
+        "electronic",
+        [5.0, 0.0, 4.0],
+        [5.0, 4.0, 1.0, 0.0, -2.0, 2.0, 1.0, 1.0, 4.0, 5.0],
+    ),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EqMode {
+    /// Bass shelf, mid peak and treble shelf, like the Web Audio engine.
+    ThreeBand,
+    /// Ten octave-spaced peaking bands from 31 Hz to 16 kHz.
+    Graphic,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EqBand {
+    pub frequency: f32,
+    pub gain_db: f32,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EqSettings {
+    pub mode: EqMode,
+    pub bands: Vec<EqBand>,
+    /// Name of the preset the gains came from, cleared by manual edits.
+    pub preset: Option<String>,
+    pub presets: Vec<String>,
+}
+
+impl EqSettings {
+    fn flat(mode: EqMode) -> Self {
+        let frequencies: &[f32] = match mode {
+            EqMode::ThreeBand => &THREE_BAND_FREQUENCIES,
+            EqMode::Graphic => &GRAPHIC_FREQUENCIES,
+        };
+
+        Self {
+            mode,
+            bands: frequencies
+                .iter()
+                .map(|&frequency| EqBand {
+                    frequency,
+                    gain_db: 0.0,
+                })
+                .collect(),
+            preset: Some("flat".to_string()),
+            presets: PRESETS.iter().map(|(name, _, _)| name.to_string()).collect(),
+        }
+    }
+
+    fn apply_preset(&mut self, name: &str) -> Result<(), String> {
+        let (_, three_band, graphic) = PRESETS
+            .iter()
+            .find(|(preset, _, _)| *preset == name)
+            .ok_or_else(|| format!("Unknown EQ preset: {}", name))?;
+
+        let gains: &[f32] = match self.mode {
+            EqMode::ThreeBand => three_band,
+            EqMode::Graphic => graphic,
+        };
+        for (band, gain_db) in self.bands.iter_mut().zip(gains) {
+            band.gain_db = *gain_db;
+        }
+        self.preset = Some(name.to_string());
+        Ok(())
+    }
+
+    fn is_flat(&self) -> bool {
+        self.bands.iter().all(|band| band.gain_db == 0.0)
+    }
+
+    fn coefficients(&self, sample_rate: u32) -> Vec<Coefficients> {
+        let last = self.bands.len().saturating_sub(1);
+        self.bands
+            .iter()
+            .enumerate()
+            .map(|(index, band)| {
+                let frequency = band.frequency as f64;
+                let gain_db = band.gain_db as f64;
+                match self.mode {
+                    EqMode::ThreeBand if index == 0 => {
+                        Coefficients::low_shelf(sample_rate, frequency, gain_db)
+                    }
+                    EqMode::ThreeBand if index == last => {
+                        Coefficients::high_shelf(sample_rate, frequency, gain_db)
+                    }
+                    EqMode::ThreeBand => Coefficients::peaking(sample_rate, frequency, 1.0, gain_db),
+                    EqMode::Graphic => {
+                        Coefficients::peaking(sample_rate, frequency, GRAPHIC_Q, gain_db)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Equalizer parameters shared by every track's `Equalizer` stage. Each edit
+/// bumps `version` so running stages pick it up without restarting the sink.
+pub struct EqControl {
+    settings: Mutex<EqSettings>,
+    version: AtomicU64,
+}
+
+impl Default for EqControl {
+    fn default() -> Self {
+        Self {
+            settings: Mutex::new(EqSettings::flat(EqMode::ThreeBand)),
+            version: AtomicU64::new(0),
+        }
+    }
+}
+
+impl EqControl {
+    pub fn settings(&self) -> Result<EqSettings, String> {
+        self.settings
+            .lock()
+            .map(|settings| settings.clone())
+            .map_err(|e| format!("Mutex lock error: {}", e))
+    }
+
+    fn update(&self, edit: impl FnOnce(&mut EqSettings) -> Result<(), String>) -> Result<(), String> {
+        let mut settings = self
+            .settings
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        edit(&mut settings)?;
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn set_mode(&self, mode: EqMode) -> Result<(), String> {
+        self.update(|settings| {
+            if settings.mode != mode {
+                let preset = settings.preset.take();
+                *settings = EqSettings::flat(mode);
+                // Carry the preset over to the other band layout.
+                if let Some(preset) = preset {
+                    settings.apply_preset(&preset)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_band(&self, index: usize, gain_db: f32) -> Result<(), String> {
+        self.update(|settings| {
+            let band = settings
+                .bands
+                .get_mut(index)
+                .ok_or_else(|| format!("EQ band out of range: {}", index))?;
+            band.gain_db = gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+            settings.preset = None;
+            Ok(())
+        })
+    }
+
+    pub fn set_preset(&self, name: &str) -> Result<(), String> {
+        self.update(|settings| settings.apply_preset(name))
+    }
+}
+
+/// Source stage running the shared EQ bands over every channel.
+pub struct Equalizer<S> {
+    inner: S,
+    control: Arc<EqControl>,
+    version: u64,
+    sample_rate: u32,
+    channels: u16,
+    channel: u16,
+    bypass: bool,
+    // One filter chain per channel.
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl<S> Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<EqControl>) -> Self {
+        let mut equalizer = Self {
+            sample_rate: inner.sample_rate(),
+            channels: inner.channels(),
+            inner,
+            control,
+            version: u64::MAX,
+            channel: 0,
+            bypass: true,
+            filters: Vec::new(),
+        };
+        equalizer.refresh();
+        equalizer
+    }
+
+    /// Recomputes the coefficients after an edit or a format change. Uses
+    /// `try_lock` so the audio thread never waits on a command.
+    fn refresh(&mut self) {
+        let version = self.control.version.load(Ordering::SeqCst);
+        let format_changed =
+            self.sample_rate != self.inner.sample_rate() || self.channels != self.inner.channels();
+        if version == self.version && !format_changed {
+            return;
+        }
+        let Ok(settings) = self.control.settings.try_lock() else {
+            return;
+        };
+
+        self.sample_rate = self.inner.sample_rate();
+        self.channels = self.inner.channels();
+        self.version = version;
+        self.bypass = settings.is_flat();
+
+        let coefficients = settings.coefficients(self.sample_rate);
+        let rebuild = format_changed
+            || self.filters.len() != self.channels as usize
+            || self.filters.first().map(Vec::len) != Some(coefficients.len());
+        if rebuild {
+            self.filters = vec![
+                coefficients.iter().copied().map(Biquad::new).collect();
+                self.channels as usize
+            ];
+        } else {
+            for chain in &mut self.filters {
+                for (filter, c) in chain.iter_mut().zip(&coefficients) {
+                    filter.set_coefficients(*c);
+                }
+            }
+        }
+    }
+}
+
+impl<S> Iterator for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.refresh();
+        }
+
+        let sample = self.inner.next()?;
+        let channel = self.channel as usize;
+        self.channel = (self.channel + 1) % self.channels.max(1);
+
+        if self.bypass {
+            return Some(sample);
+        }
+        let Some(chain) = self.filters.get_mut(channel) else {
+            return Some(sample);
+        };
+
+        let mut value = sample as f64;
+        for filter in chain {
+            value = filter.process(value);
+        }
+        Some(value as f32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 48_000;
+
+    /// One second of a sine at `frequency` on the left channel of a stereo
+    /// stream, with silence on the right.
+    fn left_sine(frequency: f32) -> SamplesBuffer<f32> {
+        let samples = (0..RATE)
+            .map(|frame| 0.25 * (2.0 * PI * frequency * frame as f32 / RATE as f32).sin())
+            .flat_map(|sample| [sample, 0.0])
+            .collect::<Vec<f32>>();
+        SamplesBuffer::new(2, RATE, samples)
+    }
+
+    /// RMS of each channel over the last half of `samples`, once the filters
+    /// settled.
+    fn rms(samples: &[f32]) -> [f32; 2] {
+        let settled = &samples[samples.len() / 2..];
+        let mut sums = [0.0; 2];
+        for frame in settled.chunks(2) {
+            sums[0] += frame[0] * frame[0];
+            sums[1] += frame[1] * frame[1];
+        }
+        sums.map(|sum| (sum / (settled.len() / 2) as f32).sqrt())
+    }
+
+    #[test]
+    fn flat_settings_pass_samples_through() {
+        let control = Arc::new(EqControl::default());
+        let input: Vec<f32> = left_sine(440.0).collect();
+
+        let output: Vec<f32> = Equalizer::new(left_sine(440.0), control).collect();
+
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn bass_shelf_boosts_low_notes_on_each_channel() {
+        let control = Arc::new(EqControl::default());
+        control.set_band(0, 12.0).unwrap();
+        let before = rms(&left_sine(20.0).collect::<Vec<_>>());
+
+        let after = rms(&Equalizer::new(left_sine(20.0), control).collect::<Vec<_>>());
+
+        let gain_db = 20.0 * (after[0] / before[0]).log10();
+        assert!((gain_db - 12.0).abs() < 0.5, "{} dB", gain_db);
+        assert_eq!(after[1], 0.0);
+    }
+
+    #[test]
+    fn running_stages_pick_up_edits() {
+        let control = Arc::new(EqControl::default());
+        let mut equalizer = Equalizer::new(left_sine(20.0), control.clone());
+        let unchanged: Vec<f32> = equalizer.by_ref().take(RATE as usize).collect();
+
+        control.set_preset("bassBoost").unwrap();
+        let boosted: Vec<f32> = equalizer.collect();
+
+        assert!(rms(&boosted)[0] > 1.5 * rms(&unchanged)[0]);
+    }
+
+    #[test]
+    fn presets_carry_over_to_the_other_layout() {
+        let control = EqControl::default();
+        control.set_preset("rock").unwrap();
+
+        control.set_mode(EqMode::Graphic).unwrap();
+
+        let settings = control.settings().unwrap();
+        assert_eq!(settings.preset.as_deref(), Some("rock"));
+        let gains: Vec<f32> = settings.bands.iter().map(|band| band.gain_db).collect();
+        assert_eq!(gains, [5.0, 4.0, 3.0, 1.0, -1.0, -1.0, 1.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn band_edits_are_clamped_and_clear_the_preset() {
+        let control = EqControl::default();
+        control.set_preset("vocal").unwrap();
+
+        control.set_band(1, 40.0).unwrap();
+
+        let settings = control.settings().unwrap();
+        assert_eq!(settings.bands[1].gain_db, MAX_GAIN_DB);
+        assert_eq!(settings.preset, None);
+        assert!(control.set_band(3, 1.0).is_err());
+        assert!(control.set_preset("loud").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//! Processing stages wrapped around every track before it reaches the sink.
+//! Parameters live in shared controls so edits apply to the running chain.
+
+pub mod biquad;
+pub mod channels;
+pub mod declick;
+pub mod eq;
+pub mod replaygain;
+pub mod stretch;
+
+use rodio::Source;
+use std::sync::Arc;
+
+use crate::{
+    tags::ReplayGainTags,
+    track::{Seekable, TrackSource},
+};
+use channels::{ChannelControl, ChannelMixer};
+use declick::{Declick, DeclickControl};
+use eq::{EqControl, Equalizer};
+use replaygain::{ReplayGain, ReplayGainControl};
+use stretch::{StretchControl, TimeStretch};
+
+#[derive(Default)]
+pub struct DspControls {
+    pub eq: Arc<EqControl>,
+    pub replay_gain: Arc<ReplayGainControl>,
+    pub stretch: Arc<StretchControl>,
+    pub declick: Arc<DeclickControl>,
+    pub channels: Arc<ChannelControl>,
+}
+
+impl DspControls {
+    /// Wraps a decoded track in the processing chain. `in_album` tells the
+    /// ReplayGain stage whether the track is played as part of its album.
+    pub fn build_chain<S>(
+        &self,
+        track: TrackSource<S>,
+        replay_gain: ReplayGainTags,
+        in_album: bool,
+    ) -> Box<dyn Source<Item = f32> + Send>
+    where
+        S: Source<Item = f32> + Seekable + Send + 'static,
+    {
+        let control = track.control();
+        let source = TimeStretch::new(track, self.stretch.clone(), control.clone());
+        let source = ReplayGain::new(source, self.replay_gain.clone(), replay_gain, in_album);
+        let source = Equalizer::new(source, self.eq.clone());
+        let source = ChannelMixer::new(source, self.channels.clone());
+        Box::new(Declick::new(source, self.declick.clone(), control))
+    }
+}
@@ -0,0 +1,248 @@
+use rodio::Source;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::tags::ReplayGainTags;
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain while the queue plays an album in order, track gain otherwise.
+    Auto,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    pub preamp_db: f32,
+    /// Lowers the gain so the tagged peak never goes above full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Auto,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl ReplayGainSettings {
+    /// Linear gain for a track with the given tags. `in_album` tells whether it
+    /// is being played as part of its album.
+    fn gain(&self, tags: &ReplayGainTags, in_album: bool) -> f32 {
+        let use_album = match self.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => in_album,
+        };
+
+        let track = tags.track_gain.map(|gain| (gain, tags.track_peak));
+        let album = tags.album_gain.map(|gain| (gain, tags.album_peak));
+        let chosen = if use_album {
+            album.or(track)
+        } else {
+            track.or(album)
+        };
+        // Untagged files play unchanged rather than getting only the pre-amp.
+        let Some((gain_db, peak)) = chosen else {
+            return 1.0;
+        };
+
+        let gain = 10f32.powf((gain_db + self.preamp_db) / 20.0);
+        match peak.filter(|peak| self.prevent_clipping && *peak > 0.0) {
+            Some(peak) => gain.min(1.0 / peak),
+            None => gain,
+        }
+    }
+}
+
+/// ReplayGain settings shared by every track's `ReplayGain` stage.
+#[derive(Default)]
+pub struct ReplayGainControl {
+    settings: Mutex<ReplayGainSettings>,
+    version: AtomicU64,
+}
+
+impl ReplayGainControl {
+    pub fn settings(&self) -> Result<ReplayGainSettings, String> {
+        self.settings
+            .lock()
+            .map(|settings| settings.clone())
+            .map_err(|e| format!("Mutex lock error: {}", e))
+    }
+
+    pub fn update(&self, edit: impl FnOnce(&mut ReplayGainSettings)) -> Result<(), String> {
+        let mut settings = self
+            .settings
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        edit(&mut settings);
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Source stage applying the track's ReplayGain.
+pub struct ReplayGain<S> {
+    inner: S,
+    control: Arc<ReplayGainControl>,
+    tags: ReplayGainTags,
+    in_album: bool,
+    version: u64,
+    gain: f32,
+}
+
+impl<S> ReplayGain<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<ReplayGainControl>, tags: ReplayGainTags, in_album: bool) -> Self {
+        let version = control.version.load(Ordering::SeqCst);
+        // A poisoned lock leaves the track at unity gain.
+        let gain = control
+            .settings
+            .lock()
+            .map_or(1.0, |settings| settings.gain(&tags, in_album));
+        Self {
+            inner,
+            control,
+            tags,
+            in_album,
+            version,
+            gain,
+        }
+    }
+
+    fn refresh(&mut self) {
+        let version = self.control.version.load(Ordering::Relaxed);
+        if version == self.version {
+            return;
+        }
+        if let Ok(settings) = self.control.settings.try_lock() {
+            self.gain = settings.gain(&self.tags, self.in_album);
+            self.version = version;
+        }
+    }
+}
+
+impl<S> Iterator for ReplayGain<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.refresh();
+        self.inner.next().map(|sample| sample * self.gain)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for ReplayGain<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const TAGS: ReplayGainTags = ReplayGainTags {
+        track_gain: Some(-6.0),
+        track_peak: Some(0.5),
+        album_gain: Some(-3.0),
+        album_peak: Some(0.9),
+    };
+
+    fn settings(mode: ReplayGainMode, preamp_db: f32, clip: bool) -> ReplayGainSettings {
+        ReplayGainSettings {
+            mode,
+            preamp_db,
+            prevent_clipping: clip,
+        }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{} is not {}", value, expected);
+    }
+
+    #[test]
+    fn mode_picks_the_gain() {
+        let gain = |mode, in_album| settings(mode, 0.0, false).gain(&TAGS, in_album);
+
+        assert_eq!(gain(ReplayGainMode::Off, true), 1.0);
+        assert_near(gain(ReplayGainMode::Track, true), 0.501_187);
+        assert_near(gain(ReplayGainMode::Album, false), 0.707_946);
+        assert_near(gain(ReplayGainMode::Auto, false), 0.501_187);
+        assert_near(gain(ReplayGainMode::Auto, true), 0.707_946);
+    }
+
+    #[test]
+    fn missing_gains_fall_back_or_play_unchanged() {
+        let track_only = ReplayGainTags {
+            album_gain: None,
+            album_peak: None,
+            ..TAGS
+        };
+        let album = settings(ReplayGainMode::Album, 6.0, false);
+
+        assert_near(album.gain(&track_only, true), 1.0);
+        // The pre-amp alone doesn't apply to untagged files.
+        assert_eq!(album.gain(&ReplayGainTags::default(), true), 1.0);
+    }
+
+    #[test]
+    fn peaks_limit_the_gain_when_preventing_clipping() {
+        let boosted = ReplayGainTags {
+            track_gain: Some(12.0),
+            ..TAGS
+        };
+
+        assert_near(settings(ReplayGainMode::Track, 0.0, true).gain(&boosted, false), 2.0);
+        assert_near(settings(ReplayGainMode::Track, 0.0, false).gain(&boosted, false), 3.981_072);
+    }
+
+    #[test]
+    fn running_stages_pick_up_new_settings() {
+        let control = Arc::new(ReplayGainControl::default());
+        let input = SamplesBuffer::new(1, 48_000, vec![0.5; 4]);
+        let mut stage = ReplayGain::new(input, control.clone(), TAGS, false);
+
+        assert_near(stage.next().unwrap(), 0.5 * 0.501_187);
+        control.update(|settings| settings.mode = ReplayGainMode::Off).unwrap();
+        assert_eq!(stage.next(), Some(0.5));
+    }
+}
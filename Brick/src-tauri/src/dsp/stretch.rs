@@ -0,0 +1,605 @@
+use rodio::Source;
+use std::{
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::track::TrackControl;
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.5;
+pub const MAX_PLAYBACK_RATE: f32 = 3.0;
+pub const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+// WSOLA segment length, how far around the nominal position a segment may be
+// moved to line up with the previous one, and how much of it is compared.
+const SEGMENT: Duration = Duration::from_millis(40);
+const SEARCH: Duration = Duration::from_millis(12);
+const COMPARE: Duration = Duration::from_millis(10);
+// Offsets tried in the coarse pass of the search, before refining.
+const COARSE_STEP: usize = 4;
+
+#[derive(Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StretchSettings {
+    /// Speed of playback; the pitch is kept unless shifted separately.
+    pub playback_rate: f32,
+    pub pitch_semitones: f32,
+}
+
+impl Default for StretchSettings {
+    fn default() -> Self {
+        Self {
+            playback_rate: 1.0,
+            pitch_semitones: 0.0,
+        }
+    }
+}
+
+impl StretchSettings {
+    fn is_identity(&self) -> bool {
+        self.playback_rate == 1.0 && self.pitch_semitones == 0.0
+    }
+
+    /// Resampling ratio that moves the pitch by the requested semitones.
+    fn pitch_factor(&self) -> f64 {
+        2f64.powf(self.pitch_semitones as f64 / 12.0)
+    }
+
+    /// Input frames the time-stretcher consumes per frame it outputs. The
+    /// resampler after it speeds things up by `pitch_factor` again.
+    fn tempo(&self) -> f64 {
+        self.playback_rate as f64 / self.pitch_factor()
+    }
+}
+
+impl StretchSettings {
+    fn to_bits(self) -> u64 {
+        (self.playback_rate.to_bits() as u64) << 32 | self.pitch_semitones.to_bits() as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            playback_rate: f32::from_bits((bits >> 32) as u32),
+            pitch_semitones: f32::from_bits(bits as u32),
+        }
+    }
+}
+
+/// Speed and pitch shared by every track's `TimeStretch` stage. Both fit in
+/// one atomic, so the audio thread reads them without a lock.
+pub struct StretchControl {
+    settings: AtomicU64,
+    version: AtomicU64,
+}
+
+impl Default for StretchControl {
+    fn default() -> Self {
+        Self {
+            settings: AtomicU64::new(StretchSettings::default().to_bits()),
+            version: AtomicU64::new(0),
+        }
+    }
+}
+
+impl StretchControl {
+    pub fn settings(&self) -> StretchSettings {
+        StretchSettings::from_bits(self.settings.load(Ordering::SeqCst))
+    }
+
+    fn update(&self, edit: impl Fn(&mut StretchSettings)) {
+        let _ = self
+            .settings
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bits| {
+                let mut settings = StretchSettings::from_bits(bits);
+                edit(&mut settings);
+                Some(settings.to_bits())
+            });
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn set_playback_rate(&self, rate: f32) {
+        self.update(|settings| {
+            settings.playback_rate = rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE);
+        });
+    }
+
+    pub fn set_pitch_semitones(&self, semitones: f32) {
+        self.update(|settings| {
+            settings.pitch_semitones = semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES);
+        });
+    }
+}
+
+/// Waveform-similarity overlap-add time-stretcher working on interleaved frames.
+struct Wsola {
+    channels: usize,
+    segment: usize,
+    hop: usize,
+    search: usize,
+    compare: usize,
+    window: Vec<f32>,
+    // Interleaved input not yet consumed.
+    input: Vec<f32>,
+    // Nominal start of the next segment, in frames into `input`.
+    position: f64,
+    // Where the previous segment would naturally continue, in frames into `input`.
+    continuation: Option<usize>,
+    // Second, windowed half of the previous segment.
+    overlap: Vec<f32>,
+}
+
+impl Wsola {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let frames = |duration: Duration| (duration.as_secs_f64() * sample_rate as f64) as usize;
+        // Even length so the two halves of the Hann window add up to one.
+        let segment = (frames(SEGMENT) / 2 * 2).max(2);
+        let hop = segment / 2;
+
+        Self {
+            channels,
+            segment,
+            hop,
+            search: frames(SEARCH),
+            compare: frames(COMPARE).clamp(1, hop),
+            window: (0..segment)
+                .map(|n| (0.5 - 0.5 * (2.0 * PI * n as f64 / segment as f64).cos()) as f32)
+                .collect(),
+            input: Vec::new(),
+            position: 0.0,
+            continuation: None,
+            overlap: vec![0.0; hop * channels],
+        }
+    }
+
+    fn input_frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    /// Input frames that must be buffered before `step` can run.
+    fn needed_frames(&self) -> usize {
+        self.position as usize + self.search + self.segment + 1
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        self.input[frame * self.channels..(frame + 1) * self.channels]
+            .iter()
+            .sum()
+    }
+
+    /// Normalised cross-correlation of the frames at `candidate` with the
+    /// natural continuation of the previous segment.
+    fn similarity(&self, continuation: usize, candidate: usize) -> f32 {
+        let mut correlation = 0.0;
+        let mut energy = 0.0;
+        for n in 0..self.compare {
+            let value = self.mono(candidate + n);
+            correlation += self.mono(continuation + n) * value;
+            energy += value * value;
+        }
+        correlation / energy.sqrt().max(1e-9)
+    }
+
+    fn best_match(&self, continuation: usize, nominal: usize) -> usize {
+        let low = nominal.saturating_sub(self.search);
+        let high = nominal + self.search;
+        let best_in = |candidates: &mut dyn Iterator<Item = usize>| {
+            candidates
+                .map(|candidate| (candidate, self.similarity(continuation, candidate)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(nominal, |(candidate, _)| candidate)
+        };
+
+        let coarse = best_in(&mut (low..=high).step_by(COARSE_STEP));
+        let fine_low = coarse.saturating_sub(COARSE_STEP - 1).max(low);
+        let fine_high = (coarse + COARSE_STEP - 1).min(high);
+        best_in(&mut (fine_low..=fine_high))
+    }
+
+    /// Emits `hop` frames into `output`. Needs `needed_frames` buffered.
+    fn step(&mut self, tempo: f64, output: &mut Vec<f32>) {
+        let nominal = self.position.round() as usize;
+        let start = match self.continuation {
+            Some(continuation) => self.best_match(continuation, nominal),
+            None => nominal,
+        };
+
+        let channels = self.channels;
+        for n in 0..self.hop {
+            for channel in 0..channels {
+                let sample = self.input[(start + n) * channels + channel];
+                output.push(self.overlap[n * channels + channel] + self.window[n] * sample);
+            }
+        }
+        for n in 0..self.hop {
+            for channel in 0..channels {
+                let sample = self.input[(start + self.hop + n) * channels + channel];
+                self.overlap[n * channels + channel] = self.window[self.hop + n] * sample;
+            }
+        }
+
+        let continuation = start + self.hop;
+        self.position += self.hop as f64 * tempo;
+
+        // Drop input nothing will look at again.
+        let unused = (self.position as usize)
+            .saturating_sub(self.search)
+            .min(continuation);
+        if unused >= self.segment {
+            self.input.drain(..unused * channels);
+            self.position -= unused as f64;
+            self.continuation = Some(continuation - unused);
+        } else {
+            self.continuation = Some(continuation);
+        }
+    }
+}
+
+/// Cubic resampler consuming `factor` input frames per output frame.
+struct Resampler {
+    channels: usize,
+    frames: Vec<f32>,
+    // Read position in frames into `frames`, kept at least one frame in so
+    // the interpolation has a sample on each side.
+    position: f64,
+}
+
+impl Resampler {
+    fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            frames: vec![0.0; channels],
+            position: 1.0,
+        }
+    }
+
+    fn process(&mut self, factor: f64, input: &mut Vec<f32>, output: &mut Vec<f32>) {
+        self.frames.append(input);
+        let channels = self.channels;
+        let available = self.frames.len() / channels;
+
+        while (self.position as usize) + 2 < available {
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            for channel in 0..channels {
+                let at = |frame: usize| self.frames[frame * channels + channel];
+                output.push(catmull_rom(
+                    at(index - 1),
+                    at(index),
+                    at(index + 1),
+                    at(index + 2),
+                    t,
+                ));
+            }
+            self.position += factor;
+        }
+
+        let consumed = (self.position as usize).saturating_sub(1).min(available);
+        self.frames.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+}
+
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+    let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+    let c = -0.5 * p0 + 0.5 * p2;
+    ((a * t + b) * t + c) * t + p1
+}
+
+/// Source stage changing speed and pitch independently: WSOLA stretches the
+/// track by `tempo`, then resampling by `pitch_factor` shifts the pitch.
+/// Sits right after the `TrackSource`, so positions stay in track time.
+pub struct TimeStretch<S> {
+    inner: S,
+    control: Arc<StretchControl>,
+    track: Arc<TrackControl>,
+    version: u64,
+    seeks: u64,
+    settings: StretchSettings,
+    channels: usize,
+    wsola: Wsola,
+    resampler: Resampler,
+    stretched: Vec<f32>,
+    // Frame of `wsola.input` at which the track ran out, once it has.
+    input_end: Option<usize>,
+    // Whether stretching has played out the whole track.
+    ended: bool,
+    output: Vec<f32>,
+    offset: usize,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<StretchControl>, track: Arc<TrackControl>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        let mut stretch = Self {
+            wsola: Wsola::new(channels, sample_rate),
+            resampler: Resampler::new(channels),
+            seeks: track.seek_count(),
+            inner,
+            control,
+            track,
+            version: u64::MAX,
+            settings: StretchSettings::default(),
+            channels,
+            stretched: Vec::new(),
+            input_end: None,
+            ended: false,
+            output: Vec::new(),
+            offset: 0,
+        };
+        // Known settings tell `current_frame_len` whether the stage is bypassed.
+        stretch.refresh();
+        stretch
+    }
+
+    /// Picks up new settings, and drops buffered audio after a seek.
+    fn refresh(&mut self) {
+        let seeks = self.track.seek_count();
+        if seeks != self.seeks {
+            self.seeks = seeks;
+            self.reset();
+        }
+
+        let version = self.control.version.load(Ordering::Relaxed);
+        if version == self.version {
+            return;
+        }
+        let settings = self.control.settings();
+        if settings.is_identity() != self.settings.is_identity() {
+            self.reset();
+        }
+        self.settings = settings;
+        self.version = version;
+    }
+
+    fn reset(&mut self) {
+        self.wsola = Wsola::new(self.channels, self.inner.sample_rate());
+        self.resampler = Resampler::new(self.channels);
+        self.stretched.clear();
+        self.input_end = None;
+        self.ended = false;
+    }
+
+    /// Produces the next batch of stretched samples into `output`. Leaves it
+    /// empty once the track is over or the stage is bypassed.
+    fn refill(&mut self) {
+        self.output.clear();
+        self.offset = 0;
+
+        loop {
+            self.refresh();
+
+            if self.settings.is_identity() {
+                return;
+            }
+
+            while self.wsola.input_frames() < self.wsola.needed_frames() {
+                if self.input_end.is_some() {
+                    // Pad the end so the last segments can still be read.
+                    self.wsola.input.extend(std::iter::repeat_n(0.0, self.channels));
+                    continue;
+                }
+                let frame: Vec<f32> = self.inner.by_ref().take(self.channels).collect();
+                if frame.len() < self.channels {
+                    self.input_end = Some(self.wsola.input_frames());
+                } else {
+                    self.wsola.input.extend(frame);
+                }
+            }
+            if self
+                .input_end
+                .is_some_and(|end| self.wsola.position >= end as f64)
+            {
+                self.ended = true;
+                return;
+            }
+
+            let input_before = self.wsola.input_frames();
+            self.wsola.step(self.settings.tempo(), &mut self.stretched);
+            if let Some(end) = &mut self.input_end {
+                // Input dropped past the end leaves only padding behind.
+                *end = end.saturating_sub(input_before - self.wsola.input_frames());
+            }
+
+            let factor = self.settings.pitch_factor();
+            if factor == 1.0 {
+                self.output.append(&mut self.stretched);
+            } else {
+                self.resampler.process(factor, &mut self.stretched, &mut self.output);
+            }
+            if !self.output.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.offset >= self.output.len() {
+            self.refresh();
+            if self.settings.is_identity() {
+                return self.inner.next();
+            }
+            self.refill();
+        }
+        let sample = *self.output.get(self.offset)?;
+        self.offset += 1;
+        // Stretch the next batch right away so `current_frame_len` only drops
+        // to zero at the end of the track.
+        if self.offset == self.output.len() {
+            self.refill();
+        }
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.output.len() - self.offset, None)
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.offset < self.output.len() {
+            Some(self.output.len() - self.offset)
+        } else if self.settings.is_identity() {
+            self.inner.current_frame_len()
+        } else {
+            // Nothing is known before the first batch is stretched.
+            self.ended.then_some(0)
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    /// Stretches `input` (mono) by `tempo`, as far as the buffered input allows.
+    fn stretch(input: &[f32], tempo: f64) -> Vec<f32> {
+        let mut wsola = Wsola::new(1, RATE);
+        wsola.input.extend_from_slice(input);
+        let mut output = Vec::new();
+        while wsola.input_frames() >= wsola.needed_frames() {
+            wsola.step(tempo, &mut output);
+        }
+        output
+    }
+
+    fn sine(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|n| (2.0 * PI * 441.0 * n as f64 / RATE as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn output_length_follows_the_tempo() {
+        let input = sine(RATE as usize * 4);
+        for tempo in [0.5, 1.0, 1.5, 2.0] {
+            let output = stretch(&input, tempo);
+            let expected = input.len() as f64 / tempo;
+            // Only the last segment and the search margin are held back.
+            let held_back = (0.1 * RATE as f64) / tempo;
+            assert!(
+                output.len() as f64 <= expected && output.len() as f64 >= expected - held_back,
+                "tempo {}: {} frames, expected about {}",
+                tempo,
+                output.len(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn segments_line_up_without_dropouts() {
+        let input = sine(RATE as usize * 2);
+        for tempo in [0.75, 1.25] {
+            let output = stretch(&input, tempo);
+            // The first half segment fades in from nothing.
+            let settled = &output[Wsola::new(1, RATE).hop..];
+            let peak = settled.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+            let jump = settled
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .fold(0f32, f32::max);
+
+            assert!(peak > 0.95 && peak < 1.05, "tempo {}: peak {}", tempo, peak);
+            // A 441 Hz sine moves at most about 0.063 per sample.
+            assert!(jump < 0.08, "tempo {}: jump {}", tempo, jump);
+        }
+    }
+
+    /// Reads `source` a frame at a time, checking that the frame length only
+    /// drops to zero at the end. Returns the number of samples read.
+    fn read_frames(mut source: impl Source<Item = f32>) -> usize {
+        let mut read = 0;
+        loop {
+            let len = match source.current_frame_len() {
+                Some(0) => {
+                    assert_eq!(source.next(), None);
+                    return read;
+                }
+                Some(len) => len,
+                // Only before the first sample, see `TimeStretch::current_frame_len`.
+                None if read == 0 => 1,
+                None => panic!("unknown frame length at {}", read),
+            };
+            for _ in 0..len {
+                assert!(source.next().is_some(), "frame ended early at {}", read);
+            }
+            read += len;
+        }
+    }
+
+    fn time_stretch<S>(input: S, control: Arc<StretchControl>) -> TimeStretch<S>
+    where
+        S: Source<Item = f32>,
+    {
+        let format = crate::decoder::SourceFormat {
+            codec: "pcm".to_string(),
+            sample_rate: RATE,
+            bits_per_sample: None,
+            channels: 2,
+        };
+        let (events, _) = std::sync::mpsc::channel();
+        let track = crate::track::TrackSource::new(
+            rodio::buffer::SamplesBuffer::new(2, RATE, Vec::<f32>::new()),
+            events,
+            None,
+            format,
+            None,
+        );
+        TimeStretch::new(input, control, track.control())
+    }
+
+    #[test]
+    fn frame_length_counts_the_stretched_block() {
+        let control = Arc::new(StretchControl::default());
+        control.set_playback_rate(1.5);
+
+        let input = rodio::buffer::SamplesBuffer::new(2, RATE, sine(2 * RATE as usize));
+        let read = read_frames(time_stretch(input, control));
+
+        let expected = 2.0 * RATE as f64 / 1.5;
+        assert!((read as f64 - expected).abs() < 0.1 * RATE as f64, "{} samples", read);
+    }
+
+    #[test]
+    fn bypassed_stage_passes_the_frame_length_through() {
+        let input = rodio::source::Zero::new_samples(2, RATE, 10_000);
+        let stretch = time_stretch(input, Arc::default());
+
+        assert_eq!(stretch.current_frame_len(), Some(10_000));
+        assert_eq!(read_frames(stretch), 10_000);
+    }
+}
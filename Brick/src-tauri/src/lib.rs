@@ -1,166 +1,2409 @@
-use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ImageEncoder};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType};
 use lofty::{Accessor, AudioFile, Probe, TaggedFileExt};
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use rodio::{Sink, Source};
 use std::{
+    collections::HashMap,
     fs::File,
     io::BufReader,
     path::PathBuf,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use tauri::{Emitter, State};
 use dirs::data_dir;
 use sha2::{Digest, Sha256};
 
+mod crossfade;
+mod cue;
+mod decoder;
+mod dsp;
+mod loudness;
+mod output;
+mod preload;
+mod queue;
+mod session;
+mod settings;
+mod sleep;
+mod spectrum;
+mod storage;
+mod stream;
+mod tags;
+mod track;
+mod waveform;
+
+use crossfade::{CrossfadeSettings, FadeCurve};
+use decoder::{SourceFormat, SupportedFormats, SymphoniaDecoder};
+use dsp::{
+    channels::{ChannelSettings, Crossfeed},
+    declick::DeclickSettings,
+    eq::{EqMode, EqSettings},
+    replaygain::{ReplayGainMode, ReplayGainSettings},
+    DspControls,
+};
+use loudness::{meter::LevelMeter, LoudnessCache, ScanError, ScanJobs, ScanRequest};
+use output::{OutputBackend, OutputFormat, SampleTap};
+use preload::{Preload, PreloadPayload, PreloadSettings, PreloadStatus};
+use queue::{PlayQueue, QueueEntry, QueueSnapshot, RepeatMode};
+use session::StoredSession;
+use settings::StoredSettings;
+use sleep::{Following, SleepMode, SleepTimer, SleepTimerPayload};
+use spectrum::{SpectrumAnalyzer, SpectrumSettings, SpectrumWindow};
+use stream::StreamStatus;
+use tags::TrackTags;
+use track::{Seekable, TrackControl, TrackEvent, TrackSource};
+use waveform::{Waveform, WaveformCache};
+
 /// Shared audio playback state managed on the Rust side.
 pub struct AudioState {
-    // The `OutputStream` is purposely not stored inside the shared state so the
-    // state remains `Send + Sync`. We keep the `OutputStream` alive in the
-    // `run()` function so the stream does not get dropped. The `stream_handle`
-    // is used to create sinks from other threads safely.
-    stream_handle: OutputStreamHandle,
+    // Sound device or headless sink every `Sink` plays through; `None` while
+    // no device could be opened.
+    output: Option<Box<dyn OutputBackend>>,
     sink: Sink,
     current_file: Option<String>,
     volume: f32,
+    queue: PlayQueue,
+    // The track appended to `sink` behind the current one so the transition is
+    // gapless. It must always match `queue.peek_next()`.
+    pending: Option<PendingTrack>,
+    // Control of the track the output is currently playing.
+    current: Option<Arc<TrackControl>>,
+    // The next queue entry, decoded ahead once the current track is within
+    // the preload lead of its end. `schedule_next` waits for it.
+    preload: Option<Preload>,
+    preload_settings: PreloadSettings,
+    track_events: Sender<TrackEvent>,
+    progress_interval: Duration,
+    crossfade: CrossfadeSettings,
+    // Queue entry the current track will crossfade into once its tail is
+    // reached, used instead of `pending` when crossfading.
+    crossfade_to: Option<u64>,
+    // Sink of the previous track while it fades out under the current one,
+    // with the control of that track.
+    fading: Option<(Arc<TrackControl>, Sink)>,
+    dsp: DspControls,
+    tag_cache: HashMap<String, TrackTags>,
+    loudness_cache: LoudnessCache,
+    loudness_jobs: ScanJobs,
+    waveforms: Arc<WaveformCache>,
+    waveform_jobs: ScanJobs,
+    sleep_timer: Option<SleepTimer>,
+    // Copies of what the output plays, collected while the visualizer or the
+    // level meter is on.
+    tap: Arc<SampleTap>,
+    spectrum: SpectrumSettings,
+    visualizer_enabled: bool,
+    meter_enabled: bool,
+    // Asks the analysis thread to reset the latched clip flags.
+    clear_clip: bool,
+    // A stream connecting in the background, played by `finish_opening` once
+    // it is ready.
+    opening: Option<Opening>,
+    // Whether a streamed current track was last reported as buffering.
+    buffering: bool,
+    // Whether the session on disk may be overwritten, which is only once it
+    // was restored or something else was played.
+    session_active: bool,
+    // Entries dropped because they failed to open, with why, until
+    // `tick_progress` reports them.
+    skipped: Vec<(String, String)>,
+    // Why the output couldn't be opened, reported with `no-output`.
+    output_error: Option<String>,
+}
+
+struct Opening {
+    entry: QueueEntry,
+    preload: Preload,
+    position: Option<Duration>,
+    paused: bool,
+}
+
+struct PendingTrack {
+    entry_id: u64,
+    control: Arc<TrackControl>,
+}
+
+/// A queue entry decoded and wrapped in the DSP chain, ready for a sink.
+struct OpenedTrack {
+    control: Arc<TrackControl>,
+    source: Box<dyn Source<Item = f32> + Send>,
+}
+
+// Shortest A-B loop accepted by `set_loop_region`.
+const MIN_LOOP_SECONDS: f32 = 0.05;
+
+// Delay between two attempts at opening a missing output device.
+const OUTPUT_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+// Time given to the output on top of a declick ramp before a sink is paused
+// or stopped, since the mixer pulls samples a buffer at a time.
+const DECLICK_MARGIN: Duration = Duration::from_millis(50);
+
+// How often the sleep timer updates its fade and countdown.
+const SLEEP_TIMER_TICK: Duration = Duration::from_millis(100);
+// Fade-out of a sleep timer when none is given.
+const DEFAULT_SLEEP_FADE: Duration = Duration::from_secs(10);
+
+// How often the analysis thread checks whether the visualizer or the level
+// meter was turned on.
+const ANALYSIS_IDLE_INTERVAL: Duration = Duration::from_millis(200);
+// Time between two `native-audio://levels` events.
+const LEVEL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How often the playback session is written to disk while it changes.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Upper bound on cached tags before the cache is dropped and rebuilt.
+const TAG_CACHE_LIMIT: usize = 2048;
+
+impl AudioState {
+    /// State with nothing playing, whose sinks play through `output` and
+    /// whose tracks report to `track_events`.
+    fn new(
+        output: Option<Box<dyn OutputBackend>>,
+        tap: Arc<SampleTap>,
+        track_events: Sender<TrackEvent>,
+    ) -> Self {
+        let sink = output
+            .as_ref()
+            .and_then(|output| output.new_sink().ok())
+            .unwrap_or_else(|| Sink::new_idle().0);
+
+        Self {
+            output,
+            sink,
+            current_file: None,
+            volume: 1.0,
+            queue: PlayQueue::default(),
+            pending: None,
+            current: None,
+            preload: None,
+            preload_settings: PreloadSettings::default(),
+            track_events,
+            progress_interval: Duration::from_millis(250),
+            crossfade: CrossfadeSettings::default(),
+            crossfade_to: None,
+            fading: None,
+            dsp: DspControls::default(),
+            tag_cache: HashMap::new(),
+            loudness_cache: LoudnessCache::default(),
+            loudness_jobs: ScanJobs::default(),
+            waveforms: Arc::new(WaveformCache::default()),
+            waveform_jobs: ScanJobs::default(),
+            sleep_timer: None,
+            tap,
+            spectrum: SpectrumSettings::default(),
+            visualizer_enabled: false,
+            meter_enabled: false,
+            clear_clip: false,
+            opening: None,
+            buffering: false,
+            session_active: false,
+            skipped: Vec::new(),
+            output_error: None,
+        }
+    }
+
+    fn tags(&mut self, file_path: &str) -> TrackTags {
+        if let Some(tags) = self.tag_cache.get(file_path) {
+            return tags.clone();
+        }
+        self.cache_tags(file_path, TrackTags::read(file_path))
+    }
+
+    /// Keeps tags read for `file_path`, filling in the ReplayGain of files
+    /// without it from the loudness analysis.
+    fn cache_tags(&mut self, file_path: &str, mut tags: TrackTags) -> TrackTags {
+        if self.tag_cache.len() >= TAG_CACHE_LIMIT {
+            self.tag_cache.clear();
+        }
+        if tags.replay_gain.track_gain.is_none() {
+            if let Some(result) = self.loudness_cache.get(file_path) {
+                tags.replay_gain = result.replay_gain();
+            }
+        }
+        self.tag_cache.insert(file_path.to_string(), tags.clone());
+        tags
+    }
+
+    /// Shuffles the queue with `seed`, keeping tracks of the same artist or
+    /// album apart where the queue allows it. `tags` comes from `read_tags`;
+    /// entries missing from it are kept apart from nothing.
+    fn shuffle_queue(&mut self, seed: u64, tags: HashMap<String, TrackTags>) {
+        for (file_path, tags) in &tags {
+            if !self.tag_cache.contains_key(file_path) {
+                self.cache_tags(file_path, tags.clone());
+            }
+        }
+        let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+
+        self.queue.shuffle(seed, |a, b| match (tags.get(&a.file_path), tags.get(&b.file_path)) {
+            (Some(a), Some(b)) => same(&a.artist, &b.artist) || same(&a.album, &b.album),
+            _ => false,
+        });
+    }
+
+    /// Whether the entry sits next to a neighbouring track of its album in
+    /// the queue, i.e. the album is being played in order.
+    fn plays_as_album(&mut self, entry_id: u64, tags: &TrackTags) -> bool {
+        let (previous, next) = self.queue.neighbours(entry_id);
+        let (previous, next) = (previous.cloned(), next.cloned());
+
+        previous.is_some_and(|previous| self.tags(&previous.file_path).precedes_on_album(tags))
+            || next.is_some_and(|next| tags.precedes_on_album(&self.tags(&next.file_path)))
+    }
+
+    fn open_entry(
+        &mut self,
+        entry: &QueueEntry,
+        fade_in: Option<(Duration, FadeCurve)>,
+    ) -> Result<OpenedTrack, String> {
+        let preload = self
+            .preload
+            .iter()
+            .chain(self.opening.as_ref().map(|opening| &opening.preload))
+            .find(|preload| preload.entry_id == entry.id);
+        match preload.and_then(|preload| preload.claim()) {
+            Some(decoder) => {
+                let format = decoder.source_format();
+                let stream = decoder.stream_status();
+                Ok(self.wrap_track(entry, decoder, format, stream, fade_in))
+            }
+            // Streams are only opened by a preload, off the state lock.
+            None if stream::is_url(&entry.file_path) => Err(preload
+                .and_then(|preload| preload.error())
+                .unwrap_or_else(|| "Stream error: not connected yet".to_string())),
+            None => {
+                let decoder = SymphoniaDecoder::open(&entry.file_path)?;
+                let format = decoder.source_format();
+                let stream = decoder.stream_status();
+                Ok(self.wrap_track(entry, decoder, format, stream, fade_in))
+            }
+        }
+    }
+
+    fn wrap_track<S>(
+        &mut self,
+        entry: &QueueEntry,
+        decoder: S,
+        format: SourceFormat,
+        stream: Option<Arc<StreamStatus>>,
+        fade_in: Option<(Duration, FadeCurve)>,
+    ) -> OpenedTrack
+    where
+        S: Source<Item = f32> + Seekable + Send + 'static,
+    {
+        let tags = self.tags(&entry.file_path);
+        let duration = decoder.total_duration().or(tags.duration);
+
+        let mut track = TrackSource::new(decoder, self.track_events.clone(), duration, format, stream);
+        if let Some((length, curve)) = fade_in {
+            track = track.with_fade_in(length, curve);
+        }
+        let control = track.control();
+        let in_album = self.plays_as_album(entry.id, &tags);
+
+        OpenedTrack {
+            control,
+            source: self.dsp.build_chain(track, tags.replay_gain, in_album),
+        }
+    }
+
+    /// Payload for `native-audio://state` describing the current playback.
+    fn state_payload(&self, status: &str) -> AudioEventPayload {
+        let stretch = self.dsp.stretch.settings();
+        AudioEventPayload {
+            status: status.to_string(),
+            file_path: self.current_file.clone(),
+            position: None,
+            volume: Some(self.volume),
+            playback_rate: stretch.playback_rate,
+            pitch_semitones: stretch.pitch_semitones,
+            loop_region: self
+                .current
+                .as_ref()
+                .and_then(|current| current.loop_region())
+                .map(|(start, end)| LoopRegion {
+                    start: start.as_secs_f32(),
+                    end: end.as_secs_f32(),
+                }),
+            format: self.playback_format(),
+            error: None,
+        }
+    }
+
+    /// Formats of the current track and of the output, `None` with neither.
+    fn playback_format(&self) -> Option<PlaybackFormat> {
+        let source = self.current.as_ref().map(|current| current.source_format().clone());
+        let output = self.output.as_ref().map(|output| output.format());
+        let (resampling, channel_conversion) = match (&source, &output) {
+            (Some(source), Some(output)) => (
+                source.sample_rate != output.sample_rate,
+                source.channels != output.channels,
+            ),
+            _ => (false, false),
+        };
+
+        (source.is_some() || output.is_some()).then_some(PlaybackFormat {
+            source,
+            output,
+            resampling,
+            channel_conversion,
+        })
+    }
+
+    /// Writes the settings kept across sessions.
+    fn save_settings(&self) -> Result<(), String> {
+        let settings = StoredSettings {
+            channels: self.dsp.channels.settings()?,
+        };
+        settings.save()
+    }
+
+    /// The session to write to disk, `None` while the stored one is still
+    /// waiting to be restored.
+    fn session(&self) -> Option<StoredSession> {
+        self.session_active.then(|| StoredSession {
+            queue: self.queue.stored(),
+            position: self
+                .current
+                .as_ref()
+                .map(|current| current.position())
+                .or(self.opening.as_ref().and_then(|opening| opening.position))
+                .map_or(0.0, |position| position.as_secs_f32()),
+            volume: self.volume,
+        })
+    }
+
+    /// Collects output samples only while something analyses them.
+    fn update_tap(&self) {
+        self.tap.set_enabled(self.visualizer_enabled || self.meter_enabled);
+    }
+
+    /// Volume the sinks play at: the user's volume, lowered while a sleep
+    /// timer fades out.
+    fn output_volume(&self) -> f32 {
+        self.volume * self.sleep_timer.as_ref().map_or(1.0, |timer| timer.gain)
+    }
+
+    fn apply_volume(&self) {
+        let volume = self.output_volume();
+        self.sink.set_volume(volume);
+        if let Some((_, fading)) = &self.fading {
+            fading.set_volume(volume);
+        }
+    }
+
+    fn new_sink(&self) -> Result<Sink, String> {
+        self.output
+            .as_ref()
+            .ok_or_else(|| "No audio output available".to_string())?
+            .new_sink()
+    }
+
+    /// Jumps to a queue entry on a fresh sink, skipping whatever was playing.
+    fn play_entry(&mut self, entry: QueueEntry) -> Result<(), String> {
+        self.load_entry(entry, None, false)
+    }
+
+    /// Puts a queue entry on a fresh sink, optionally starting at `position`
+    /// and left paused.
+    fn load_entry(
+        &mut self,
+        entry: QueueEntry,
+        position: Option<Duration>,
+        paused: bool,
+    ) -> Result<(), String> {
+        if stream::is_url(&entry.file_path) && !self.preload_ready(entry.id) {
+            return self.open_stream(entry, position, paused);
+        }
+        let track = self.open_entry(&entry, None)?;
+        if let Some(position) = position {
+            track.control.seek_smoothly(position);
+        }
+        // Ramped in when resumed.
+        track.control.set_gate(!paused);
+
+        let new_sink = self
+            .new_sink()
+            .map_err(|e| format!("Sink creation error: {}", e))?;
+        new_sink.set_volume(self.output_volume());
+        if paused {
+            new_sink.pause();
+        }
+        new_sink.append(track.source);
+
+        let old_sink = std::mem::replace(&mut self.sink, new_sink);
+        self.retire(old_sink);
+        self.pending = None;
+        self.crossfade_to = None;
+        self.current = Some(track.control);
+        self.current_file = Some(entry.file_path.clone());
+        self.queue.select(entry.id);
+        self.opening = None;
+        self.buffering = false;
+        self.schedule_next();
+        self.session_active = true;
+        Ok(())
+    }
+
+    /// Whether a preload holds the entry's decoder, open or failed.
+    fn preload_ready(&self, entry_id: u64) -> bool {
+        self.preload
+            .iter()
+            .chain(self.opening.as_ref().map(|opening| &opening.preload))
+            .any(|preload| preload.entry_id == entry_id && preload.status() != PreloadStatus::Opening)
+    }
+
+    /// Silences what was playing and connects to a stream in the background.
+    /// `finish_opening` puts it on a sink once it is ready.
+    fn open_stream(
+        &mut self,
+        entry: QueueEntry,
+        position: Option<Duration>,
+        paused: bool,
+    ) -> Result<(), String> {
+        self.stop()?;
+        self.current_file = Some(entry.file_path.clone());
+        self.queue.select(entry.id);
+        self.opening = Some(Opening {
+            preload: Preload::start(entry.id, entry.file_path.clone(), &self.preload_settings),
+            entry,
+            position,
+            paused,
+        });
+        self.buffering = true;
+        self.session_active = true;
+        Ok(())
+    }
+
+    /// Plays the stream `open_stream` connected to once it is ready. Returns
+    /// `None` while it is still connecting.
+    fn finish_opening(&mut self) -> Option<Result<(), String>> {
+        let opening = self.opening.as_ref()?;
+        if opening.preload.status() == PreloadStatus::Opening {
+            return None;
+        }
+        let (entry, position, paused) = (opening.entry.clone(), opening.position, opening.paused);
+        let result = self.load_entry(entry, position, paused);
+        if result.is_err() {
+            self.opening = None;
+            self.current_file = None;
+            self.buffering = false;
+        }
+        Some(result)
+    }
+
+    /// Stops playback and forgets the current track. The queue is kept.
+    fn stop(&mut self) -> Result<(), String> {
+        // Without an output there is nothing to play on; `attach_output` gives
+        // the state a real sink again.
+        let new_sink = match self.output {
+            Some(_) => self
+                .new_sink()
+                .map_err(|e| format!("Sink creation error: {}", e))?,
+            None => Sink::new_idle().0,
+        };
+        let old_sink = std::mem::replace(&mut self.sink, new_sink);
+        self.retire(old_sink);
+        self.pending = None;
+        self.crossfade_to = None;
+        self.current = None;
+        self.current_file = None;
+        self.preload = None;
+        self.opening = None;
+        Ok(())
+    }
+
+    /// Tracks that can currently be heard or are about to be: the current
+    /// one, the one fading out under it and the one queued behind it.
+    fn audible_tracks(&self) -> Vec<Arc<TrackControl>> {
+        self.current
+            .iter()
+            .chain(self.fading.as_ref().map(|(fading, _)| fading))
+            .chain(self.pending.as_ref().map(|pending| &pending.control))
+            .cloned()
+            .collect()
+    }
+
+    /// Ramps every audible track down to silence or back up.
+    fn set_gates(&self, open: bool) {
+        for track in self.audible_tracks() {
+            track.set_gate(open);
+        }
+    }
+
+    /// Ramps down what `sink` and the fading sink play and stops both once
+    /// the ramp has been played, without holding up the caller.
+    fn retire(&mut self, sink: Sink) {
+        self.set_gates(false);
+        let fading = self.fading.take().map(|(_, fading)| fading);
+        let wait = self.dsp.declick.ramp() + DECLICK_MARGIN;
+        thread::spawn(move || {
+            thread::sleep(wait);
+            sink.stop();
+            if let Some(fading) = fading {
+                fading.stop();
+            }
+        });
+    }
+
+    /// Drops an output that stopped working and pauses. The current track
+    /// keeps its position so `attach_output` can pick it up again.
+    fn detach_output(&mut self) {
+        self.sink.pause();
+        self.cancel_next();
+        self.fading = None;
+        self.output = None;
+    }
+
+    /// Installs a freshly opened output and moves playback onto it, paused
+    /// where it was when the previous output went away.
+    fn attach_output(&mut self, output: Box<dyn OutputBackend>) -> Result<(), String> {
+        self.output = Some(output);
+        self.output_error = None;
+
+        let position = self.current.as_ref().map(|current| current.position());
+        match (self.queue.current().cloned(), position) {
+            (Some(entry), Some(position)) => self.load_entry(entry, Some(position), true),
+            _ => {
+                self.sink = self.new_sink()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes sure the sink holds exactly the next queue entry behind the
+    /// current track, appending it ahead of time for a gapless transition.
+    fn schedule_next(&mut self) {
+        let stops = self.sleep_ends_with_current();
+        let wanted = self
+            .queue
+            .peek_next()
+            .map(|entry| entry.id)
+            .filter(|_| !stops);
+        let scheduled = self
+            .pending
+            .as_ref()
+            .map(|pending| pending.entry_id)
+            .or(self.crossfade_to);
+        if scheduled == wanted {
+            return;
+        }
+
+        self.cancel_next();
+
+        if self.current_file.is_none() || stops {
+            return;
+        }
+
+        while let Some(entry) = self.queue.peek_next().cloned() {
+            // `maintain_preload` calls back once the decoder is open. Files can
+            // crossfade before that, streams can't be opened without it.
+            let ready = self.preload_ready(entry.id);
+            if (ready || !stream::is_url(&entry.file_path)) && self.arm_crossfade(&entry.file_path) {
+                self.crossfade_to = Some(entry.id);
+                return;
+            }
+            if !ready {
+                return;
+            }
+
+            match self.open_entry(&entry, None) {
+                Ok(track) => {
+                    self.pending = Some(PendingTrack {
+                        entry_id: entry.id,
+                        control: track.control,
+                    });
+                    self.sink.append(track.source);
+                    return;
+                }
+                Err(e) => {
+                    self.skipped.push((entry.file_path.clone(), e));
+                    // Repeating the playing entry must not drop it from the queue.
+                    if !self.queue.remove_id(entry.id) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a sleep timer stops playback once the current track ends, so
+    /// nothing must be scheduled behind it.
+    fn sleep_ends_with_current(&mut self) -> bool {
+        let Some(mode) = self.sleep_timer.as_ref().map(|timer| timer.mode) else {
+            return false;
+        };
+        let Some(current) = self.queue.current().cloned() else {
+            return false;
+        };
+        let next = self.queue.neighbours(current.id).1.cloned();
+
+        match mode {
+            SleepMode::Minutes => false,
+            SleepMode::EndOfTrack => true,
+            SleepMode::EndOfQueue => next.is_none(),
+            SleepMode::EndOfAlbum => {
+                let tags = self.tags(&current.file_path);
+                !next.is_some_and(|next| tags.precedes_on_album(&self.tags(&next.file_path)))
+            }
+        }
+    }
+
+    /// Playing time left before the sleep timer stops playback, taking the
+    /// playback rate into account. `None` when it can't be told, e.g. for a
+    /// track of unknown length or while `plan_sleep_timer` hasn't caught up
+    /// with a changed queue.
+    fn sleep_remaining(&self) -> Option<Duration> {
+        let timer = self.sleep_timer.as_ref()?;
+        if let Some(remaining) = timer.deadline_remaining() {
+            return Some(remaining);
+        }
+        let current = self.current.as_ref()?;
+        let mut remaining = current.duration()?.saturating_sub(current.position());
+        if timer.mode != SleepMode::EndOfTrack {
+            let ids: Vec<u64> = self.queue.current_onwards().iter().map(|entry| entry.id).collect();
+            remaining += timer.following.as_ref()?.remaining(&ids)?;
+        }
+
+        Some(remaining.div_f32(self.dsp.stretch.settings().playback_rate))
+    }
+
+    fn sleep_payload(&self, fired: bool) -> SleepTimerPayload {
+        let remaining = self.sleep_remaining();
+        SleepTimerPayload {
+            mode: self.sleep_timer.as_ref().map(|timer| timer.mode),
+            remaining_seconds: remaining.map(|remaining| remaining.as_secs_f32()),
+            fade_seconds: self
+                .sleep_timer
+                .as_ref()
+                .map_or(0.0, |timer| timer.fade.as_secs_f32()),
+            fired,
+        }
+    }
+
+    /// Whether the current track is close enough to its end for the next one
+    /// to be decoded ahead. Tracks of unknown length always are.
+    fn within_preload_lead(&self) -> bool {
+        let Some(current) = &self.current else {
+            return false;
+        };
+        current.duration().is_none_or(|duration| {
+            duration.saturating_sub(current.position()) <= self.preload_settings.lead()
+        })
+    }
+
+    /// Starts decoding the next entry once the current track nears its end
+    /// and schedules it when its decoder is open. Returns the change to
+    /// report through `native-audio://preload`, if any.
+    fn maintain_preload(&mut self) -> Option<PreloadPayload> {
+        let stops = self.sleep_ends_with_current();
+        let next = self.queue.peek_next().filter(|_| !stops).cloned();
+        if self
+            .preload
+            .as_ref()
+            .is_some_and(|preload| Some(preload.entry_id) != next.as_ref().map(|entry| entry.id))
+        {
+            self.preload = None;
+        }
+        if self.preload.is_none() {
+            let next = next.filter(|_| self.within_preload_lead())?;
+            self.preload = Some(Preload::start(next.id, next.file_path, &self.preload_settings));
+        }
+
+        let preload = self.preload.as_mut()?;
+        let status = preload.status();
+        if preload.announced == Some(status) {
+            return None;
+        }
+        preload.announced = Some(status);
+        let payload = PreloadPayload {
+            status: match status {
+                PreloadStatus::Opening => "preloading",
+                PreloadStatus::Buffered => "preloaded",
+                _ => "",
+            }
+            .to_string(),
+            file_path: preload.file_path.clone(),
+            buffered_seconds: preload.buffered_seconds(),
+        };
+
+        if status != PreloadStatus::Opening {
+            self.schedule_next();
+        }
+        (!payload.status.is_empty()).then_some(payload)
+    }
+
+    /// Drops the preload once the track it was claimed by is playing.
+    fn release_preload(&mut self) {
+        if self.preload.as_ref().is_some_and(|preload| preload.is_claimed()) {
+            self.preload = None;
+        }
+    }
+
+    /// Forgets how the next track was scheduled so `schedule_next` decides again.
+    fn cancel_next(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.control.cancel();
+        }
+        if self.crossfade_to.take().is_some() {
+            if let Some(current) = &self.current {
+                current.disarm_tail();
+            }
+        }
+    }
+
+    /// Arms the current track to crossfade into `next_file` when crossfading is
+    /// on. Consecutive tracks of the same album stay gapless instead.
+    fn arm_crossfade(&mut self, next_file: &str) -> bool {
+        if !self.crossfade.enabled {
+            return false;
+        }
+        let (Some(current), Some(current_file)) = (self.current.clone(), self.current_file.clone())
+        else {
+            return false;
+        };
+        let next_tags = self.tags(next_file);
+        if self.tags(&current_file).precedes_on_album(&next_tags) {
+            return false;
+        }
+
+        current.arm_tail(self.crossfade.duration())
+    }
+
+    /// Called when the current track reaches its crossfade point: starts the
+    /// next track on a new sink, fading in, while the current one fades out.
+    fn on_track_tail(&mut self, id: u64) -> bool {
+        let Some(current) = self.current.clone().filter(|current| current.id() == id) else {
+            return false;
+        };
+        let Some(entry_id) = self.crossfade_to.take() else {
+            return false;
+        };
+        let Some(entry) = self
+            .queue
+            .peek_next()
+            .filter(|entry| entry.id == entry_id)
+            .cloned()
+        else {
+            self.schedule_next();
+            return false;
+        };
+
+        let length = current.tail_lead(self.crossfade.duration());
+        let curve = self.crossfade.curve;
+        let track = match self.open_entry(&entry, Some((length, curve))) {
+            Ok(track) => track,
+            Err(e) => {
+                self.skipped.push((entry.file_path.clone(), e));
+                if self.queue.remove_id(entry.id) {
+                    self.schedule_next();
+                }
+                return false;
+            }
+        };
+        // Without a sink the output is gone, which `watch_output` reports.
+        let Ok(new_sink) = self.new_sink() else {
+            return false;
+        };
+        new_sink.set_volume(self.output_volume());
+        new_sink.append(track.source);
+
+        let remaining = current
+            .duration()
+            .map_or(length, |duration| duration.saturating_sub(current.position()));
+        current.fade_out(length.min(remaining), curve);
+
+        let old_sink = std::mem::replace(&mut self.sink, new_sink);
+        self.fading = Some((current.clone(), old_sink));
+        self.current = Some(track.control);
+        self.current_file = Some(entry.file_path.clone());
+        self.queue.select(entry.id);
+        self.release_preload();
+        self.schedule_next();
+        true
+    }
+
+    /// Called when a track starts playing; promotes the pending track to
+    /// current if that's the one the output just reached.
+    fn on_track_started(&mut self, id: u64) -> bool {
+        let Some(pending) = self.pending.take_if(|pending| pending.control.id() == id) else {
+            return false;
+        };
+
+        self.current_file = self
+            .queue
+            .select(pending.entry_id)
+            .map(|entry| entry.file_path.clone());
+        self.current = Some(pending.control);
+        self.release_preload();
+        self.schedule_next();
+        true
+    }
+
+    /// Why the current track, if it is `id`, stopped before its end, with its file.
+    fn stream_error(&self, id: u64) -> Option<(Option<String>, String)> {
+        let current = self.current.as_ref().filter(|current| current.id() == id)?;
+        Some((self.current_file.clone(), current.stream_error()?))
+    }
+
+    /// Called when a track runs out of samples; returns the finished file when
+    /// nothing is queued behind it, i.e. the sink has drained.
+    fn on_track_finished(&mut self, id: u64) -> Option<String> {
+        if self.fading.as_ref().is_some_and(|(fading, _)| fading.id() == id) {
+            self.fading = None;
+            return None;
+        }
+
+        self.current.as_ref().filter(|current| current.id() == id)?;
+        if self.pending.is_some() {
+            return None;
+        }
+
+        self.current = None;
+        self.current_file.take()
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SongMetadata {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    duration: u64,
+    file_path: String,
+    cover_art_path: Option<String>,
+    /// Where a track of a cue sheet starts and ends in its file, in seconds.
+    /// The end is missing for the last track.
+    start: Option<f32>,
+    end: Option<f32>,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AudioEventPayload {
+    status: String,
+    file_path: Option<String>,
+    position: Option<f32>,
+    volume: Option<f32>,
+    playback_rate: f32,
+    pitch_semitones: f32,
+    loop_region: Option<LoopRegion>,
+    format: Option<PlaybackFormat>,
+    /// What went wrong, with the `error`, `skipped` and `no-output` statuses.
+    error: Option<String>,
+}
+
+/// Active A-B loop of the current track, in seconds.
+#[derive(Clone, serde::Serialize)]
+struct LoopRegion {
+    start: f32,
+    end: f32,
+}
+
+/// What the current track is decoded as and what the output plays. The mixer
+/// converts between the two when they differ.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaybackFormat {
+    source: Option<SourceFormat>,
+    output: Option<OutputFormat>,
+    /// The track is resampled to the output's sample rate.
+    resampling: bool,
+    /// The track is up- or downmixed to the output's channel count.
+    channel_conversion: bool,
+}
+
+fn emit_audio_state(app: &tauri::AppHandle, payload: AudioEventPayload) {
+    let _ = app.emit("native-audio://state", payload);
+}
+
+fn emit_audio_error(app: &tauri::AppHandle, audio: &AudioState, file_path: Option<String>, error: String) {
+    emit_audio_state(
+        app,
+        AudioEventPayload {
+            file_path,
+            error: Some(error),
+            ..audio.state_payload("error")
+        },
+    );
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProgressPayload {
+    file_path: Option<String>,
+    position: f32,
+    duration: Option<f32>,
+}
+
+fn emit_queue(app: &tauri::AppHandle, queue: &PlayQueue) {
+    let _ = app.emit("native-audio://queue", queue.snapshot());
+}
+
+fn emit_sleep_timer(app: &tauri::AppHandle, payload: SleepTimerPayload) {
+    let _ = app.emit("native-audio://sleep-timer", payload);
+}
+
+fn emit_track_started(app: &tauri::AppHandle, audio: &AudioState) {
+    emit_audio_state(
+        app,
+        AudioEventPayload {
+            position: Some(0.0),
+            ..audio.state_payload(if audio.buffering { "buffering" } else { "playing" })
+        },
+    );
+    emit_queue(app, &audio.queue);
+}
+
+#[tauri::command]
+fn greet(name: &str) -> String {
+    format!("Hello, {}! You've been greeted from Rust!", name)
+}
+
+/// Follows track transitions reported by the audio thread and keeps the
+/// queue, the preloaded next track and the frontend in sync.
+fn watch_tracks(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>, events: Receiver<TrackEvent>) {
+    for event in events {
+        let Ok(mut audio) = state.lock() else {
+            return;
+        };
+
+        match event {
+            TrackEvent::Started { id } => {
+                if audio.on_track_started(id) {
+                    emit_track_started(&app, &audio);
+                }
+            }
+            TrackEvent::Tail { id } => {
+                if audio.on_track_tail(id) {
+                    emit_track_started(&app, &audio);
+                }
+            }
+            TrackEvent::Finished { id } => {
+                if let Some((file_path, e)) = audio.stream_error(id) {
+                    emit_audio_error(&app, &audio, file_path, e);
+                }
+                if let Some(file_path) = audio.on_track_finished(id) {
+                    emit_audio_state(
+                        &app,
+                        AudioEventPayload {
+                            file_path: Some(file_path),
+                            ..audio.state_payload("ended")
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Notices a missing or lost output device and keeps trying to reopen it,
+/// reporting both through `native-audio://state`.
+fn watch_output(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>) {
+    let mut announced = false;
+    loop {
+        let missing = {
+            let Ok(mut audio) = state.lock() else {
+                return;
+            };
+
+            if audio.output.as_ref().is_some_and(|output| output.is_lost()) {
+                audio.detach_output();
+                announced = false;
+            }
+            if audio.output.is_none() && !announced {
+                emit_no_output(&app, &audio);
+                announced = true;
+            }
+            audio.output.is_none()
+        };
+
+        if missing {
+            match reopen_output(&app, &state) {
+                Ok(()) => announced = false,
+                // A new reason is announced again; the same one only once.
+                Err(e) => {
+                    if let Ok(mut audio) = state.lock() {
+                        if audio.output_error.as_ref() != Some(&e) {
+                            audio.output_error = Some(e);
+                            announced = false;
+                        }
+                    }
+                }
+            }
+        }
+        thread::sleep(OUTPUT_RETRY_INTERVAL);
+    }
+}
+
+fn emit_no_output(app: &tauri::AppHandle, audio: &AudioState) {
+    emit_audio_state(
+        app,
+        AudioEventPayload {
+            position: audio.current.as_ref().map(|current| current.position().as_secs_f32()),
+            error: audio.output_error.clone(),
+            ..audio.state_payload("no-output")
+        },
+    );
+}
+
+/// Opens the output again if there is none and moves playback onto it.
+/// The device is opened without holding the state lock.
+fn reopen_output(app: &tauri::AppHandle, state: &Arc<Mutex<AudioState>>) -> Result<(), String> {
+    let tap = state
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?
+        .tap
+        .clone();
+    let output = output::open_from_env(tap)?;
+
+    let mut audio = state
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+    if audio.output.is_some() {
+        return Ok(());
+    }
+    audio.attach_output(output)?;
+
+    let position = audio.current.as_ref().map(|current| current.position().as_secs_f32());
+    emit_audio_state(
+        app,
+        AudioEventPayload {
+            position,
+            ..audio.state_payload(if position.is_some() { "paused" } else { "stopped" })
+        },
+    );
+    Ok(())
+}
+
+/// Emits `native-audio://progress` with the elapsed position of the current
+/// track while it plays, and starts preloading the next one when it's due.
+fn tick_progress(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>) {
+    loop {
+        let interval = {
+            let Ok(mut audio) = state.lock() else {
+                return;
+            };
+
+            if let Some(payload) = audio.maintain_preload() {
+                let _ = app.emit("native-audio://preload", payload);
+            }
+
+            match audio.finish_opening() {
+                Some(Ok(())) if audio.sink.is_paused() => {
+                    let position = audio.current.as_ref().map(|current| current.position().as_secs_f32());
+                    emit_audio_state(
+                        &app,
+                        AudioEventPayload {
+                            position,
+                            ..audio.state_payload("paused")
+                        },
+                    );
+                }
+                Some(Ok(())) => emit_track_started(&app, &audio),
+                Some(Err(e)) => {
+                    let file_path = audio.queue.current().map(|entry| entry.file_path.clone());
+                    emit_audio_error(&app, &audio, file_path, e);
+                }
+                None => {}
+            }
+
+            let skipped = std::mem::take(&mut audio.skipped);
+            if !skipped.is_empty() {
+                for (file_path, error) in skipped {
+                    emit_audio_state(
+                        &app,
+                        AudioEventPayload {
+                            file_path: Some(file_path),
+                            error: Some(error),
+                            ..audio.state_payload("skipped")
+                        },
+                    );
+                }
+                emit_queue(&app, &audio.queue);
+            }
+
+            // A stream plays silence while its download catches up; report it
+            // as a state of its own and again once playback resumes.
+            let buffering = audio.opening.is_some()
+                || audio.current.as_ref().is_some_and(|current| current.is_buffering());
+            if buffering != audio.buffering && !audio.sink.is_paused() {
+                audio.buffering = buffering;
+                let position = audio.current.as_ref().map(|current| current.position().as_secs_f32());
+                emit_audio_state(
+                    &app,
+                    AudioEventPayload {
+                        position,
+                        ..audio.state_payload(if buffering { "buffering" } else { "playing" })
+                    },
+                );
+            }
+
+            if let Some(current) = audio.current.as_ref().filter(|_| !audio.sink.is_paused()) {
+                let _ = app.emit(
+                    "native-audio://progress",
+                    ProgressPayload {
+                        file_path: audio.current_file.clone(),
+                        position: current.position().as_secs_f32(),
+                        duration: current.duration().map(|d| d.as_secs_f32()),
+                    },
+                );
+            }
+            audio.progress_interval
+        };
+
+        thread::sleep(interval);
+    }
+}
+
+/// Writes the playback session to disk whenever it changed since the last
+/// save. `run` saves it once more on exit. A failing save is reported once
+/// through `native-audio://state` until the error changes.
+fn tick_session(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>) {
+    let mut saved: Option<StoredSession> = None;
+    let mut reported: Option<String> = None;
+    loop {
+        thread::sleep(SESSION_SAVE_INTERVAL);
+        let session = match state.lock() {
+            Ok(audio) => audio.session(),
+            Err(_) => return,
+        };
+        let Some(session) = session.filter(|session| saved.as_ref() != Some(session)) else {
+            continue;
+        };
+        match session.save() {
+            Ok(()) => {
+                saved = Some(session);
+                reported = None;
+            }
+            Err(e) if reported.as_ref() != Some(&e) => {
+                let Ok(audio) = state.lock() else {
+                    return;
+                };
+                emit_audio_error(&app, &audio, None, e.clone());
+                reported = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn save_session(state: &Mutex<AudioState>) -> Result<(), String> {
+    let session = state
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?
+        .session();
+    session.map_or(Ok(()), |session| session.save())
+}
+
+/// Fades out and stops playback when the sleep timer runs out, emitting
+/// `native-audio://sleep-timer` once per second of the countdown.
+fn tick_sleep_timer(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>) {
+    let mut last_second: Option<Option<u64>> = None;
+    loop {
+        thread::sleep(SLEEP_TIMER_TICK);
+        if plan_sleep_timer(&state).is_err() {
+            return;
+        }
+        let Ok(mut audio) = state.lock() else {
+            return;
+        };
+        if audio.sleep_timer.is_none() {
+            last_second = None;
+            continue;
+        }
+
+        // A timer for the end of something is done once playback stopped.
+        let done = match audio.sleep_timer.as_ref().and_then(|timer| timer.deadline_remaining()) {
+            Some(remaining) => remaining.is_zero(),
+            None => audio.current.is_none(),
+        };
+        if done {
+            let stopped = audio.stop();
+            audio.sleep_timer = None;
+            audio.apply_volume();
+            if let Err(e) = stopped {
+                emit_audio_error(&app, &audio, None, e);
+            }
+            emit_audio_state(
+                &app,
+                AudioEventPayload {
+                    file_path: None,
+                    ..audio.state_payload("stopped")
+                },
+            );
+            emit_sleep_timer(&app, audio.sleep_payload(true));
+            last_second = None;
+            continue;
+        }
+
+        let remaining = audio.sleep_remaining();
+        if let (Some(timer), Some(remaining)) = (audio.sleep_timer.as_mut(), remaining) {
+            timer.update_gain(remaining);
+        }
+        audio.apply_volume();
+
+        let second = remaining.map(|remaining| remaining.as_secs());
+        if last_second != Some(second) {
+            last_second = Some(second);
+            emit_sleep_timer(&app, audio.sleep_payload(false));
+        }
+    }
+}
+
+/// Analyses what the output plays while the visualizer or the level meter
+/// is on: emits `native-audio://spectrum` at the configured frame rate while
+/// a track plays, and `native-audio://levels` every `LEVEL_INTERVAL`. The
+/// analysis runs without the lock.
+fn tick_analysis(app: tauri::AppHandle, state: Arc<Mutex<AudioState>>) {
+    let mut analyzer = SpectrumAnalyzer::new(SpectrumSettings::default());
+    let mut meter: Option<LevelMeter> = None;
+    let mut last_spectrum = Instant::now();
+    let mut last_levels = Instant::now();
+    loop {
+        let (samples, (channels, sample_rate), visualizer, metering, clear_clip, playing) = {
+            let Ok(mut audio) = state.lock() else {
+                return;
+            };
+            if !audio.tap.is_enabled() {
+                drop(audio);
+                meter = None;
+                thread::sleep(ANALYSIS_IDLE_INTERVAL);
+                continue;
+            }
+            if analyzer.settings() != &audio.spectrum {
+                analyzer = SpectrumAnalyzer::new(audio.spectrum.clone());
+            }
+            let playing = audio.current.is_some() && !audio.sink.is_paused();
+            let clear_clip = std::mem::take(&mut audio.clear_clip);
+            (
+                audio.tap.take(),
+                audio.tap.format(),
+                audio.visualizer_enabled,
+                audio.meter_enabled,
+                clear_clip,
+                playing,
+            )
+        };
+
+        if visualizer {
+            analyzer.push(&samples, channels);
+            if playing && last_spectrum.elapsed() >= analyzer.settings().frame_interval() {
+                last_spectrum = Instant::now();
+                if let Some(payload) = analyzer.analyze(sample_rate) {
+                    let _ = app.emit("native-audio://spectrum", payload);
+                }
+            }
+        }
+
+        if metering {
+            let meter = match &mut meter {
+                Some(meter) if meter.matches(channels, sample_rate) => meter,
+                _ => meter.insert(LevelMeter::new(channels, sample_rate)),
+            };
+            if clear_clip {
+                meter.clear_clip();
+            }
+            meter.push(&samples);
+            if last_levels.elapsed() >= LEVEL_INTERVAL {
+                last_levels = Instant::now();
+                let _ = app.emit("native-audio://levels", meter.report());
+            }
+        } else {
+            meter = None;
+        }
+
+        let spectrum_interval = analyzer.settings().frame_interval();
+        let interval = match (visualizer, metering) {
+            (true, true) => spectrum_interval.min(LEVEL_INTERVAL),
+            (true, false) => spectrum_interval,
+            _ => LEVEL_INTERVAL,
+        };
+        thread::sleep(interval);
+    }
+}
+
+/// Retries opening the output device right away instead of waiting for the
+/// next periodic attempt.
+#[tauri::command(rename_all = "camelCase")]
+fn retry_audio_output(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+) -> Result<(), String> {
+    {
+        let mut audio = state
+            .inner()
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        if audio.output.as_ref().is_some_and(|output| output.is_lost()) {
+            audio.detach_output();
+        }
+    }
+    reopen_output(&app, state.inner())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn set_progress_interval(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    interval_ms: u64,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.progress_interval = Duration::from_millis(interval_ms.clamp(16, 5000));
+
+    emit_audio_state(&app, audio.state_payload("progress-interval"));
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn play_song(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    file_path: String,
+    up_next: Option<Vec<String>>,
+) -> Result<(), String> {
+    let up_next = up_next.unwrap_or_default();
+    // A shuffled queue needs the tags of the new entries, read before locking.
+    let shuffled = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?
+        .queue
+        .shuffle_seed()
+        .is_some();
+    let tags = if shuffled {
+        let paths: Vec<String> = std::iter::once(&file_path).chain(&up_next).cloned().collect();
+        read_tags(state.inner(), &paths)?
+    } else {
+        HashMap::new()
+    };
+
+    // `state` is a `State<Arc<Mutex<AudioState>>>`; call `inner()` to get the
+    // `Arc<Mutex<_>>` and then lock it.
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.replace(file_path, up_next);
+    if let Some(seed) = audio.queue.shuffle_seed() {
+        audio.shuffle_queue(seed, tags);
+    }
+    let entry = audio
+        .queue
+        .current()
+        .cloned()
+        .ok_or_else(|| "Queue is empty".to_string())?;
+    audio.play_entry(entry)?;
+
+    emit_track_started(&app, &audio);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn pause_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let shared = state.inner().clone();
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    if let Some(opening) = audio.opening.as_mut() {
+        opening.paused = true;
+    }
+    // The sinks are paused once the tracks have ramped down to silence.
+    audio.set_gates(false);
+    let wait = audio.dsp.declick.ramp() + DECLICK_MARGIN;
+    thread::spawn(move || {
+        thread::sleep(wait);
+        let Ok(audio) = shared.lock() else {
+            return;
+        };
+        // Resumed in the meantime.
+        if audio.current.as_ref().is_some_and(|current| current.is_gate_open()) {
+            return;
+        }
+        audio.sink.pause();
+        if let Some((_, fading)) = &audio.fading {
+            fading.pause();
+        }
+    });
+
+    emit_audio_state(
+        &app,
+        audio.state_payload("paused"),
+    );
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn resume_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    if let Some(opening) = audio.opening.as_mut() {
+        opening.paused = false;
+    }
+    audio.sink.play();
+    if let Some((_, fading)) = &audio.fading {
+        fading.play();
+    }
+    audio.set_gates(true);
+
+    emit_audio_state(
+        &app,
+        audio.state_payload("playing"),
+    );
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn stop_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.stop()?;
+
+    emit_audio_state(
+        &app,
+        AudioEventPayload {
+            file_path: None,
+            ..audio.state_payload("stopped")
+        },
+    );
+
+    Ok(())
+}
+
+/// Brings back the queue, volume and track of the last session, paused at
+/// the saved position. Returns `false` when there was nothing to restore.
+#[tauri::command(rename_all = "camelCase")]
+fn restore_session(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<bool, String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    let session = StoredSession::load();
+    audio.session_active = true;
+    audio.volume = session.volume.clamp(0.0, 1.0);
+    audio.apply_volume();
+    if session.queue.file_paths.is_empty() {
+        return Ok(false);
+    }
+
+    audio.queue.restore(session.queue);
+    emit_queue(&app, &audio.queue);
+    let Some(entry) = audio.queue.current().cloned() else {
+        return Ok(true);
+    };
+    // A corrupt position starts the track from the beginning.
+    let position = Duration::try_from_secs_f32(session.position.max(0.0)).unwrap_or_default();
+    audio.load_entry(entry, Some(position), true)?;
+
+    emit_audio_state(
+        &app,
+        AudioEventPayload {
+            position: Some(position.as_secs_f32()),
+            ..audio.state_payload("paused")
+        },
+    );
+
+    Ok(true)
+}
+
+/// Changes playback speed without changing the pitch, from 0.5x to 3x.
+#[tauri::command(rename_all = "camelCase")]
+fn set_playback_rate(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    rate: f32,
+) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.stretch.set_playback_rate(rate);
+    emit_audio_state(&app, audio.state_payload("playback-rate"));
+    Ok(())
+}
+
+/// Shifts the pitch by up to an octave either way without changing speed.
+#[tauri::command(rename_all = "camelCase")]
+fn set_pitch_semitones(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    semitones: f32,
+) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.stretch.set_pitch_semitones(semitones);
+    emit_audio_state(&app, audio.state_payload("pitch"));
+    Ok(())
+}
+
+/// Loops the current track between `start` and `end` seconds. The jump back
+/// happens inside the source, so the loop is gapless and sample-accurate.
+#[tauri::command(rename_all = "camelCase")]
+fn set_loop_region(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    start: f32,
+    end: f32,
+) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    let current = audio
+        .current
+        .as_ref()
+        .ok_or_else(|| "No track is playing".to_string())?;
+    let start = start.max(0.0);
+    let end = match current.duration() {
+        Some(duration) => end.min(duration.as_secs_f32()),
+        None => end,
+    };
+    if end - start < MIN_LOOP_SECONDS {
+        return Err(format!("Invalid loop region: {} -> {}", start, end));
+    }
+
+    let (Ok(start), Ok(end)) = (Duration::try_from_secs_f32(start), Duration::try_from_secs_f32(end))
+    else {
+        return Err(format!("Invalid loop region: {} -> {}", start, end));
+    };
+
+    current.set_loop(start, end);
+    emit_audio_state(&app, audio.state_payload("loop"));
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn clear_loop_region(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    if let Some(current) = &audio.current {
+        current.clear_loop();
+    }
+    emit_audio_state(&app, audio.state_payload("loop"));
+    Ok(())
+}
+
+/// Source and output formats of the current playback, also sent with every
+/// `native-audio://state` event.
+#[tauri::command(rename_all = "camelCase")]
+fn get_playback_format(
+    state: State<Arc<Mutex<AudioState>>>,
+) -> Result<Option<PlaybackFormat>, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.playback_format())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn get_queue(state: State<Arc<Mutex<AudioState>>>) -> Result<QueueSnapshot, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.queue.snapshot())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn enqueue(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    file_paths: Vec<String>,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.push(file_paths);
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn insert_next(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    file_path: String,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.insert_next(file_path);
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn remove_from_queue(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    index: usize,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.remove(index)?;
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn move_in_queue(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    from: usize,
+    to: usize,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.move_entry(from, to)?;
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn clear_queue(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.clear();
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn set_repeat_mode(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    mode: RepeatMode,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.queue.set_repeat(mode);
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+/// Shuffles the queue, or restores its original order when disabled. Without
+/// a seed a new one is picked; the seed is reported in the queue snapshot so
+/// the same order can be produced again.
+#[tauri::command(rename_all = "camelCase")]
+fn set_shuffle(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    enabled: bool,
+    seed: Option<u64>,
+) -> Result<(), String> {
+    let tags = if enabled {
+        let paths: Vec<String> = state
+            .inner()
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?
+            .queue
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|entry| entry.file_path)
+            .collect();
+        read_tags(state.inner(), &paths)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    if enabled {
+        audio.shuffle_queue(seed.unwrap_or_else(new_shuffle_seed), tags);
+    } else {
+        audio.queue.unshuffle();
+    }
+    audio.schedule_next();
+    emit_queue(&app, &audio.queue);
+
+    Ok(())
+}
+
+/// Works out what plays after the current track before an end-of-album or
+/// end-of-queue sleep timer stops, when the queue changed since it last was.
+/// The tags are read without holding the lock.
+fn plan_sleep_timer(state: &Arc<Mutex<AudioState>>) -> Result<(), String> {
+    let (mode, entries) = {
+        let audio = state
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        let Some(timer) = audio.sleep_timer.as_ref() else {
+            return Ok(());
+        };
+        if !matches!(timer.mode, SleepMode::EndOfAlbum | SleepMode::EndOfQueue) {
+            return Ok(());
+        }
+        let entries = audio.queue.current_onwards();
+        let ids: Vec<u64> = entries.iter().map(|entry| entry.id).collect();
+        if timer.following.as_ref().is_some_and(|following| following.remaining(&ids).is_some()) {
+            return Ok(());
+        }
+        (timer.mode, entries.to_vec())
+    };
+
+    let paths: Vec<String> = entries.iter().map(|entry| entry.file_path.clone()).collect();
+    let tags = read_tags(state, &paths)?;
+    let mut durations = Vec::new();
+    for pair in paths.windows(2) {
+        let (previous, next) = (&tags[&pair[0]], &tags[&pair[1]]);
+        if mode == SleepMode::EndOfAlbum && !previous.precedes_on_album(next) {
+            break;
+        }
+        durations.push(next.duration.unwrap_or_default());
+    }
+
+    let mut audio = state
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+    // A queue that changed meanwhile leaves this stale, and the next tick
+    // works it out again.
+    if let Some(timer) = audio.sleep_timer.as_mut().filter(|timer| timer.mode == mode) {
+        timer.following = Some(Following {
+            ids: entries.iter().map(|entry| entry.id).collect(),
+            durations,
+        });
+    }
+    Ok(())
+}
+
+/// Tags of `paths`, read without holding the lock except to look them up in
+/// the tag cache.
+fn read_tags(
+    state: &Arc<Mutex<AudioState>>,
+    paths: &[String],
+) -> Result<HashMap<String, TrackTags>, String> {
+    let mut tags: HashMap<String, TrackTags> = {
+        let audio = state
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        paths
+            .iter()
+            .filter_map(|path| Some((path.clone(), audio.tag_cache.get(path)?.clone())))
+            .collect()
+    };
+    for path in paths {
+        if !tags.contains_key(path) {
+            tags.insert(path.clone(), TrackTags::read(path));
+        }
+    }
+    Ok(tags)
+}
+
+/// A seed from the clock, kept below 2^53 so JavaScript numbers hold it exactly.
+fn new_shuffle_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    nanos & ((1 << 53) - 1)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn next_track(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    let entry = audio
+        .queue
+        .next_on_skip()
+        .cloned()
+        .ok_or_else(|| "No next track in queue".to_string())?;
+    audio.play_entry(entry)?;
+
+    emit_track_started(&app, &audio);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn previous_track(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    // With nothing before it, "previous" restarts the current track.
+    let entry = audio
+        .queue
+        .peek_previous()
+        .or_else(|| audio.queue.current())
+        .cloned()
+        .ok_or_else(|| "No previous track in queue".to_string())?;
+    audio.play_entry(entry)?;
+
+    emit_track_started(&app, &audio);
+
+    Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn get_preload(state: State<Arc<Mutex<AudioState>>>) -> Result<PreloadSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.preload_settings.clone())
+}
+
+/// Sets how long before the end of a track the next one starts decoding, and
+/// how much decoded audio may be held for it.
+#[tauri::command(rename_all = "camelCase")]
+fn set_preload(
+    state: State<Arc<Mutex<AudioState>>>,
+    lead_seconds: Option<f32>,
+    budget_mb: Option<u32>,
+) -> Result<PreloadSettings, String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    if let Some(lead_seconds) = lead_seconds.filter(|seconds| seconds.is_finite()) {
+        audio.preload_settings.lead_seconds = lead_seconds.clamp(1.0, 600.0);
+    }
+    if let Some(budget_mb) = budget_mb {
+        audio.preload_settings.budget_mb =
+            budget_mb.clamp(preload::MIN_BUDGET_MB, preload::MAX_BUDGET_MB);
+    }
+    Ok(audio.preload_settings.clone())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn get_crossfade(state: State<Arc<Mutex<AudioState>>>) -> Result<CrossfadeSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.crossfade.clone())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn set_crossfade(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    enabled: bool,
+    duration_ms: Option<u64>,
+    curve: Option<FadeCurve>,
+) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.crossfade.enabled = enabled;
+    if let Some(duration_ms) = duration_ms {
+        audio.crossfade.duration_ms = duration_ms.clamp(100, 20_000);
+    }
+    if let Some(curve) = curve {
+        audio.crossfade.curve = curve;
+    }
+
+    // Re-plan the upcoming transition with the new settings.
+    audio.cancel_next();
+    audio.schedule_next();
+
+    emit_audio_state(&app, audio.state_payload("crossfade"));
+    Ok(())
 }
 
-#[derive(Clone, serde::Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SongMetadata {
-    title: Option<String>,
-    artist: Option<String>,
-    album: Option<String>,
-    duration: u64,
-    file_path: String,
-    cover_art_path: Option<String>,
+#[tauri::command(rename_all = "camelCase")]
+fn get_eq(state: State<Arc<Mutex<AudioState>>>) -> Result<EqSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.eq.settings()
 }
 
-#[derive(Clone, serde::Serialize)]
-struct AudioEventPayload {
-    status: String,
-    file_path: Option<String>,
-    position: Option<f32>,
-    volume: Option<f32>,
+#[tauri::command(rename_all = "camelCase")]
+fn set_eq_mode(state: State<Arc<Mutex<AudioState>>>, mode: EqMode) -> Result<EqSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.eq.set_mode(mode)?;
+    audio.dsp.eq.settings()
 }
 
-fn emit_audio_state(app: &tauri::AppHandle, payload: AudioEventPayload) {
-    let _ = app.emit("native-audio://state", payload);
+#[tauri::command(rename_all = "camelCase")]
+fn set_eq_band(
+    state: State<Arc<Mutex<AudioState>>>,
+    index: usize,
+    gain_db: f32,
+) -> Result<EqSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.eq.set_band(index, gain_db)?;
+    audio.dsp.eq.settings()
 }
 
-#[tauri::command]
-fn greet(name: &str) -> String {
-    format!("Hello, {}! You've been greeted from Rust!", name)
+#[tauri::command(rename_all = "camelCase")]
+fn set_eq_preset(state: State<Arc<Mutex<AudioState>>>, name: String) -> Result<EqSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.eq.set_preset(&name)?;
+    audio.dsp.eq.settings()
 }
 
 #[tauri::command(rename_all = "camelCase")]
-fn play_song(
+fn get_replay_gain(state: State<Arc<Mutex<AudioState>>>) -> Result<ReplayGainSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.replay_gain.settings()
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn set_replay_gain(
+    state: State<Arc<Mutex<AudioState>>>,
+    mode: Option<ReplayGainMode>,
+    preamp_db: Option<f32>,
+    prevent_clipping: Option<bool>,
+) -> Result<ReplayGainSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.replay_gain.update(|settings| {
+        if let Some(mode) = mode {
+            settings.mode = mode;
+        }
+        if let Some(preamp_db) = preamp_db {
+            settings.preamp_db = preamp_db.clamp(-15.0, 15.0);
+        }
+        if let Some(prevent_clipping) = prevent_clipping {
+            settings.prevent_clipping = prevent_clipping;
+        }
+    })?;
+    audio.dsp.replay_gain.settings()
+}
+
+/// Turns the native spectrum events on or off. The frontend passes the flag
+/// as `is_enabled`.
+#[tauri::command(rename_all = "snake_case")]
+fn set_visualizer_enabled(
     app: tauri::AppHandle,
     state: State<Arc<Mutex<AudioState>>>,
-    file_path: String,
+    is_enabled: bool,
 ) -> Result<(), String> {
-    // `state` is a `State<Arc<Mutex<AudioState>>>`; call `inner()` to get the
-    // `Arc<Mutex<_>>` and then lock it.
     let mut audio = state
         .inner()
         .lock()
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    let file = File::open(&file_path).map_err(|e| format!("File opening error: {}", e))?;
-    let decoder = Decoder::new(BufReader::new(file))
-        .map_err(|e| format!("Decoder error: {}", e))?;
+    audio.visualizer_enabled = is_enabled;
+    audio.update_tap();
+    let _ = app.emit(
+        "visualizer-state-changed",
+        serde_json::json!({ "enabled": is_enabled }),
+    );
 
-    let new_sink = Sink::try_new(&audio.stream_handle)
-        .map_err(|e| format!("Sink creation error: {}", e))?;
-    new_sink.set_volume(audio.volume);
-    new_sink.append(decoder);
+    Ok(())
+}
 
-    audio.sink.stop();
-    audio.sink = new_sink;
-    audio.current_file = Some(file_path.clone());
+#[tauri::command(rename_all = "camelCase")]
+fn get_config_visualizer_state(state: State<Arc<Mutex<AudioState>>>) -> Result<bool, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    emit_audio_state(
-        &app,
-        AudioEventPayload {
-            status: "playing".to_string(),
-            file_path: Some(file_path),
-            position: Some(0.0),
-            volume: Some(audio.volume),
-        },
-    );
+    Ok(audio.visualizer_enabled)
+}
+
+/// Turns `native-audio://levels` events on or off. Nothing is measured while
+/// they are off.
+#[tauri::command(rename_all = "camelCase")]
+fn set_level_meter_enabled(state: State<Arc<Mutex<AudioState>>>, enabled: bool) -> Result<(), String> {
+    let mut audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.meter_enabled = enabled;
+    audio.update_tap();
 
     Ok(())
 }
 
+/// Resets the latched clip flags of the level meter.
 #[tauri::command(rename_all = "camelCase")]
-fn pause_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+fn clear_level_clip(state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
     let mut audio = state
         .inner()
         .lock()
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    audio.sink.pause();
-
-    emit_audio_state(
-        &app,
-        AudioEventPayload {
-            status: "paused".to_string(),
-            file_path: audio.current_file.clone(),
-            position: None,
-            volume: Some(audio.volume),
-        },
-    );
+    audio.clear_clip = true;
 
     Ok(())
 }
 
 #[tauri::command(rename_all = "camelCase")]
-fn resume_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
+fn get_spectrum_settings(state: State<Arc<Mutex<AudioState>>>) -> Result<SpectrumSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.spectrum.clone())
+}
+
+/// Changes the FFT size (a power of two from 256 to 16384), the window, the
+/// number of bands and how many spectrum events are sent per second.
+#[tauri::command(rename_all = "camelCase")]
+fn set_spectrum_settings(
+    state: State<Arc<Mutex<AudioState>>>,
+    fft_size: Option<usize>,
+    window: Option<SpectrumWindow>,
+    bands: Option<usize>,
+    frame_rate: Option<u32>,
+) -> Result<SpectrumSettings, String> {
     let mut audio = state
         .inner()
         .lock()
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    audio.sink.play();
+    let mut settings = audio.spectrum.clone();
+    if let Some(fft_size) = fft_size {
+        settings.fft_size = fft_size;
+    }
+    if let Some(window) = window {
+        settings.window = window;
+    }
+    if let Some(bands) = bands {
+        settings.bands = bands;
+    }
+    if let Some(frame_rate) = frame_rate {
+        settings.frame_rate = frame_rate;
+    }
+    audio.spectrum = settings.normalized();
 
-    emit_audio_state(
-        &app,
-        AudioEventPayload {
-            status: "playing".to_string(),
-            file_path: audio.current_file.clone(),
-            position: None,
-            volume: Some(audio.volume),
-        },
-    );
+    Ok(audio.spectrum.clone())
+}
 
-    Ok(())
+#[tauri::command(rename_all = "camelCase")]
+fn get_declick(state: State<Arc<Mutex<AudioState>>>) -> Result<DeclickSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.dsp.declick.settings())
 }
 
+/// Sets the length of the ramps around pause, resume, stop and seek, from 5
+/// to 50 ms.
 #[tauri::command(rename_all = "camelCase")]
-fn stop_song(app: tauri::AppHandle, state: State<Arc<Mutex<AudioState>>>) -> Result<(), String> {
-    let mut audio = state
+fn set_declick(state: State<Arc<Mutex<AudioState>>>, ramp_ms: u32) -> Result<DeclickSettings, String> {
+    let audio = state
         .inner()
         .lock()
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    audio.sink.stop();
-    audio.sink = Sink::try_new(&audio.stream_handle)
-        .map_err(|e| format!("Sink creation error: {}", e))?;
-    audio.current_file = None;
+    audio.dsp.declick.set_ramp_ms(ramp_ms);
+    Ok(audio.dsp.declick.settings())
+}
 
-    emit_audio_state(
-        &app,
-        AudioEventPayload {
-            status: "stopped".to_string(),
-            file_path: None,
-            position: None,
-            volume: Some(audio.volume),
-        },
-    );
+#[tauri::command(rename_all = "camelCase")]
+fn get_channel_settings(state: State<Arc<Mutex<AudioState>>>) -> Result<ChannelSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    Ok(())
+    audio.dsp.channels.settings()
+}
+
+/// Sets the left/right balance, from -1 (left only) to 1 (right only).
+#[tauri::command(rename_all = "camelCase")]
+fn set_balance(state: State<Arc<Mutex<AudioState>>>, balance: f32) -> Result<ChannelSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.channels.set_balance(balance)?;
+    audio.save_settings()?;
+    audio.dsp.channels.settings()
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn set_mono(state: State<Arc<Mutex<AudioState>>>, enabled: bool) -> Result<ChannelSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.channels.set_mono(enabled)?;
+    audio.save_settings()?;
+    audio.dsp.channels.settings()
+}
+
+/// Sets the headphone crossfeed: `off`, `low`, `medium` or `high`.
+#[tauri::command(rename_all = "camelCase")]
+fn set_crossfeed(
+    state: State<Arc<Mutex<AudioState>>>,
+    level: Crossfeed,
+) -> Result<ChannelSettings, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.dsp.channels.set_crossfeed(level)?;
+    audio.save_settings()?;
+    audio.dsp.channels.settings()
+}
+
+/// Starts a background EBU R128 analysis of `file_paths` and returns its job
+/// id. Progress and results arrive as `native-audio://loudness-*` events.
+#[tauri::command(rename_all = "camelCase")]
+fn scan_loudness(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    file_paths: Vec<String>,
+    as_album: Option<bool>,
+    write_tags: Option<bool>,
+) -> Result<u64, String> {
+    let shared = state.inner().clone();
+    let (job_id, cancelled) = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?
+        .loudness_jobs
+        .start();
+
+    let request = ScanRequest {
+        job_id,
+        file_paths,
+        as_album: as_album.unwrap_or(false),
+        write_tags: write_tags.unwrap_or(false),
+    };
+    thread::spawn(move || {
+        let mut finished = loudness::run_scan(&request, &cancelled, |progress| {
+            let _ = app.emit("native-audio://loudness-progress", progress);
+        });
+
+        if let Ok(mut audio) = shared.lock() {
+            audio.loudness_jobs.finish(job_id);
+            if let Err(error) = audio.loudness_cache.insert_all(&finished.results) {
+                // The results still arrive, but only for this session.
+                finished.errors.extend(finished.results.iter().map(|result| ScanError {
+                    file_path: result.file_path.clone(),
+                    error: error.clone(),
+                }));
+            }
+            // Pick up the new values the next time these files are opened.
+            for result in &finished.results {
+                audio.tag_cache.remove(&result.file_path);
+            }
+        }
+        let _ = app.emit("native-audio://loudness-finished", finished);
+    });
+
+    Ok(job_id)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn cancel_loudness_scan(state: State<Arc<Mutex<AudioState>>>, job_id: u64) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.loudness_jobs.cancel(job_id)
+}
+
+/// Min, max and RMS of `file_path` in `buckets` slices, for a waveform seek
+/// bar. Decoded once, then served from the disk cache. Runs off the main
+/// thread since decoding can take a while.
+#[tauri::command(rename_all = "camelCase", async)]
+fn get_waveform(
+    state: State<Arc<Mutex<AudioState>>>,
+    file_path: String,
+    buckets: usize,
+) -> Result<Waveform, String> {
+    let waveforms = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?
+        .waveforms
+        .clone();
+
+    waveforms
+        .get(&file_path, buckets, &AtomicBool::new(false))?
+        .ok_or_else(|| "Waveform generation cancelled".to_string())
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WaveformReady {
+    job_id: u64,
+    file_path: String,
+    file_index: usize,
+    file_count: usize,
+    waveform: Waveform,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WaveformsFinished {
+    job_id: u64,
+    cancelled: bool,
+    errors: Vec<ScanError>,
+}
+
+/// Fills the waveform cache for `file_paths` in the background and returns
+/// the job id. Every file sends `native-audio://waveform-ready`, the job ends
+/// with `native-audio://waveforms-finished`.
+#[tauri::command(rename_all = "camelCase")]
+fn generate_waveforms(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    file_paths: Vec<String>,
+    buckets: usize,
+) -> Result<u64, String> {
+    let shared = state.inner().clone();
+    let (job_id, cancelled, waveforms) = {
+        let mut audio = state
+            .inner()
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        let (job_id, cancelled) = audio.waveform_jobs.start();
+        (job_id, cancelled, audio.waveforms.clone())
+    };
+
+    thread::spawn(move || {
+        let file_count = file_paths.len();
+        let mut errors = Vec::new();
+        for (file_index, file_path) in file_paths.into_iter().enumerate() {
+            match waveforms.get(&file_path, buckets, &cancelled) {
+                Ok(Some(waveform)) => {
+                    let _ = app.emit(
+                        "native-audio://waveform-ready",
+                        WaveformReady {
+                            job_id,
+                            file_path,
+                            file_index,
+                            file_count,
+                            waveform,
+                        },
+                    );
+                }
+                Ok(None) => break,
+                Err(error) => errors.push(ScanError { file_path, error }),
+            }
+        }
+
+        if let Ok(mut audio) = shared.lock() {
+            audio.waveform_jobs.finish(job_id);
+        }
+        let _ = app.emit(
+            "native-audio://waveforms-finished",
+            WaveformsFinished {
+                job_id,
+                cancelled: cancelled.load(Ordering::Relaxed),
+                errors,
+            },
+        );
+    });
+
+    Ok(job_id)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn cancel_waveform_job(state: State<Arc<Mutex<AudioState>>>, job_id: u64) -> Result<(), String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    audio.waveform_jobs.cancel(job_id)
 }
 
-fn cache_cover_jpg(app: &tauri::AppHandle, picture_bytes: &[u8]) -> Option<String> {
+fn cache_cover_jpg(picture_bytes: &[u8]) -> Option<String> {
     let mut hasher = Sha256::new();
     hasher.update(picture_bytes);
     let hash = format!("{:x}", hasher.finalize());
@@ -184,8 +2427,8 @@ fn cache_cover_jpg(app: &tauri::AppHandle, picture_bytes: &[u8]) -> Option<Strin
     cover_path.to_str().map(|s| s.to_string())
 }
 
-#[tauri::command(rename_all = "camelCase")]
-fn scan_music_file(app: tauri::AppHandle, file_path: String) -> Result<SongMetadata, String> {
+/// Tags of a music file, with its length and embedded cue sheet.
+fn read_music_file(file_path: String) -> Result<(SongMetadata, Duration, Option<String>), String> {
     let file = File::open(&file_path).map_err(|e| format!("File opening error: {}", e))?;
     let mut reader = BufReader::new(file);
 
@@ -196,31 +2439,74 @@ fn scan_music_file(app: tauri::AppHandle, file_path: String) -> Result<SongMetad
         .map_err(|e| format!("Tag read error: {}", e))?;
 
     let properties = tagged_file.properties();
-    let duration = properties.duration().as_secs();
+    let total_duration = properties.duration();
 
     let mut title = None;
     let mut artist = None;
     let mut album = None;
     let mut cover_art_path = None;
+    let mut embedded_cue_sheet = None;
 
     if let Some(tag) = tagged_file.primary_tag().or_else(|| tagged_file.first_tag()) {
         title = tag.title().map(|s| s.to_string());
         artist = tag.artist().map(|s| s.to_string());
         album = tag.album().map(|s| s.to_string());
+        embedded_cue_sheet = tags::embedded_cue_sheet(tag).map(|s| s.to_string());
 
         if let Some(picture) = tag.pictures().first() {
-            cover_art_path = cache_cover_jpg(&app, &picture.data());
+            cover_art_path = cache_cover_jpg(picture.data());
         }
     }
 
-    Ok(SongMetadata {
+    let song = SongMetadata {
         title,
         artist,
         album,
-        duration,
+        duration: total_duration.as_secs(),
         file_path,
         cover_art_path,
-    })
+        start: None,
+        end: None,
+    };
+    Ok((song, total_duration, embedded_cue_sheet))
+}
+
+/// Reads the tracks of a music file. A single-file album image with a cue
+/// sheet, embedded or next to it, yields one entry per track; their paths
+/// play only their part of the file. Any other file yields itself.
+#[tauri::command(rename_all = "camelCase")]
+fn scan_music_file(file_path: String) -> Result<Vec<SongMetadata>, String> {
+    let (song, total_duration, embedded_cue_sheet) = read_music_file(file_path)?;
+
+    let virtual_tracks = cue::tracks_for(&song.file_path, embedded_cue_sheet.as_deref());
+    if virtual_tracks.is_empty() {
+        return Ok(vec![song]);
+    }
+
+    Ok(virtual_tracks
+        .into_iter()
+        .map(|track| SongMetadata {
+            title: track.title.or_else(|| Some(format!("Track {:02}", track.number))),
+            artist: track.performer.or_else(|| song.artist.clone()),
+            album: track.album.or_else(|| song.album.clone()),
+            duration: track
+                .range
+                .length(Some(total_duration))
+                .unwrap_or_default()
+                .as_secs(),
+            file_path: track.file_path,
+            cover_art_path: song.cover_art_path.clone(),
+            start: Some(track.range.start.as_secs_f32()),
+            end: track.range.end.map(|end| end.as_secs_f32()),
+        })
+        .collect())
+}
+
+/// File extensions and codecs the native decoder plays, so the importer can
+/// flag files it would fail on.
+#[tauri::command(rename_all = "camelCase")]
+fn get_supported_formats() -> SupportedFormats {
+    decoder::supported_formats()
 }
 
 #[tauri::command(rename_all = "camelCase")]
@@ -228,6 +2514,60 @@ fn read_lyrics(file_path: String) -> Result<String, String> {
     std::fs::read_to_string(&file_path).map_err(|e| format!("Lyrics read error: {}", e))
 }
 
+/// Stops playback after `minutes` or at the end of the current track, album
+/// or queue, fading out over `fade_seconds` first. `None` cancels the timer.
+#[tauri::command(rename_all = "camelCase")]
+fn set_sleep_timer(
+    app: tauri::AppHandle,
+    state: State<Arc<Mutex<AudioState>>>,
+    mode: Option<SleepMode>,
+    minutes: Option<f32>,
+    fade_seconds: Option<f32>,
+) -> Result<SleepTimerPayload, String> {
+    {
+        let mut audio = state
+            .inner()
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+        audio.sleep_timer = match mode {
+            Some(mode) => {
+                if mode != SleepMode::Minutes && audio.current.is_none() {
+                    return Err("No track loaded".to_string());
+                }
+                let fade = match fade_seconds.filter(|seconds| seconds.is_finite()) {
+                    Some(seconds) => Duration::try_from_secs_f32(seconds.max(0.0))
+                        .map_err(|_| format!("Invalid sleep timer fade: {} seconds", seconds))?,
+                    None => DEFAULT_SLEEP_FADE,
+                };
+                Some(SleepTimer::new(mode, minutes, fade)?)
+            }
+            None => None,
+        };
+        audio.apply_volume();
+        audio.schedule_next();
+    }
+    plan_sleep_timer(state.inner())?;
+
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+    let payload = audio.sleep_payload(false);
+    emit_sleep_timer(&app, payload.clone());
+    Ok(payload)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn get_sleep_timer(state: State<Arc<Mutex<AudioState>>>) -> Result<SleepTimerPayload, String> {
+    let audio = state
+        .inner()
+        .lock()
+        .map_err(|e| format!("Mutex lock error: {}", e))?;
+
+    Ok(audio.sleep_payload(false))
+}
+
 #[tauri::command(rename_all = "camelCase")]
 fn set_volume(
     app: tauri::AppHandle,
@@ -241,17 +2581,9 @@ fn set_volume(
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
     audio.volume = clamped;
-    audio.sink.set_volume(clamped);
+    audio.apply_volume();
 
-    emit_audio_state(
-        &app,
-        AudioEventPayload {
-            status: "volume".to_string(),
-            file_path: audio.current_file.clone(),
-            position: None,
-            volume: Some(clamped),
-        },
-    );
+    emit_audio_state(&app, audio.state_payload("volume"));
 
     Ok(())
 }
@@ -262,36 +2594,29 @@ fn seek_to(
     state: State<Arc<Mutex<AudioState>>>,
     position_seconds: f32,
 ) -> Result<(), String> {
-    let mut audio = state
+    let audio = state
         .lock()
         .map_err(|e| format!("Mutex lock error: {}", e))?;
 
-    let file_path = audio
-        .current_file
-        .clone()
+    let current = audio
+        .current
+        .as_ref()
         .ok_or_else(|| "No track loaded".to_string())?;
 
-    let file = File::open(&file_path).map_err(|e| format!("File opening error: {}", e))?;
-    let decoder = Decoder::new(BufReader::new(file))
-        .map_err(|e| format!("Decoder error: {}", e))?;
-
-    let skipped = decoder.skip_duration(Duration::from_secs_f32(position_seconds.max(0.0)));
-
-    let new_sink = Sink::try_new(&audio.stream_handle)
-        .map_err(|e| format!("Sink creation error: {}", e))?;
-    new_sink.set_volume(audio.volume);
-    new_sink.append(skipped);
-
-    audio.sink.stop();
-    audio.sink = new_sink;
+    let position = Duration::try_from_secs_f32(position_seconds.max(0.0))
+        .map_err(|_| format!("Invalid seek position: {}", position_seconds))?;
+    let position = current
+        .duration()
+        .map_or(position, |duration| position.min(duration));
+    // The decoder stays in the sink and seeks through the container index,
+    // behind a short ramp so the jump doesn't click.
+    current.seek_smoothly(position);
 
     emit_audio_state(
         &app,
         AudioEventPayload {
-            status: "seeking".to_string(),
-            file_path: Some(file_path),
-            position: Some(position_seconds.max(0.0)),
-            volume: Some(audio.volume),
+            position: Some(position.as_secs_f32()),
+            ..audio.state_payload("seeking")
         },
     );
 
@@ -300,32 +2625,119 @@ fn seek_to(
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let (_stream, stream_handle) = OutputStream::try_default()
-        .expect("Failed to open audio output stream");
-    let sink = Sink::try_new(&stream_handle).expect("Failed to create audio sink");
+    // Without a device the app still starts; `watch_output` keeps retrying.
+    let tap = Arc::new(SampleTap::default());
+    let (output, output_error) = match output::open_from_env(tap.clone()) {
+        Ok(output) => (Some(output), None),
+        Err(e) => (None, Some(e)),
+    };
+
+    let (track_events, track_receiver) = mpsc::channel();
+    let stored = StoredSettings::load();
+    // The rest of the session waits for `restore_session`.
+    let volume = StoredSession::load().volume.clamp(0.0, 1.0);
+    let dsp = DspControls::default();
+    // The controls were just created, so their lock can't be poisoned.
+    let _ = dsp.channels.set(stored.channels);
 
     let audio_state = Arc::new(Mutex::new(AudioState {
-        // note: `_stream` intentionally not included in the shared state
-        stream_handle,
-        sink,
-        current_file: None,
-        volume: 1.0,
+        volume,
+        dsp,
+        loudness_cache: LoudnessCache::load(),
+        output_error,
+        ..AudioState::new(output, tap, track_events)
     }));
+    let watched_state = audio_state.clone();
+    let ticked_state = audio_state.clone();
+    let output_state = audio_state.clone();
+    let sleep_state = audio_state.clone();
+    let analysis_state = audio_state.clone();
+    let session_state = audio_state.clone();
+    let exit_state = audio_state.clone();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(audio_state)
+        .setup(move |app| {
+            let handle = app.handle().clone();
+            thread::spawn(move || watch_tracks(handle, watched_state, track_receiver));
+            let handle = app.handle().clone();
+            thread::spawn(move || tick_progress(handle, ticked_state));
+            let handle = app.handle().clone();
+            thread::spawn(move || watch_output(handle, output_state));
+            let handle = app.handle().clone();
+            thread::spawn(move || tick_sleep_timer(handle, sleep_state));
+            let handle = app.handle().clone();
+            thread::spawn(move || tick_analysis(handle, analysis_state));
+            let handle = app.handle().clone();
+            thread::spawn(move || tick_session(handle, session_state));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             play_song,
             pause_song,
             resume_song,
             stop_song,
+            restore_session,
             set_volume,
             seek_to,
+            set_playback_rate,
+            set_pitch_semitones,
+            set_loop_region,
+            clear_loop_region,
+            get_playback_format,
+            get_queue,
+            enqueue,
+            insert_next,
+            remove_from_queue,
+            move_in_queue,
+            clear_queue,
+            set_repeat_mode,
+            set_shuffle,
+            next_track,
+            previous_track,
+            set_progress_interval,
+            retry_audio_output,
+            get_preload,
+            set_preload,
+            get_crossfade,
+            set_crossfade,
+            get_eq,
+            set_eq_mode,
+            set_eq_band,
+            set_eq_preset,
+            get_replay_gain,
+            set_replay_gain,
+            set_visualizer_enabled,
+            get_config_visualizer_state,
+            set_level_meter_enabled,
+            clear_level_clip,
+            get_spectrum_settings,
+            set_spectrum_settings,
+            get_channel_settings,
+            set_balance,
+            set_mono,
+            set_crossfeed,
+            get_declick,
+            set_declick,
+            scan_loudness,
+            cancel_loudness_scan,
+            get_waveform,
+            generate_waveforms,
+            cancel_waveform_job,
             scan_music_file,
-            read_lyrics
+            get_supported_formats,
+            read_lyrics,
+            set_sleep_timer,
+            get_sleep_timer
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(move |_, event| {
+            // Nothing is left to report a failure to once the app exits.
+            if let tauri::RunEvent::Exit = event {
+                let _ = save_session(&exit_state);
+            }
+        });
 }
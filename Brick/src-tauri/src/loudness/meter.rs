@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+use super::r128::{channel_weight, k_weighting, to_lufs, STEPS_PER_SHORT_TERM};
+use crate::dsp::biquad::Biquad;
+
+// Sample level counted as an over.
+const CLIP_LEVEL: f32 = 1.0;
+// Level reported for silence.
+const FLOOR_DB: f32 = -120.0;
+
+/// Payload of `native-audio://levels`, one entry per output channel.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LevelPayload {
+    /// Highest sample since the previous event, in dBFS.
+    pub peak_db: Vec<f32>,
+    /// RMS since the previous event, in dBFS.
+    pub rms_db: Vec<f32>,
+    /// Loudness of the last 3 seconds of the mix.
+    pub short_term_lufs: Option<f32>,
+    /// Latched once a channel reaches full scale, until cleared.
+    pub clipped: Vec<bool>,
+}
+
+/// Peak, RMS and short-term loudness of the interleaved samples sent to the
+/// output.
+pub struct LevelMeter {
+    channels: usize,
+    sample_rate: u32,
+    channel: usize,
+    peak: Vec<f32>,
+    square_sum: Vec<f64>,
+    frames: usize,
+    clipped: Vec<bool>,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    step_energy: f64,
+    step_filled: usize,
+    // Mean square energy of the last 100 ms steps, for short-term loudness.
+    steps: VecDeque<f64>,
+}
+
+impl LevelMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let [shelf, high_pass] = k_weighting(sample_rate);
+
+        Self {
+            channels,
+            sample_rate,
+            channel: 0,
+            peak: vec![0.0; channels],
+            square_sum: vec![0.0; channels],
+            frames: 0,
+            clipped: vec![false; channels],
+            weights: (0..channels)
+                .map(|channel| channel_weight(channels, channel))
+                .collect(),
+            filters: vec![[Biquad::new(shelf), Biquad::new(high_pass)]; channels],
+            step_frames: (sample_rate as usize / 10).max(1),
+            step_energy: 0.0,
+            step_filled: 0,
+            steps: VecDeque::with_capacity(STEPS_PER_SHORT_TERM),
+        }
+    }
+
+    /// Whether the meter measures samples of this format.
+    pub fn matches(&self, channels: u16, sample_rate: u32) -> bool {
+        self.channels == channels.max(1) as usize && self.sample_rate == sample_rate
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            let channel = self.channel;
+            let level = sample.abs();
+            self.peak[channel] = self.peak[channel].max(level);
+            self.square_sum[channel] += (sample as f64) * (sample as f64);
+            if level >= CLIP_LEVEL {
+                self.clipped[channel] = true;
+            }
+
+            let [shelf, high_pass] = &mut self.filters[channel];
+            let filtered = high_pass.process(shelf.process(sample as f64));
+            self.step_energy += self.weights[channel] * filtered * filtered;
+
+            self.channel += 1;
+            if self.channel == self.channels {
+                self.channel = 0;
+                self.frames += 1;
+                self.step_filled += 1;
+                if self.step_filled == self.step_frames {
+                    if self.steps.len() == STEPS_PER_SHORT_TERM {
+                        self.steps.pop_front();
+                    }
+                    self.steps.push_back(self.step_energy / self.step_frames as f64);
+                    self.step_energy = 0.0;
+                    self.step_filled = 0;
+                }
+            }
+        }
+    }
+
+    pub fn clear_clip(&mut self) {
+        self.clipped.fill(false);
+    }
+
+    /// Levels since the previous report.
+    pub fn report(&mut self) -> LevelPayload {
+        let frames = self.frames.max(1) as f64;
+        let payload = LevelPayload {
+            peak_db: self.peak.iter().map(|&peak| to_db(peak)).collect(),
+            rms_db: self
+                .square_sum
+                .iter()
+                .map(|&sum| to_db((sum / frames).sqrt() as f32))
+                .collect(),
+            short_term_lufs: (!self.steps.is_empty())
+                .then(|| self.steps.iter().sum::<f64>() / self.steps.len() as f64)
+                .filter(|&energy| energy > 0.0)
+                .map(|energy| to_lufs(energy) as f32),
+            clipped: self.clipped.clone(),
+        };
+
+        self.peak.fill(0.0);
+        self.square_sum.fill(0.0);
+        self.frames = 0;
+        payload
+    }
+}
+
+fn to_db(level: f32) -> f32 {
+    if level > 0.0 {
+        (20.0 * level.log10()).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
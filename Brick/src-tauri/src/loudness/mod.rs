@@ -0,0 +1,392 @@
+pub mod meter;
+pub mod r128;
+
+use lofty::{ItemKey, Probe, Tag, TagExt, TaggedFileExt};
+use rodio::Source;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::{cue, decoder::SymphoniaDecoder, storage, tags::ReplayGainTags};
+use r128::{integrated_loudness, R128Meter};
+
+/// ReplayGain 2.0 reference level.
+const REFERENCE_LUFS: f64 = -18.0;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// Samples decoded between two checks of the cancel flag.
+const CANCEL_CHECK_SAMPLES: usize = 1 << 14;
+
+/// Loudness of one analysed track, with the ReplayGain values derived from it.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessResult {
+    pub file_path: String,
+    pub integrated_lufs: Option<f64>,
+    pub loudness_range: f64,
+    /// `None` for digital silence, whose peak has no level in dB.
+    pub true_peak_dbtp: Option<f64>,
+    pub track_gain: Option<f64>,
+    pub track_peak: f64,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl LoudnessResult {
+    pub fn replay_gain(&self) -> ReplayGainTags {
+        ReplayGainTags {
+            track_gain: self.track_gain.map(|gain| gain as f32),
+            track_peak: Some(self.track_peak as f32),
+            album_gain: self.album_gain.map(|gain| gain as f32),
+            album_peak: self.album_peak.map(|peak| peak as f32),
+        }
+    }
+}
+
+/// Progress of the file currently being analysed by a job.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanProgress {
+    pub job_id: u64,
+    pub file_path: String,
+    pub file_index: usize,
+    pub file_count: usize,
+    /// Fraction of the current file analysed, from 0 to 1.
+    pub progress: f32,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanError {
+    pub file_path: String,
+    pub error: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanFinished {
+    pub job_id: u64,
+    pub cancelled: bool,
+    pub results: Vec<LoudnessResult>,
+    pub errors: Vec<ScanError>,
+}
+
+/// What a scan job was asked to do.
+pub struct ScanRequest {
+    pub job_id: u64,
+    pub file_paths: Vec<String>,
+    /// Treat the files as one album and compute album gain and peak too.
+    pub as_album: bool,
+    /// Write `REPLAYGAIN_*` tags into the files instead of only caching them.
+    pub write_tags: bool,
+}
+
+/// Analyses every file of `request`, calling `on_progress` along the way.
+/// Album values need every track, so an album job that is cancelled returns
+/// no results; a track job keeps the ones already finished.
+pub fn run_scan(
+    request: &ScanRequest,
+    cancelled: &AtomicBool,
+    mut on_progress: impl FnMut(ScanProgress),
+) -> ScanFinished {
+    let file_count = request.file_paths.len();
+    let mut results = Vec::with_capacity(file_count);
+    let mut album_blocks = Vec::new();
+    let mut errors = Vec::new();
+
+    for (file_index, file_path) in request.file_paths.iter().enumerate() {
+        let report = |progress| ScanProgress {
+            job_id: request.job_id,
+            file_path: file_path.clone(),
+            file_index,
+            file_count,
+            progress,
+        };
+
+        match measure(file_path, cancelled, |progress| on_progress(report(progress))) {
+            Ok(Some(measurement)) => {
+                let result = LoudnessResult {
+                    file_path: file_path.clone(),
+                    integrated_lufs: measurement.integrated,
+                    loudness_range: measurement.loudness_range,
+                    true_peak_dbtp: (measurement.true_peak > 0.0)
+                        .then(|| 20.0 * measurement.true_peak.log10()),
+                    track_gain: measurement.integrated.map(|lufs| REFERENCE_LUFS - lufs),
+                    track_peak: measurement.true_peak,
+                    album_gain: None,
+                    album_peak: None,
+                };
+                album_blocks.extend(measurement.blocks);
+                results.push(result);
+            }
+            Ok(None) => break,
+            Err(error) => errors.push(ScanError {
+                file_path: file_path.clone(),
+                error,
+            }),
+        }
+    }
+
+    let is_cancelled = cancelled.load(Ordering::Relaxed);
+    if request.as_album {
+        if is_cancelled {
+            results.clear();
+        } else {
+            let album_gain = integrated_loudness(&album_blocks).map(|lufs| REFERENCE_LUFS - lufs);
+            let album_peak = results.iter().map(|result| result.track_peak).fold(0.0, f64::max);
+            for result in &mut results {
+                result.album_gain = album_gain;
+                result.album_peak = Some(album_peak);
+            }
+        }
+    }
+
+    if request.write_tags {
+        // Silent files have no gain to write.
+        for result in results.iter().filter(|result| result.track_gain.is_some()) {
+            if let Err(error) = write_tags(result) {
+                errors.push(ScanError {
+                    file_path: result.file_path.clone(),
+                    error,
+                });
+            }
+        }
+    }
+
+    ScanFinished {
+        job_id: request.job_id,
+        cancelled: is_cancelled,
+        results,
+        errors,
+    }
+}
+
+/// Decodes a whole file through the meter. Returns `Ok(None)` if cancelled.
+fn measure(
+    file_path: &str,
+    cancelled: &AtomicBool,
+    mut on_progress: impl FnMut(f32),
+) -> Result<Option<r128::Measurement>, String> {
+    let mut decoder = SymphoniaDecoder::open(file_path)?;
+    let channels = decoder.channels();
+    let sample_rate = decoder.sample_rate();
+    let total_samples = decoder
+        .total_duration()
+        .map(|duration| duration.as_secs_f64() * sample_rate as f64 * channels as f64);
+
+    let mut meter = R128Meter::new(channels, sample_rate);
+    let mut samples = 0usize;
+    let mut last_report = Instant::now();
+    on_progress(0.0);
+
+    for sample in decoder.by_ref() {
+        meter.push(sample);
+        samples += 1;
+
+        if samples.is_multiple_of(CANCEL_CHECK_SAMPLES) {
+            if cancelled.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            if let Some(total) = total_samples.filter(|_| last_report.elapsed() >= PROGRESS_INTERVAL) {
+                on_progress((samples as f64 / total).min(1.0) as f32);
+                last_report = Instant::now();
+            }
+        }
+    }
+
+    on_progress(1.0);
+    Ok(Some(meter.finish()))
+}
+
+fn write_tags(result: &LoudnessResult) -> Result<(), String> {
+    if cue::split_range(&result.file_path).1.is_some() {
+        return Err("Tag write error: tracks of a cue sheet have no tags of their own".to_string());
+    }
+    let mut tagged_file = Probe::open(&result.file_path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| format!("Tag read error: {}", e))?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let Some(tag) = tagged_file.primary_tag_mut() else {
+        return Err("Tag write error: file has no writable tag".to_string());
+    };
+
+    let values = [
+        (ItemKey::ReplayGainTrackGain, result.track_gain.map(format_gain)),
+        (ItemKey::ReplayGainTrackPeak, Some(format_peak(result.track_peak))),
+        (ItemKey::ReplayGainAlbumGain, result.album_gain.map(format_gain)),
+        (ItemKey::ReplayGainAlbumPeak, result.album_peak.map(format_peak)),
+    ];
+    for (key, value) in values {
+        if let Some(value) = value {
+            tag.insert_text(key, value);
+        }
+    }
+
+    tag.save_to_path(&result.file_path)
+        .map_err(|e| format!("Tag write error: {}", e))
+}
+
+fn format_gain(gain: f64) -> String {
+    format!("{:.2} dB", gain)
+}
+
+fn format_peak(peak: f64) -> String {
+    format!("{:.6}", peak)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheEntry {
+    // Modification time of the file when it was analysed, in seconds.
+    modified: u64,
+    result: LoudnessResult,
+}
+
+/// Scan results kept in Brick's data directory, for files whose tags were
+/// not written or that have no tags at all.
+#[derive(Default)]
+pub struct LoudnessCache {
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl LoudnessCache {
+    fn cache_path() -> Option<PathBuf> {
+        let mut path = storage::app_data_dir()?;
+        path.push("loudness.json");
+        Some(path)
+    }
+
+    pub fn load() -> Self {
+        let entries = Self::cache_path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+        Self { entries }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = Self::cache_path().ok_or_else(|| "No data directory".to_string())?;
+        let contents = serde_json::to_vec(&self.entries)
+            .map_err(|e| format!("Loudness cache error: {}", e))?;
+        storage::write_atomic(&path, &contents).map_err(|e| format!("Loudness cache error: {}", e))
+    }
+
+    /// The cached result for `file_path`, unless the file changed since.
+    pub fn get(&self, file_path: &str) -> Option<&LoudnessResult> {
+        let entry = self.entries.get(file_path)?;
+        (modified_secs(file_path) == Some(entry.modified)).then_some(&entry.result)
+    }
+
+    pub fn insert_all(&mut self, results: &[LoudnessResult]) -> Result<(), String> {
+        for result in results {
+            let Some(modified) = modified_secs(&result.file_path) else {
+                continue;
+            };
+            self.entries.insert(
+                result.file_path.clone(),
+                CacheEntry {
+                    modified,
+                    result: result.clone(),
+                },
+            );
+        }
+        self.save()
+    }
+}
+
+fn modified_secs(file_path: &str) -> Option<u64> {
+    let modified = std::fs::metadata(cue::split_range(file_path).0).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Cancel flags of the jobs still running, by job id.
+#[derive(Default)]
+pub struct ScanJobs {
+    next_id: u64,
+    running: HashMap<u64, Arc<AtomicBool>>,
+}
+
+impl ScanJobs {
+    pub fn start(&mut self) -> (u64, Arc<AtomicBool>) {
+        self.next_id += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.running.insert(self.next_id, cancelled.clone());
+        (self.next_id, cancelled)
+    }
+
+    pub fn cancel(&self, job_id: u64) -> Result<(), String> {
+        let cancelled = self
+            .running
+            .get(&job_id)
+            .ok_or_else(|| format!("No job running with id {}", job_id))?;
+        cancelled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn finish(&mut self, job_id: u64) {
+        self.running.remove(&job_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a mono 16-bit WAV file of `samples` at 48 kHz.
+    fn write_wav(name: &str, samples: &[i16]) -> String {
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(48_000u32.to_le_bytes());
+        out.extend(96_000u32.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for sample in samples {
+            out.extend(sample.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("brick-loudness-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn silent_file_has_no_peak_level_and_gets_no_tags() {
+        let file_path = write_wav("silent", &[0; 48_000]);
+        let request = ScanRequest {
+            job_id: 1,
+            file_paths: vec![file_path.clone()],
+            as_album: false,
+            write_tags: true,
+        };
+
+        let finished = run_scan(&request, &AtomicBool::new(false), |_| {});
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert!(finished.errors.is_empty());
+        let result = &finished.results[0];
+        assert_eq!(result.true_peak_dbtp, None);
+        assert_eq!(result.track_gain, None);
+        // Cached results have to load again.
+        let json = serde_json::to_string(result).unwrap();
+        let loaded: LoudnessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.true_peak_dbtp, None);
+    }
+}
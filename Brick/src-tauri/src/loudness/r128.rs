@@ -0,0 +1,335 @@
+use std::f64::consts::PI;
+
+use crate::dsp::biquad::{Biquad, Coefficients};
+
+// Gating thresholds from ITU-R BS.1770-4 and EBU Tech 3342.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+const RANGE_RELATIVE_GATE_LU: f64 = -20.0;
+
+// Measurement works on 100 ms steps: momentary blocks span 4 of them and
+// short-term windows 30.
+const STEPS_PER_BLOCK: usize = 4;
+pub(crate) const STEPS_PER_SHORT_TERM: usize = 30;
+
+// Taps per phase of the true-peak interpolation filter.
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Loudness of a mean square energy, in LUFS.
+pub fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// The two K-weighting stages (high shelf, then RLB high-pass) for any sample
+/// rate, derived the same way as libebur128.
+pub(crate) fn k_weighting(sample_rate: u32) -> [Coefficients; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Coefficients::new(
+        [vh + vb * k / q + k * k, 2.0 * (k * k - vh), vh - vb * k / q + k * k],
+        [1.0 + k / q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    // Only the feedback side is normalised here, so scale `b` to cancel it.
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Coefficients::new(
+        [a0, -2.0 * a0, a0],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    [shelf, high_pass]
+}
+
+/// BS.1770 channel weights: surrounds count +1.5 dB, the LFE is left out.
+pub(crate) fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// EBU R128 meter fed with interleaved samples of a single track.
+pub struct R128Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    step_energy: f64,
+    step_filled: usize,
+    channel: usize,
+    // Mean square energy of every complete 100 ms step.
+    steps: Vec<f64>,
+    true_peak: TruePeak,
+}
+
+impl R128Meter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let [shelf, high_pass] = k_weighting(sample_rate);
+
+        Self {
+            channels,
+            weights: (0..channels)
+                .map(|channel| channel_weight(channels, channel))
+                .collect(),
+            filters: vec![[Biquad::new(shelf), Biquad::new(high_pass)]; channels],
+            step_frames: (sample_rate as usize / 10).max(1),
+            step_energy: 0.0,
+            step_filled: 0,
+            channel: 0,
+            steps: Vec::new(),
+            true_peak: TruePeak::new(channels, sample_rate),
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let channel = self.channel;
+        self.true_peak.push(channel, sample);
+
+        let [shelf, high_pass] = &mut self.filters[channel];
+        let filtered = high_pass.process(shelf.process(sample as f64));
+        self.step_energy += self.weights[channel] * filtered * filtered;
+
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.step_filled += 1;
+            if self.step_filled == self.step_frames {
+                self.steps.push(self.step_energy / self.step_frames as f64);
+                self.step_energy = 0.0;
+                self.step_filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Measurement {
+        let blocks = windows(&self.steps, STEPS_PER_BLOCK);
+        let short_term = windows(&self.steps, STEPS_PER_SHORT_TERM);
+
+        Measurement {
+            integrated: integrated_loudness(&blocks),
+            loudness_range: loudness_range(&short_term),
+            true_peak: self.true_peak.peak,
+            blocks,
+        }
+    }
+}
+
+/// Result of measuring one track.
+pub struct Measurement {
+    /// `None` when the track is silent below the absolute gate.
+    pub integrated: Option<f64>,
+    /// Loudness range in LU.
+    pub loudness_range: f64,
+    /// Linear sample amplitude.
+    pub true_peak: f64,
+    /// Gating block energies, kept so an album can be gated as a whole.
+    pub blocks: Vec<f64>,
+}
+
+/// Energies of overlapping windows of `length` steps, advancing one step at a time.
+fn windows(steps: &[f64], length: usize) -> Vec<f64> {
+    steps
+        .windows(length)
+        .map(|window| window.iter().sum::<f64>() / length as f64)
+        .collect()
+}
+
+fn mean(energies: &[f64]) -> Option<f64> {
+    (!energies.is_empty()).then(|| energies.iter().sum::<f64>() / energies.len() as f64)
+}
+
+/// Gated integrated loudness over the blocks of one track or a whole album.
+pub fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&energy| to_lufs(energy) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    let threshold = to_lufs(mean(&audible)?) + RELATIVE_GATE_LU;
+
+    let gated: Vec<f64> = audible
+        .into_iter()
+        .filter(|&energy| to_lufs(energy) > threshold)
+        .collect();
+    mean(&gated).map(to_lufs)
+}
+
+/// Spread between the 10th and 95th percentile of gated short-term loudness.
+fn loudness_range(short_term: &[f64]) -> f64 {
+    let audible: Vec<f64> = short_term
+        .iter()
+        .copied()
+        .filter(|&energy| to_lufs(energy) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    let Some(energy) = mean(&audible) else {
+        return 0.0;
+    };
+    let threshold = to_lufs(energy) + RANGE_RELATIVE_GATE_LU;
+
+    let mut gated: Vec<f64> = audible
+        .into_iter()
+        .map(to_lufs)
+        .filter(|&loudness| loudness > threshold)
+        .collect();
+    if gated.is_empty() {
+        return 0.0;
+    }
+    gated.sort_by(f64::total_cmp);
+
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Inter-sample peak found by oversampling with a windowed-sinc interpolator.
+struct TruePeak {
+    factor: usize,
+    // Polyphase filter, `factor` phases of `TRUE_PEAK_TAPS` taps each.
+    phases: Vec<[f64; TRUE_PEAK_TAPS]>,
+    history: Vec<[f64; TRUE_PEAK_TAPS]>,
+    cursor: Vec<usize>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let factor = match sample_rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+
+        let length = factor * TRUE_PEAK_TAPS;
+        let center = (length - 1) as f64 / 2.0;
+        let phases = (0..factor)
+            .map(|phase| {
+                let mut taps = [0.0; TRUE_PEAK_TAPS];
+                for (tap, value) in taps.iter_mut().enumerate() {
+                    let n = (phase + tap * factor) as f64;
+                    let x = (n - center) / factor as f64;
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                    let window = 0.5 - 0.5 * (2.0 * PI * (n + 0.5) / length as f64).cos();
+                    *value = sinc * window;
+                }
+                taps
+            })
+            .collect();
+
+        Self {
+            factor,
+            phases,
+            history: vec![[0.0; TRUE_PEAK_TAPS]; channels],
+            cursor: vec![0; channels],
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, channel: usize, sample: f32) {
+        let sample = sample as f64;
+        self.peak = self.peak.max(sample.abs());
+        if self.factor == 1 {
+            return;
+        }
+
+        let history = &mut self.history[channel];
+        let cursor = &mut self.cursor[channel];
+        history[*cursor] = sample;
+        *cursor = (*cursor + 1) % TRUE_PEAK_TAPS;
+
+        for taps in &self.phases {
+            // Newest sample meets the first tap.
+            let value: f64 = taps
+                .iter()
+                .enumerate()
+                .map(|(tap, coefficient)| {
+                    coefficient * history[(*cursor + TRUE_PEAK_TAPS - 1 - tap) % TRUE_PEAK_TAPS]
+                })
+                .sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// Feeds a stereo 1 kHz sine to `meter`, `seconds` long at `dbfs` peak level.
+    fn sine(meter: &mut R128Meter, dbfs: f64, seconds: f64) {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        for n in 0..(seconds * RATE as f64) as usize {
+            let sample = (amplitude * (2.0 * PI * 1000.0 * n as f64 / RATE as f64).sin()) as f32;
+            meter.push(sample);
+            meter.push(sample);
+        }
+    }
+
+    fn assert_near(value: f64, expected: f64, tolerance: f64) {
+        assert!((value - expected).abs() <= tolerance, "{} is not {}", value, expected);
+    }
+
+    // EBU Tech 3341, cases 1 and 2.
+    #[test]
+    fn steady_sine_reads_its_level() {
+        for dbfs in [-23.0, -33.0] {
+            let mut meter = R128Meter::new(2, RATE);
+            sine(&mut meter, dbfs, 20.0);
+            assert_near(meter.finish().integrated.unwrap(), dbfs, 0.1);
+        }
+    }
+
+    // EBU Tech 3341, case 3: quiet passages fall below the relative gate.
+    #[test]
+    fn relative_gate_drops_quiet_passages() {
+        let mut meter = R128Meter::new(2, RATE);
+        sine(&mut meter, -36.0, 10.0);
+        sine(&mut meter, -23.0, 60.0);
+        sine(&mut meter, -36.0, 10.0);
+        assert_near(meter.finish().integrated.unwrap(), -23.0, 0.1);
+    }
+
+    // EBU Tech 3341, case 5: silence falls below the absolute gate.
+    #[test]
+    fn absolute_gate_drops_silence() {
+        let mut meter = R128Meter::new(2, RATE);
+        sine(&mut meter, -26.0, 20.0);
+        sine(&mut meter, -200.0, 20.0);
+        sine(&mut meter, -26.0, 20.0);
+        assert_near(meter.finish().integrated.unwrap(), -26.0, 0.1);
+
+        let mut silent = R128Meter::new(2, RATE);
+        sine(&mut silent, -200.0, 5.0);
+        assert_eq!(silent.finish().integrated, None);
+    }
+
+    // EBU Tech 3342, case 1.
+    #[test]
+    fn loudness_range_spans_two_levels() {
+        let mut meter = R128Meter::new(2, RATE);
+        sine(&mut meter, -20.0, 20.0);
+        sine(&mut meter, -30.0, 20.0);
+        assert_near(meter.finish().loudness_range, 10.0, 1.0);
+    }
+
+    #[test]
+    fn true_peak_finds_the_peak_between_samples() {
+        // A quarter of the sample rate, sampled 45° off its peaks.
+        let mut meter = R128Meter::new(1, RATE);
+        for n in 0..RATE as usize {
+            meter.push((0.5 * PI * n as f64 + 0.25 * PI).sin() as f32);
+        }
+        assert_near(meter.finish().true_peak, 1.0, 0.05);
+    }
+}
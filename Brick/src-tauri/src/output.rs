@@ -0,0 +1,912 @@
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample, StreamConfig, StreamError,
+};
+use rodio::{
+    dynamic_mixer::{self, DynamicMixer, DynamicMixerController},
+    Sink, Source,
+};
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Selects the backend: `device` (default), `null` or `wav:<path>`.
+const OUTPUT_ENV: &str = "BRICK_AUDIO_OUTPUT";
+/// Selects how fast a headless backend runs: `realtime` (default) or `unpaced`.
+const PACE_ENV: &str = "BRICK_AUDIO_PACE";
+
+// Format the headless backends mix to.
+const HEADLESS_CHANNELS: u16 = 2;
+const HEADLESS_SAMPLE_RATE: u32 = 44_100;
+// Audio pulled from the mixer per step of a headless backend.
+const HEADLESS_CHUNK: Duration = Duration::from_millis(10);
+// How often the device thread checks whether its stream should be dropped.
+const STREAM_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+// Most frames `Reframed` reads ahead of what the output plays.
+const REFRAME_FRAMES: usize = 512;
+// Samples the tap collects on the audio thread before handing them over.
+const TAP_BATCH: usize = 1024;
+// Most captured audio kept for readers that fall behind.
+const TAP_CAPACITY: Duration = Duration::from_secs(1);
+
+/// Where the sinks of `AudioState` send their samples.
+pub trait OutputBackend: Send + Sync {
+    /// Starts playing `source`, mixed with everything already playing.
+    fn play(&self, source: Box<dyn Source<Item = f32> + Send>) -> Result<(), String>;
+
+    fn format(&self) -> OutputFormat;
+
+    /// Creates a sink whose output is played by this backend.
+    fn new_sink(&self) -> Result<Sink, String> {
+        let (sink, queue) = Sink::new_idle();
+        self.play(Box::new(Reframed::new(queue)))?;
+        Ok(sink)
+    }
+
+    /// Whether the backend stopped playing for good, e.g. because its device
+    /// was unplugged. A lost backend has to be replaced.
+    fn is_lost(&self) -> bool {
+        false
+    }
+}
+
+/// Format of the stream the output plays, which every track is converted to.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputFormat {
+    /// Name of the sound device; headless backends have none.
+    pub device: Option<String>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Sample format the device is fed, e.g. `f32` or `i16`.
+    pub sample_format: String,
+}
+
+/// Which backend to open.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum OutputKind {
+    Device,
+    Null,
+    Wav(String),
+}
+
+impl OutputKind {
+    /// Parses `device`, `null` or `wav:<path>`; empty means `device`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "" | "device" => Ok(OutputKind::Device),
+            "null" => Ok(OutputKind::Null),
+            other => match other.strip_prefix("wav:") {
+                Some(path) => Ok(OutputKind::Wav(path.to_string())),
+                None => Err(format!("Unknown audio output: {}", other)),
+            },
+        }
+    }
+}
+
+/// How a headless backend pulls the mix. Sound devices set their own pace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pace {
+    /// As a sound device would, so tracks advance as they would on one.
+    RealTime,
+    /// As fast as the mix is computed, to render playback in tests and CI.
+    /// Silent stretches still go at real time so an idle output doesn't spin.
+    Unpaced,
+}
+
+impl Pace {
+    /// Parses `realtime` or `unpaced`; empty means `realtime`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "" | "realtime" => Ok(Pace::RealTime),
+            "unpaced" => Ok(Pace::Unpaced),
+            other => Err(format!("Unknown audio pace: {}", other)),
+        }
+    }
+}
+
+/// Opens a backend, copying what it plays into `tap`.
+pub fn open(
+    kind: &OutputKind,
+    pace: Pace,
+    tap: Arc<SampleTap>,
+) -> Result<Box<dyn OutputBackend>, String> {
+    match kind {
+        OutputKind::Device => Ok(Box::new(DeviceOutput::open(tap)?)),
+        OutputKind::Null => Ok(Box::new(HeadlessOutput::null(tap, pace))),
+        OutputKind::Wav(path) => Ok(Box::new(HeadlessOutput::wav(path, tap, pace)?)),
+    }
+}
+
+/// Opens the backend named by `BRICK_AUDIO_OUTPUT` at the pace named by
+/// `BRICK_AUDIO_PACE`, copying what it plays into `tap`.
+pub fn open_from_env(tap: Arc<SampleTap>) -> Result<Box<dyn OutputBackend>, String> {
+    let kind = OutputKind::parse(&std::env::var(OUTPUT_ENV).unwrap_or_default())
+        .map_err(|e| format!("{} error: {}", OUTPUT_ENV, e))?;
+    let pace = Pace::parse(&std::env::var(PACE_ENV).unwrap_or_default())
+        .map_err(|e| format!("{} error: {}", PACE_ENV, e))?;
+    open(&kind, pace, tap)
+}
+
+/// Copies of the samples sent to the output, collected for analysis while
+/// enabled. Disabled, it costs the audio thread one atomic load per sample.
+#[derive(Default)]
+pub struct SampleTap {
+    enabled: AtomicBool,
+    channels: AtomicU32,
+    sample_rate: AtomicU32,
+    captured: Mutex<Vec<f32>>,
+}
+
+impl SampleTap {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            if let Ok(mut captured) = self.captured.lock() {
+                captured.clear();
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Channel count and sample rate of the captured samples.
+    pub fn format(&self) -> (u16, u32) {
+        (
+            self.channels.load(Ordering::Relaxed) as u16,
+            self.sample_rate.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Interleaved samples played since the last call.
+    pub fn take(&self) -> Vec<f32> {
+        self.captured
+            .lock()
+            .map(|mut captured| std::mem::take(&mut *captured))
+            .unwrap_or_default()
+    }
+
+    fn set_format(&self, channels: u16, sample_rate: u32) {
+        self.channels.store(channels as u32, Ordering::Relaxed);
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Moves `batch` over unless a reader holds the lock, in which case the
+    /// audio thread keeps it for the next try rather than wait.
+    fn hand_over(&self, batch: &mut Vec<f32>) {
+        let Ok(mut captured) = self.captured.try_lock() else {
+            if batch.len() > 4 * TAP_BATCH {
+                batch.clear();
+            }
+            return;
+        };
+        captured.append(batch);
+
+        let (channels, sample_rate) = self.format();
+        let capacity = (TAP_CAPACITY.as_secs_f64() * sample_rate as f64) as usize * channels as usize;
+        if captured.len() > capacity {
+            let excess = captured.len() - capacity;
+            captured.drain(..excess);
+        }
+    }
+}
+
+/// Mixer output passing through a `SampleTap`.
+struct Tapped<I> {
+    inner: I,
+    tap: Arc<SampleTap>,
+    batch: Vec<f32>,
+}
+
+impl<I: Iterator<Item = f32>> Tapped<I> {
+    fn new(inner: I, tap: Arc<SampleTap>, channels: u16, sample_rate: u32) -> Self {
+        tap.set_format(channels, sample_rate);
+        Self {
+            inner,
+            tap,
+            batch: Vec::with_capacity(TAP_BATCH),
+        }
+    }
+}
+
+impl<I: Iterator<Item = f32>> Iterator for Tapped<I> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        if self.tap.is_enabled() {
+            self.batch.push(sample);
+            if self.batch.len() >= TAP_BATCH {
+                self.tap.hand_over(&mut self.batch);
+            }
+        } else if !self.batch.is_empty() {
+            self.batch.clear();
+        }
+        Some(sample)
+    }
+}
+
+/// Sink queue cut into frames that each hold samples of a single source.
+/// rodio's queue reports the format of the source that just ended for the
+/// first frame of the next one, and a mono format for the first frame of an
+/// idle sink, so the first samples of a track would be converted as if they
+/// had the wrong channel count or sample rate.
+struct Reframed<S> {
+    inner: S,
+    frame: Vec<f32>,
+    offset: usize,
+    channels: u16,
+    sample_rate: u32,
+    // First sample of the next frame, read while looking for the end of this
+    // one, with its format.
+    next: Option<(f32, u16, u32)>,
+}
+
+impl<S: Source<Item = f32>> Reframed<S> {
+    fn new(inner: S) -> Self {
+        let mut reframed = Self {
+            channels: inner.channels(),
+            sample_rate: inner.sample_rate(),
+            inner,
+            frame: Vec::new(),
+            offset: 0,
+            next: None,
+        };
+        reframed.fill();
+        reframed
+    }
+
+    /// Reads the next frame: samples up to where the format changes.
+    fn fill(&mut self) {
+        self.frame.clear();
+        self.offset = 0;
+        loop {
+            let read = self.next.take().or_else(|| {
+                // The queue moves on to the next source within `next`.
+                let sample = self.inner.next()?;
+                Some((sample, self.inner.channels(), self.inner.sample_rate()))
+            });
+            let Some((sample, channels, sample_rate)) = read else {
+                return;
+            };
+            if self.frame.is_empty() {
+                self.channels = channels;
+                self.sample_rate = sample_rate;
+            } else if (channels, sample_rate) != (self.channels, self.sample_rate) {
+                self.next = Some((sample, channels, sample_rate));
+                return;
+            }
+            self.frame.push(sample);
+            if self.frame.len() >= REFRAME_FRAMES * self.channels.max(1) as usize {
+                return;
+            }
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Reframed<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = *self.frame.get(self.offset)?;
+        self.offset += 1;
+        // Read ahead right away so the frame length is only zero at the end.
+        if self.offset == self.frame.len() {
+            self.fill();
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Reframed<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.frame.len() - self.offset)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Flags shared between a `DeviceOutput` and the thread owning its stream.
+#[derive(Default)]
+struct StreamStatus {
+    // Set by the stream's error callback when the device went away.
+    lost: AtomicBool,
+    // Set when the `DeviceOutput` is dropped.
+    closed: AtomicBool,
+}
+
+/// The default sound device, played through a cpal stream of our own so
+/// errors reported by the device can be acted upon.
+pub struct DeviceOutput {
+    mixer: Arc<DynamicMixerController<f32>>,
+    status: Arc<StreamStatus>,
+    format: OutputFormat,
+}
+
+impl DeviceOutput {
+    /// The cpal `Stream` isn't `Send`, so it is opened and kept alive on a
+    /// thread of its own until the device is lost or the output dropped.
+    pub fn open(tap: Arc<SampleTap>) -> Result<Self, String> {
+        let status = Arc::new(StreamStatus::default());
+        let stream_status = status.clone();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let stream = match open_stream(stream_status.clone(), tap) {
+                Ok((stream, mixer, format)) => {
+                    let _ = sender.send(Ok((mixer, format)));
+                    stream
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+
+            while !stream_status.lost.load(Ordering::SeqCst)
+                && !stream_status.closed.load(Ordering::SeqCst)
+            {
+                thread::sleep(STREAM_CHECK_INTERVAL);
+            }
+            drop(stream);
+        });
+
+        let (mixer, format) = receiver
+            .recv()
+            .map_err(|e| format!("Audio output error: {}", e))??;
+        Ok(Self {
+            mixer,
+            status,
+            format,
+        })
+    }
+}
+
+impl Drop for DeviceOutput {
+    fn drop(&mut self) {
+        self.status.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl OutputBackend for DeviceOutput {
+    fn play(&self, source: Box<dyn Source<Item = f32> + Send>) -> Result<(), String> {
+        if self.is_lost() {
+            return Err("Audio output error: the output device is gone".to_string());
+        }
+        self.mixer.add(source);
+        Ok(())
+    }
+
+    fn format(&self) -> OutputFormat {
+        self.format.clone()
+    }
+
+    fn is_lost(&self) -> bool {
+        self.status.lost.load(Ordering::SeqCst)
+    }
+}
+
+/// Opens the default output device in its preferred format, with a mixer
+/// feeding it.
+fn open_stream(
+    status: Arc<StreamStatus>,
+    tap: Arc<SampleTap>,
+) -> Result<(cpal::Stream, Arc<DynamicMixerController<f32>>, OutputFormat), String> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| "Audio output error: no output device available".to_string())?;
+    let supported = device
+        .default_output_config()
+        .map_err(|e| format!("Audio output error: {}", e))?;
+    let config = supported.config();
+    let format = OutputFormat {
+        device: device.name().ok(),
+        sample_rate: config.sample_rate.0,
+        channels: config.channels,
+        sample_format: supported.sample_format().to_string(),
+    };
+    let (controller, mixer) = dynamic_mixer::mixer(config.channels, config.sample_rate.0);
+    let mixer = Tapped::new(mixer, tap, config.channels, config.sample_rate.0);
+
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer, status),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer, status),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer, status),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, mixer, status),
+        other => Err(format!("Audio output error: unsupported sample format {}", other)),
+    }?;
+    stream
+        .play()
+        .map_err(|e| format!("Audio output error: {}", e))?;
+
+    Ok((stream, controller, format))
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut mixer: Tapped<DynamicMixer<f32>>,
+    status: Arc<StreamStatus>,
+) -> Result<cpal::Stream, String>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                for sample in data.iter_mut() {
+                    *sample = mixer.next().map(T::from_sample).unwrap_or(T::EQUILIBRIUM);
+                }
+            },
+            // `watch_output` reports and replaces a device that went away;
+            // other stream errors don't stop playback.
+            move |e| {
+                if matches!(e, StreamError::DeviceNotAvailable) {
+                    status.lost.store(true, Ordering::SeqCst);
+                }
+            },
+            None,
+        )
+        .map_err(|e| format!("Audio output error: {}", e))
+}
+
+/// Backend without a sound device. A thread pulls the mix, in real time or
+/// as fast as it can, and hands every chunk to a writer until the backend is
+/// dropped.
+pub struct HeadlessOutput {
+    mixer: Arc<DynamicMixerController<f32>>,
+    closed: Arc<AtomicBool>,
+    // Set once writing failed; the mix keeps being pulled so playback goes on.
+    failed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HeadlessOutput {
+    /// Throws the samples away.
+    pub fn null(tap: Arc<SampleTap>, pace: Pace) -> Self {
+        Self::start(tap, pace, |_| Ok(()))
+    }
+
+    /// Records the mix into a 32-bit float WAV file, finished when the
+    /// backend is dropped.
+    pub fn wav(path: &str, tap: Arc<SampleTap>, pace: Pace) -> Result<Self, String> {
+        let mut writer = WavWriter::create(path, HEADLESS_CHANNELS, HEADLESS_SAMPLE_RATE)
+            .map_err(|e| format!("WAV output error: {}", e))?;
+        Ok(Self::start(tap, pace, move |samples| writer.write(samples)))
+    }
+
+    fn start(
+        tap: Arc<SampleTap>,
+        pace: Pace,
+        mut write: impl FnMut(&[f32]) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        let (controller, mixer) = dynamic_mixer::mixer(HEADLESS_CHANNELS, HEADLESS_SAMPLE_RATE);
+        let mut mixer = Tapped::new(mixer, tap, HEADLESS_CHANNELS, HEADLESS_SAMPLE_RATE);
+        let closed = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (thread_closed, thread_failed) = (closed.clone(), failed.clone());
+
+        let thread = thread::spawn(move || {
+            let frames = (HEADLESS_SAMPLE_RATE as f64 * HEADLESS_CHUNK.as_secs_f64()) as usize;
+            let mut chunk = vec![0.0; frames * HEADLESS_CHANNELS as usize];
+            let mut deadline = Instant::now();
+
+            while !thread_closed.load(Ordering::SeqCst) {
+                for sample in chunk.iter_mut() {
+                    // The mixer yields nothing while no sink is attached.
+                    *sample = mixer.next().unwrap_or(0.0);
+                }
+                if !thread_failed.load(Ordering::Relaxed) && write(&chunk).is_err() {
+                    thread_failed.store(true, Ordering::Relaxed);
+                }
+
+                if pace == Pace::Unpaced && chunk.iter().any(|&sample| sample != 0.0) {
+                    deadline = Instant::now();
+                    continue;
+                }
+                deadline += HEADLESS_CHUNK;
+                if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+            }
+            // Dropping the writer finishes the file.
+        });
+
+        Self {
+            mixer: controller,
+            closed,
+            failed,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for HeadlessOutput {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl OutputBackend for HeadlessOutput {
+    fn play(&self, source: Box<dyn Source<Item = f32> + Send>) -> Result<(), String> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err("Audio output error: writing the output failed".to_string());
+        }
+        self.mixer.add(source);
+        Ok(())
+    }
+
+    fn format(&self) -> OutputFormat {
+        OutputFormat {
+            device: None,
+            sample_rate: HEADLESS_SAMPLE_RATE,
+            channels: HEADLESS_CHANNELS,
+            sample_format: "f32".to_string(),
+        }
+    }
+}
+
+/// Minimal WAV writer. The header gets its lengths when the writer is
+/// finished or dropped.
+struct WavWriter {
+    file: File,
+    data_len: u32,
+}
+
+impl WavWriter {
+    const HEADER_LEN: u64 = 44;
+
+    fn create(path: &str, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let block_align = channels * 4;
+
+        let mut header = Vec::with_capacity(Self::HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // Format 3 is IEEE float.
+        header.extend_from_slice(&3u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&32u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self { file, data_len: 0 })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.file.write_all(&bytes)?;
+        self.data_len = self.data_len.saturating_add(bytes.len() as u32);
+        Ok(())
+    }
+
+    /// Writes the lengths into the header.
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&self.data_len.saturating_add(36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decoder::SymphoniaDecoder,
+        dsp::{
+            eq::{EqControl, Equalizer},
+            DspControls,
+        },
+        tags::ReplayGainTags,
+        track::{TrackEvent, TrackSource},
+        AudioState,
+    };
+    use rodio::buffer::SamplesBuffer;
+    use std::f32::consts::PI;
+
+    fn temp_path(name: &str, extension: &str) -> String {
+        let file = format!("brick-output-{}-{}.{}", name, std::process::id(), extension);
+        std::env::temp_dir().join(file).to_string_lossy().into_owned()
+    }
+
+    /// Plays `sources` back to back through a sink into an unpaced WAV file
+    /// and returns the samples written, without the silence around them.
+    fn render(name: &str, sources: Vec<Box<dyn Source<Item = f32> + Send>>) -> Vec<f32> {
+        let path = temp_path(name, "wav");
+        let output = HeadlessOutput::wav(&path, Arc::default(), Pace::Unpaced).unwrap();
+        let sink = output.new_sink().unwrap();
+        for source in sources {
+            sink.append(source);
+        }
+        sink.sleep_until_end();
+        // The output still plays the frame `Reframed` read ahead.
+        thread::sleep(Duration::from_millis(100));
+        drop(sink);
+        drop(output);
+        read_rendered(&path)
+    }
+
+    /// Reads back a WAV file written by a headless output, without the
+    /// silence around the audio, and removes it.
+    fn read_rendered(path: &str) -> Vec<f32> {
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let data_len = u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize;
+        let riff_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(data_len, bytes.len() - WavWriter::HEADER_LEN as usize);
+        assert_eq!(riff_len, data_len + 36);
+
+        let samples: Vec<f32> = bytes[WavWriter::HEADER_LEN as usize..]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let start = samples.iter().position(|&sample| sample != 0.0).unwrap();
+        let end = samples.iter().rposition(|&sample| sample != 0.0).unwrap();
+        samples[start..=end].to_vec()
+    }
+
+    /// Writes a 16-bit WAV file at the headless sample rate holding
+    /// `frames` frames of a sawtooth that never crosses zero.
+    fn pcm_file(name: &str, channels: u16, frames: usize, step: i32) -> (String, Vec<f32>) {
+        let samples: Vec<i16> = (0..frames * channels as usize)
+            .map(|index| (1000 + (index as i32 * step) % 20_000) as i16)
+            .collect();
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(channels.to_le_bytes());
+        out.extend(HEADLESS_SAMPLE_RATE.to_le_bytes());
+        out.extend((HEADLESS_SAMPLE_RATE * channels as u32 * 2).to_le_bytes());
+        out.extend((channels * 2).to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for sample in &samples {
+            out.extend(sample.to_le_bytes());
+        }
+
+        let path = temp_path(name, "wav");
+        std::fs::write(&path, out).unwrap();
+        let decoded = samples.iter().map(|&sample| sample as f32 / 32768.0).collect();
+        (path, decoded)
+    }
+
+    /// `samples` as the stereo output plays them.
+    fn as_stereo(samples: &[f32], channels: u16) -> Vec<f32> {
+        match channels {
+            1 => samples.iter().flat_map(|&sample| [sample, sample]).collect(),
+            _ => samples.to_vec(),
+        }
+    }
+
+    fn buffer(samples: Vec<f32>) -> SamplesBuffer<f32> {
+        SamplesBuffer::new(HEADLESS_CHANNELS, HEADLESS_SAMPLE_RATE, samples)
+    }
+
+    /// Left channel at `left` Hz and right at `right` Hz, one second long.
+    fn sines(left: f32, right: f32) -> Vec<f32> {
+        let rate = HEADLESS_SAMPLE_RATE as f32;
+        (0..HEADLESS_SAMPLE_RATE)
+            .flat_map(|frame| {
+                let t = frame as f32 / rate;
+                [
+                    0.25 * (2.0 * PI * left * t).cos(),
+                    0.25 * (2.0 * PI * right * t).cos(),
+                ]
+            })
+            .collect()
+    }
+
+    /// Peak of one channel over the second half, once the filters settled.
+    fn peak(samples: &[f32], channel: usize) -> f32 {
+        samples[samples.len() / 2..]
+            .iter()
+            .skip(channel)
+            .step_by(2)
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn renders_a_gapless_pair_faster_than_real_time() {
+        let first: Vec<f32> = (0..44_100)
+            .map(|i| 0.1 + (i % 100) as f32 / 1000.0)
+            .collect();
+        let second: Vec<f32> = (0..26_460)
+            .map(|i| -0.1 - (i % 77) as f32 / 1000.0)
+            .collect();
+        let expected: Vec<f32> = first.iter().chain(&second).copied().collect();
+
+        let started = Instant::now();
+        let rendered = render(
+            "gapless",
+            vec![Box::new(buffer(first)), Box::new(buffer(second))],
+        );
+
+        assert_eq!(rendered, expected);
+        // 0.8 seconds of audio.
+        assert!(started.elapsed() < Duration::from_millis(800));
+    }
+
+    #[test]
+    fn converts_each_track_with_its_own_channel_count() {
+        let mono: Vec<f32> = (0..3000).map(|i| 0.1 + (i % 50) as f32 / 1000.0).collect();
+        let stereo: Vec<f32> = (0..3000).map(|i| -0.1 - (i % 30) as f32 / 1000.0).collect();
+        let expected: Vec<f32> = mono
+            .iter()
+            .flat_map(|&sample| [sample, sample])
+            .chain(stereo.iter().copied())
+            .chain(mono.iter().flat_map(|&sample| [sample, sample]))
+            .collect();
+
+        let rendered = render(
+            "channels",
+            vec![
+                Box::new(SamplesBuffer::new(1, HEADLESS_SAMPLE_RATE, mono.clone())),
+                Box::new(buffer(stereo)),
+                Box::new(SamplesBuffer::new(1, HEADLESS_SAMPLE_RATE, mono)),
+            ],
+        );
+
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn renders_an_equalized_track() {
+        let control = Arc::new(EqControl::default());
+        control.set_preset("bassBoost").unwrap();
+        let offline: Vec<f32> =
+            Equalizer::new(buffer(sines(100.0, 5000.0)), control.clone()).collect();
+
+        let rendered = render(
+            "eq",
+            vec![Box::new(Equalizer::new(
+                buffer(sines(100.0, 5000.0)),
+                control,
+            ))],
+        );
+
+        assert_eq!(rendered, offline);
+        // The +6 dB low shelf at 200 Hz lifts 100 Hz and leaves 5 kHz alone.
+        let bass = 20.0 * (peak(&rendered, 0) / 0.25).log10();
+        let treble = 20.0 * (peak(&rendered, 1) / 0.25).log10();
+        assert!((4.0..6.0).contains(&bass), "bass gain {} dB", bass);
+        assert!(treble.abs() < 0.5, "treble gain {} dB", treble);
+    }
+
+    #[test]
+    fn renders_decoded_tracks_through_the_dsp_chain() {
+        let (mono, mono_samples) = pcm_file("chain-mono", 1, 3000, 7);
+        let (stereo, stereo_samples) = pcm_file("chain-stereo", 2, 4000, 5);
+        let dsp = DspControls::default();
+        let (events, received) = mpsc::channel();
+        let replay_gain = ReplayGainTags {
+            track_gain: Some(-6.0),
+            ..Default::default()
+        };
+
+        let mut ids = Vec::new();
+        let mut sources: Vec<Box<dyn Source<Item = f32> + Send>> = Vec::new();
+        for path in [&mono, &stereo] {
+            let decoder = SymphoniaDecoder::open(path).unwrap();
+            let format = decoder.source_format();
+            let track = TrackSource::new(decoder, events.clone(), None, format, None);
+            ids.push(track.control().id());
+            sources.push(dsp.build_chain(track, replay_gain, false));
+        }
+        let rendered = render("chain", sources);
+        std::fs::remove_file(&mono).unwrap();
+        std::fs::remove_file(&stereo).unwrap();
+
+        let gain = 10f32.powf(-6.0 / 20.0);
+        let expected: Vec<f32> = as_stereo(&mono_samples, 1)
+            .into_iter()
+            .chain(stereo_samples)
+            .map(|sample| sample * gain)
+            .collect();
+        assert_eq!(rendered, expected);
+
+        let order: Vec<(&str, u64)> = received
+            .try_iter()
+            .map(|event| match event {
+                TrackEvent::Started { id } => ("started", id),
+                TrackEvent::Finished { id } => ("finished", id),
+                TrackEvent::Tail { id } => ("tail", id),
+            })
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("started", ids[0]),
+                ("finished", ids[0]),
+                ("started", ids[1]),
+                ("finished", ids[1])
+            ]
+        );
+    }
+
+    #[test]
+    fn audio_state_plays_its_queue_gaplessly() {
+        let (mono, mono_samples) = pcm_file("queue-mono", 1, 11_025, 3);
+        let (stereo, stereo_samples) = pcm_file("queue-stereo", 2, 11_025, 11);
+        let path = temp_path("queue", "wav");
+        let output = HeadlessOutput::wav(&path, Arc::default(), Pace::RealTime).unwrap();
+        let (events, received) = mpsc::channel();
+        let mut audio = AudioState::new(Some(Box::new(output)), Arc::default(), events);
+
+        audio.queue.replace(mono.clone(), vec![stereo.clone()]);
+        let first = audio.queue.current().cloned().unwrap();
+        audio.play_entry(first).unwrap();
+        // What `tick_progress` and `watch_tracks` do on their threads.
+        let finished = loop {
+            audio.maintain_preload();
+            match received.recv_timeout(Duration::from_millis(10)) {
+                Ok(TrackEvent::Started { id }) => {
+                    audio.on_track_started(id);
+                }
+                Ok(TrackEvent::Tail { id }) => {
+                    audio.on_track_tail(id);
+                }
+                Ok(TrackEvent::Finished { id }) => {
+                    if let Some(file_path) = audio.on_track_finished(id) {
+                        break file_path;
+                    }
+                }
+                Err(_) => {}
+            }
+        };
+        assert_eq!(finished, stereo);
+        assert_eq!(audio.queue.current().unwrap().file_path, stereo);
+        thread::sleep(Duration::from_millis(100));
+        drop(audio);
+        std::fs::remove_file(&mono).unwrap();
+        std::fs::remove_file(&stereo).unwrap();
+
+        let expected: Vec<f32> = as_stereo(&mono_samples, 1)
+            .into_iter()
+            .chain(stereo_samples)
+            .collect();
+        assert_eq!(read_rendered(&path), expected);
+    }
+}
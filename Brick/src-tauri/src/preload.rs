@@ -0,0 +1,544 @@
+use rodio::Source;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    decoder::{SourceFormat, SymphoniaDecoder},
+    stream::StreamStatus,
+    track::Seekable,
+};
+
+// Samples moved between the shared buffer and either side per lock.
+const CHUNK_SAMPLES: usize = 4096;
+// How often the worker of a stream checks for room in the buffer or a seek.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+pub const MIN_BUDGET_MB: u32 = 1;
+pub const MAX_BUDGET_MB: u32 = 512;
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreloadSettings {
+    /// Time left in the current track when the next one starts decoding.
+    pub lead_seconds: f32,
+    /// Most decoded audio held ahead for the next track, in megabytes.
+    pub budget_mb: u32,
+}
+
+impl Default for PreloadSettings {
+    fn default() -> Self {
+        Self {
+            lead_seconds: 30.0,
+            budget_mb: 32,
+        }
+    }
+}
+
+impl PreloadSettings {
+    pub fn lead(&self) -> Duration {
+        Duration::from_secs_f32(self.lead_seconds.max(0.0))
+    }
+
+    fn budget_samples(&self) -> usize {
+        self.budget_mb.clamp(MIN_BUDGET_MB, MAX_BUDGET_MB) as usize * (1 << 20)
+            / std::mem::size_of::<f32>()
+    }
+}
+
+/// How far a preload got.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PreloadStatus {
+    Opening,
+    /// The decoder is open and filling the buffer; the track can be queued.
+    Decoding,
+    /// The buffer is full or holds the whole file.
+    Buffered,
+    Failed,
+}
+
+impl PreloadStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => PreloadStatus::Opening,
+            1 => PreloadStatus::Decoding,
+            2 => PreloadStatus::Buffered,
+            _ => PreloadStatus::Failed,
+        }
+    }
+}
+
+/// Payload of `native-audio://preload`.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreloadPayload {
+    /// `preloading` or `preloaded`.
+    pub status: String,
+    pub file_path: String,
+    pub buffered_seconds: f32,
+}
+
+// Format and length of the decoded file, and its download if streamed.
+type Format = (SourceFormat, Option<Duration>, Option<Arc<StreamStatus>>);
+
+struct Shared {
+    // Kept here for files. The decoder of a stream stays with the worker so
+    // the output never waits on the network.
+    decoder: Option<SymphoniaDecoder>,
+    buffer: VecDeque<f32>,
+    // The decoder ran out of samples.
+    exhausted: bool,
+    // Position a stream is to seek to, left for the worker.
+    seek: Option<Duration>,
+    // Bumped on every seek of a stream so the worker drops what it decoded
+    // before it.
+    generation: u64,
+    // Why the decoder couldn't be opened.
+    error: Option<String>,
+}
+
+/// The next track, decoded ahead by a worker thread into a buffer bounded by
+/// the memory budget. Streams are decoded by the worker for as long as they
+/// play, files only until the buffer is full.
+pub struct Preload {
+    pub entry_id: u64,
+    pub file_path: String,
+    shared: Arc<Mutex<Shared>>,
+    status: Arc<AtomicU8>,
+    cancelled: Arc<AtomicBool>,
+    claimed: AtomicBool,
+    // Format of the decoder, known once it is open.
+    format: Arc<Mutex<Option<Format>>>,
+    /// Last status reported to the frontend.
+    pub announced: Option<PreloadStatus>,
+}
+
+impl Preload {
+    /// Opens `file_path` and starts decoding it on a thread of its own.
+    pub fn start(entry_id: u64, file_path: String, settings: &PreloadSettings) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            decoder: None,
+            buffer: VecDeque::new(),
+            exhausted: false,
+            seek: None,
+            generation: 0,
+            error: None,
+        }));
+        let status = Arc::new(AtomicU8::new(PreloadStatus::Opening as u8));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let format = Arc::new(Mutex::new(None));
+        let budget = settings.budget_samples();
+
+        let worker = Worker {
+            file_path: file_path.clone(),
+            shared: shared.clone(),
+            status: status.clone(),
+            cancelled: cancelled.clone(),
+            format: format.clone(),
+            budget,
+        };
+        thread::spawn(move || worker.run());
+
+        Self {
+            entry_id,
+            file_path,
+            shared,
+            status,
+            cancelled,
+            claimed: AtomicBool::new(false),
+            format,
+            announced: None,
+        }
+    }
+
+    pub fn status(&self) -> PreloadStatus {
+        PreloadStatus::from_u8(self.status.load(Ordering::SeqCst))
+    }
+
+    /// Seconds of audio decoded ahead.
+    pub fn buffered_seconds(&self) -> f32 {
+        let Some((format, _, _)) = self.format.lock().ok().and_then(|format| format.clone()) else {
+            return 0.0;
+        };
+        let samples = self.shared.lock().map_or(0, |shared| shared.buffer.len());
+        samples as f32 / (format.channels.max(1) as f32 * format.sample_rate.max(1) as f32)
+    }
+
+    /// Why the decoder couldn't be opened, once the preload failed.
+    pub fn error(&self) -> Option<String> {
+        self.shared.lock().ok().and_then(|shared| shared.error.clone())
+    }
+
+    pub fn is_claimed(&self) -> bool {
+        self.claimed.load(Ordering::SeqCst)
+    }
+
+    /// Hands the preloaded track to a sink. Only the first call gets it, and
+    /// only once the decoder is open.
+    pub fn claim(&self) -> Option<PreloadedDecoder> {
+        let (format, total_duration, stream) = self.format.lock().ok()?.clone()?;
+        if self.status() == PreloadStatus::Failed || self.claimed.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(PreloadedDecoder {
+            shared: self.shared.clone(),
+            chunk: VecDeque::with_capacity(CHUNK_SAMPLES),
+            format,
+            total_duration,
+            stream,
+            cancelled: self.cancelled.clone(),
+        })
+    }
+}
+
+impl Drop for Preload {
+    /// Stops the worker unless a claimed decoder still reads from it.
+    fn drop(&mut self) {
+        if !self.is_claimed() {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+struct Worker {
+    file_path: String,
+    shared: Arc<Mutex<Shared>>,
+    status: Arc<AtomicU8>,
+    cancelled: Arc<AtomicBool>,
+    format: Arc<Mutex<Option<Format>>>,
+    budget: usize,
+}
+
+impl Worker {
+    fn run(self) {
+        let decoder = match SymphoniaDecoder::open(&self.file_path) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                if let Ok(mut shared) = self.shared.lock() {
+                    shared.error = Some(e);
+                }
+                self.status.store(PreloadStatus::Failed as u8, Ordering::SeqCst);
+                return;
+            }
+        };
+        let stream = decoder.stream_status();
+        let format = (decoder.source_format(), decoder.total_duration(), stream.clone());
+        if let Some(stream) = stream {
+            if let Ok(mut known) = self.format.lock() {
+                *known = Some(format);
+            }
+            self.status.store(PreloadStatus::Decoding as u8, Ordering::SeqCst);
+            self.decode_stream(decoder, &stream);
+            return;
+        }
+
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.decoder = Some(decoder);
+        }
+        if let Ok(mut known) = self.format.lock() {
+            *known = Some(format);
+        }
+        self.status.store(PreloadStatus::Decoding as u8, Ordering::SeqCst);
+
+        let mut chunk = Vec::with_capacity(CHUNK_SAMPLES);
+        while !self.cancelled.load(Ordering::Relaxed) {
+            // The decoder sits behind the buffer's lock so the worker and the
+            // player never decode out of order; it is held for one chunk.
+            let Ok(mut shared) = self.shared.lock() else {
+                return;
+            };
+            if shared.exhausted || shared.buffer.len() >= self.budget {
+                break;
+            }
+            let Some(decoder) = shared.decoder.as_mut() else {
+                return;
+            };
+            chunk.extend(decoder.by_ref().take(CHUNK_SAMPLES));
+            if chunk.len() < CHUNK_SAMPLES {
+                shared.exhausted = true;
+            }
+            shared.buffer.extend(chunk.drain(..));
+        }
+
+        if !self.cancelled.load(Ordering::Relaxed) {
+            self.status.store(PreloadStatus::Buffered as u8, Ordering::SeqCst);
+        }
+    }
+
+    /// Keeps the buffer of a stream topped up, and seeks it, until the track
+    /// is dropped. Decoding happens outside the lock since reads wait for the
+    /// download.
+    fn decode_stream(&self, mut decoder: SymphoniaDecoder, stream: &StreamStatus) {
+        let mut chunk = Vec::with_capacity(CHUNK_SAMPLES);
+        let mut exhausted = false;
+        while !self.cancelled.load(Ordering::Relaxed) {
+            let (seek, generation) = {
+                let Ok(mut shared) = self.shared.lock() else {
+                    return;
+                };
+                let seek = shared.seek.take();
+                if seek.is_none() && (exhausted || shared.buffer.len() >= self.budget) {
+                    drop(shared);
+                    self.status.store(PreloadStatus::Buffered as u8, Ordering::SeqCst);
+                    thread::sleep(STREAM_POLL_INTERVAL);
+                    continue;
+                }
+                (seek, shared.generation)
+            };
+
+            if let Some(position) = seek {
+                if let Err(e) = decoder.seek(position) {
+                    stream.set_error(e);
+                    exhausted = true;
+                    if let Ok(mut shared) = self.shared.lock() {
+                        shared.exhausted = shared.generation == generation;
+                    }
+                    continue;
+                }
+            }
+            chunk.extend(decoder.by_ref().take(CHUNK_SAMPLES));
+            exhausted = chunk.len() < CHUNK_SAMPLES;
+
+            let Ok(mut shared) = self.shared.lock() else {
+                return;
+            };
+            if shared.generation != generation {
+                // Seeked while decoding; the chunk is from before the seek.
+                chunk.clear();
+                exhausted = false;
+                continue;
+            }
+            shared.buffer.extend(chunk.drain(..));
+            if exhausted {
+                shared.exhausted = true;
+                if let Some(e) = decoder.error() {
+                    stream.set_error(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Decoder of a preloaded track: plays the buffered samples first, then
+/// decodes the rest of the file as it is pulled. A stream only ever plays
+/// from the buffer, and plays silence while it is empty.
+pub struct PreloadedDecoder {
+    shared: Arc<Mutex<Shared>>,
+    // Samples taken out of the shared buffer in one go, so the audio thread
+    // locks once per chunk rather than once per sample.
+    chunk: VecDeque<f32>,
+    format: SourceFormat,
+    total_duration: Option<Duration>,
+    stream: Option<Arc<StreamStatus>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl PreloadedDecoder {
+    pub fn source_format(&self) -> SourceFormat {
+        self.format.clone()
+    }
+
+    pub fn stream_status(&self) -> Option<Arc<StreamStatus>> {
+        self.stream.clone()
+    }
+
+    fn refill(&mut self) {
+        let Ok(mut shared) = self.shared.lock() else {
+            return;
+        };
+        let channels = self.format.channels.max(1) as usize;
+        let mut available = shared.buffer.len().min(CHUNK_SAMPLES);
+        if self.stream.is_some() && !shared.exhausted {
+            // Whole frames only, so silence can go in between.
+            available -= available % channels;
+        }
+        if available > 0 {
+            self.chunk.extend(shared.buffer.drain(..available));
+            if let Some(stream) = &self.stream {
+                stream.set_buffering(false);
+            }
+            return;
+        }
+        if shared.exhausted {
+            return;
+        }
+        if let Some(stream) = &self.stream {
+            stream.set_buffering(true);
+            self.chunk.extend(std::iter::repeat_n(0.0, channels));
+            return;
+        }
+        let Some(decoder) = shared.decoder.as_mut() else {
+            return;
+        };
+        self.chunk.extend(decoder.by_ref().take(CHUNK_SAMPLES));
+        if self.chunk.len() < CHUNK_SAMPLES {
+            shared.exhausted = true;
+        }
+    }
+}
+
+impl Drop for PreloadedDecoder {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Iterator for PreloadedDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.chunk.is_empty() {
+            self.refill();
+        }
+        self.chunk.pop_front()
+    }
+}
+
+impl Source for PreloadedDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+impl Seekable for PreloadedDecoder {
+    fn seek(&mut self, position: Duration) -> Result<Duration, String> {
+        let mut shared = self
+            .shared
+            .lock()
+            .map_err(|e| format!("Mutex lock error: {}", e))?;
+        if self.stream.is_some() {
+            // The worker seeks; silence plays until it has decoded again.
+            shared.seek = Some(position);
+            shared.generation += 1;
+            shared.buffer.clear();
+            shared.exhausted = false;
+            self.chunk.clear();
+            return Ok(position);
+        }
+        let decoder = shared
+            .decoder
+            .as_mut()
+            .ok_or_else(|| "Seek error: decoder is not open".to_string())?;
+        let reached = decoder.seek(position)?;
+        shared.buffer.clear();
+        shared.exhausted = false;
+        self.chunk.clear();
+        Ok(reached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Writes a stereo 16-bit WAV file at 48 kHz and returns its path and
+    /// the samples it decodes to.
+    fn write_wav(name: &str, frames: usize) -> (String, Vec<f32>) {
+        let samples: Vec<i16> = (0..frames * 2).map(|index| (index % 30_000) as i16).collect();
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(48_000u32.to_le_bytes());
+        out.extend(192_000u32.to_le_bytes());
+        out.extend(4u16.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for sample in &samples {
+            out.extend(sample.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("brick-preload-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        let decoded = samples.iter().map(|&sample| sample as f32 / 32768.0).collect();
+        (path.to_string_lossy().into_owned(), decoded)
+    }
+
+    fn settings(budget_mb: u32) -> PreloadSettings {
+        PreloadSettings {
+            lead_seconds: 30.0,
+            budget_mb,
+        }
+    }
+
+    fn wait_for(preload: &Preload, status: PreloadStatus) {
+        let started = Instant::now();
+        while preload.status() != status {
+            assert!(started.elapsed() < Duration::from_secs(10), "preload never got there");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn budget_is_kept_within_its_limits() {
+        assert_eq!(settings(0).budget_samples(), 1 << 18);
+        assert_eq!(settings(4).budget_samples(), 1 << 20);
+        assert_eq!(settings(100_000).budget_samples(), MAX_BUDGET_MB as usize * (1 << 18));
+    }
+
+    #[test]
+    fn decoding_ahead_stops_at_the_budget() {
+        // 1.2 MB of samples against a budget of 1 MB.
+        let (file_path, expected) = write_wav("budget", 150_000);
+        let preload = Preload::start(1, file_path.clone(), &settings(1));
+
+        wait_for(&preload, PreloadStatus::Buffered);
+
+        let budget = settings(1).budget_samples();
+        let buffered = preload.shared.lock().unwrap().buffer.len();
+        assert!((budget..budget + CHUNK_SAMPLES).contains(&buffered), "{}", buffered);
+        assert_eq!(preload.buffered_seconds(), buffered as f32 / 96_000.0);
+        // The rest is decoded as it plays, right after the buffer.
+        let played: Vec<f32> = preload.claim().unwrap().collect();
+        assert!(played == expected);
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn short_tracks_are_buffered_whole_and_claimed_once() {
+        let (file_path, expected) = write_wav("short", 10_000);
+        let preload = Preload::start(1, file_path.clone(), &settings(32));
+
+        wait_for(&preload, PreloadStatus::Buffered);
+
+        assert!(preload.shared.lock().unwrap().exhausted);
+        let decoder = preload.claim().unwrap();
+        assert!(preload.claim().is_none());
+        assert!(decoder.collect::<Vec<f32>>() == expected);
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_files_fail_with_an_error() {
+        let preload = Preload::start(1, "/nonexistent/track.flac".to_string(), &settings(32));
+
+        wait_for(&preload, PreloadStatus::Failed);
+
+        assert!(preload.error().is_some());
+        assert!(preload.claim().is_none());
+    }
+}
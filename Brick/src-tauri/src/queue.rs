@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+
+/// What happens when playback reaches the end of the current entry.
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Plays the current entry again.
+    One,
+    /// Wraps around to the start of the queue after the last entry.
+    All,
+}
+
+/// A single item of the play queue. The `id` stays stable while the entry is
+/// moved around so the same file can appear more than once.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueEntry {
+    pub id: u64,
+    pub file_path: String,
+}
+
+/// Full queue as sent to the frontend so any window can re-render from it.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueSnapshot {
+    pub entries: Vec<QueueEntry>,
+    pub current_index: Option<usize>,
+    pub repeat: RepeatMode,
+    /// Seed of the active shuffle, `None` while the queue is in its original order.
+    pub shuffle_seed: Option<u64>,
+}
+
+/// Queue as kept across restarts. Entry ids are handed out afresh on load.
+#[derive(Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StoredQueue {
+    pub file_paths: Vec<String>,
+    pub current_index: Option<usize>,
+    pub repeat: RepeatMode,
+    pub shuffle_seed: Option<u64>,
+    /// Indices into `file_paths` in the order they had before shuffling.
+    pub original_order: Vec<usize>,
+}
+
+/// Ordered list of tracks with a cursor on the one currently playing.
+#[derive(Default)]
+pub struct PlayQueue {
+    entries: Vec<QueueEntry>,
+    current: Option<usize>,
+    next_id: u64,
+    repeat: RepeatMode,
+    shuffle: Option<Shuffle>,
+}
+
+struct Shuffle {
+    seed: u64,
+    // Entry ids in the order they had before shuffling, kept up to date as
+    // entries come and go so turning shuffle off can restore it.
+    original: Vec<u64>,
+}
+
+/// SplitMix64, a small generator that gives the same sequence for a seed on
+/// every platform, so a shuffle can be reproduced.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+impl PlayQueue {
+    fn make_entry(&mut self, file_path: String) -> QueueEntry {
+        self.next_id += 1;
+        QueueEntry {
+            id: self.next_id,
+            file_path,
+        }
+    }
+
+    /// Replaces the whole queue with `current` followed by `upcoming`.
+    pub fn replace(&mut self, current: String, upcoming: Vec<String>) {
+        let mut entries = Vec::with_capacity(upcoming.len() + 1);
+        entries.push(self.make_entry(current));
+        for file_path in upcoming {
+            entries.push(self.make_entry(file_path));
+        }
+        if let Some(shuffle) = &mut self.shuffle {
+            shuffle.original = entries.iter().map(|entry| entry.id).collect();
+        }
+        self.entries = entries;
+        self.current = Some(0);
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            entries: self.entries.clone(),
+            current_index: self.current,
+            repeat: self.repeat,
+            shuffle_seed: self.shuffle_seed(),
+        }
+    }
+
+    pub fn stored(&self) -> StoredQueue {
+        let original_order = self.shuffle.as_ref().map_or_else(Vec::new, |shuffle| {
+            shuffle
+                .original
+                .iter()
+                .filter_map(|&id| self.entries.iter().position(|entry| entry.id == id))
+                .collect()
+        });
+        StoredQueue {
+            file_paths: self.entries.iter().map(|entry| entry.file_path.clone()).collect(),
+            current_index: self.current,
+            repeat: self.repeat,
+            shuffle_seed: self.shuffle_seed(),
+            original_order,
+        }
+    }
+
+    /// Replaces the whole queue with one saved by `stored`.
+    pub fn restore(&mut self, stored: StoredQueue) {
+        let entries: Vec<QueueEntry> = stored
+            .file_paths
+            .into_iter()
+            .map(|file_path| self.make_entry(file_path))
+            .collect();
+        self.shuffle = stored.shuffle_seed.map(|seed| Shuffle {
+            seed,
+            original: stored
+                .original_order
+                .iter()
+                .filter_map(|&index| entries.get(index).map(|entry| entry.id))
+                .collect(),
+        });
+        self.current = stored.current_index.filter(|&index| index < entries.len());
+        self.repeat = stored.repeat;
+        self.entries = entries;
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn shuffle_seed(&self) -> Option<u64> {
+        self.shuffle.as_ref().map(|shuffle| shuffle.seed)
+    }
+
+    pub fn current(&self) -> Option<&QueueEntry> {
+        self.current.and_then(|index| self.entries.get(index))
+    }
+
+    /// The current entry followed by every entry after it, empty without a
+    /// current entry.
+    pub fn current_onwards(&self) -> &[QueueEntry] {
+        self.current.map_or(&[], |index| &self.entries[index..])
+    }
+
+    /// The entry that plays after the current one runs out, if any. Without
+    /// a current entry this is the head of the queue.
+    pub fn peek_next(&self) -> Option<&QueueEntry> {
+        match (self.current, self.repeat) {
+            (Some(index), RepeatMode::One) => self.entries.get(index),
+            _ => self.next_on_skip(),
+        }
+    }
+
+    /// The entry a manual skip goes to. Unlike `peek_next` this moves on
+    /// even when repeating a single entry.
+    pub fn next_on_skip(&self) -> Option<&QueueEntry> {
+        let Some(index) = self.current else {
+            return self.entries.first();
+        };
+        match self.entries.get(index + 1) {
+            None if self.repeat == RepeatMode::All => self.entries.first(),
+            next => next,
+        }
+    }
+
+    /// The entry that played before the current one, if any.
+    pub fn peek_previous(&self) -> Option<&QueueEntry> {
+        match self.current?.checked_sub(1) {
+            Some(index) => self.entries.get(index),
+            None if self.repeat == RepeatMode::All => self.entries.last(),
+            None => None,
+        }
+    }
+
+    /// Entries directly before and after the one with the given id.
+    pub fn neighbours(&self, id: u64) -> (Option<&QueueEntry>, Option<&QueueEntry>) {
+        let Some(index) = self.entries.iter().position(|entry| entry.id == id) else {
+            return (None, None);
+        };
+        let previous = index.checked_sub(1).and_then(|index| self.entries.get(index));
+        (previous, self.entries.get(index + 1))
+    }
+
+    /// Appends files to the end of the queue.
+    pub fn push(&mut self, file_paths: Vec<String>) {
+        for file_path in file_paths {
+            let entry = self.make_entry(file_path);
+            if let Some(shuffle) = &mut self.shuffle {
+                shuffle.original.push(entry.id);
+            }
+            self.entries.push(entry);
+        }
+    }
+
+    /// Inserts a file so it plays right after the current entry.
+    pub fn insert_next(&mut self, file_path: String) {
+        let index = self.current.map_or(0, |current| current + 1);
+        let entry = self.make_entry(file_path);
+        let current_id = self.current().map(|current| current.id);
+        if let Some(shuffle) = &mut self.shuffle {
+            // Also right after the current entry once the order is restored.
+            let position = current_id
+                .and_then(|id| shuffle.original.iter().position(|&other| other == id))
+                .map_or(0, |position| position + 1);
+            shuffle.original.insert(position, entry.id);
+        }
+        self.entries.insert(index, entry);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<QueueEntry, String> {
+        if index >= self.entries.len() {
+            return Err(format!("Queue index out of range: {}", index));
+        }
+        if Some(index) == self.current {
+            return Err("Cannot remove the track that is playing".to_string());
+        }
+
+        let entry = self.entries.remove(index);
+        if let Some(current) = self.current {
+            if index < current {
+                self.current = Some(current - 1);
+            }
+        }
+        self.forget_original(entry.id);
+        Ok(entry)
+    }
+
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Result<(), String> {
+        let len = self.entries.len();
+        if from >= len || to >= len {
+            return Err(format!("Queue index out of range: {} -> {}", from, to));
+        }
+
+        let current_id = self.current().map(|entry| entry.id);
+        let entry = self.entries.remove(from);
+        let moved_id = entry.id;
+        self.entries.insert(to, entry);
+        if let Some(id) = current_id {
+            self.current = self.entries.iter().position(|entry| entry.id == id);
+        }
+        if let Some(shuffle) = &mut self.shuffle {
+            // Kept after its new neighbour, or before it at the front, so the
+            // move survives restoring the order.
+            shuffle.original.retain(|&id| id != moved_id);
+            let position = match to.checked_sub(1) {
+                Some(previous) => {
+                    let previous_id = self.entries[previous].id;
+                    let position = shuffle.original.iter().position(|&id| id == previous_id);
+                    position.map(|position| position + 1)
+                }
+                None => self
+                    .entries
+                    .get(1)
+                    .and_then(|next| shuffle.original.iter().position(|&id| id == next.id)),
+            };
+            let position = position.unwrap_or(shuffle.original.len());
+            shuffle.original.insert(position, moved_id);
+        }
+        Ok(())
+    }
+
+    /// Drops every entry except the one that is playing.
+    pub fn clear(&mut self) {
+        match self.current.map(|index| self.entries.swap_remove(index)) {
+            Some(current) => {
+                self.entries = vec![current];
+                self.current = Some(0);
+            }
+            None => self.entries.clear(),
+        }
+        let kept: Vec<u64> = self.entries.iter().map(|entry| entry.id).collect();
+        if let Some(shuffle) = &mut self.shuffle {
+            shuffle.original = kept;
+        }
+    }
+
+    /// Moves the cursor onto the entry with the given id.
+    pub fn select(&mut self, id: u64) -> Option<&QueueEntry> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        self.current = Some(index);
+        self.entries.get(index)
+    }
+
+    /// Drops an entry that can't be played. The current entry stays, since
+    /// repeating it must not pull it out from under the cursor. Returns
+    /// whether the entry was dropped.
+    pub fn remove_id(&mut self, id: u64) -> bool {
+        let Some(index) = self.entries.iter().position(|entry| entry.id == id) else {
+            return false;
+        };
+        if Some(index) == self.current {
+            return false;
+        }
+        self.entries.remove(index);
+        if let Some(current) = self.current {
+            if index < current {
+                self.current = Some(current - 1);
+            }
+        }
+        self.forget_original(id);
+        true
+    }
+
+    fn forget_original(&mut self, id: u64) {
+        if let Some(shuffle) = &mut self.shuffle {
+            shuffle.original.retain(|&other| other != id);
+        }
+    }
+
+    /// Shuffles every entry except the current one, which moves to the front.
+    /// The rest is permuted from its original order, so the same seed, current
+    /// entry and original order always give the same order, however often the
+    /// queue was shuffled before. Neighbours for
+    /// which `conflicts` holds, e.g. the same artist, are pulled apart when
+    /// another entry can go in between.
+    pub fn shuffle(&mut self, seed: u64, conflicts: impl Fn(&QueueEntry, &QueueEntry) -> bool) {
+        let original = match self.shuffle.take() {
+            Some(shuffle) => shuffle.original,
+            None => self.entries.iter().map(|entry| entry.id).collect(),
+        };
+
+        let current = self.current.map(|index| self.entries.remove(index));
+        let mut rest = std::mem::take(&mut self.entries);
+        let ranks = original_ranks(&original);
+        rest.sort_by_key(|entry| rank_of(&ranks, entry));
+        let mut rng = SplitMix64(seed);
+        for index in (1..rest.len()).rev() {
+            rest.swap(index, rng.below(index + 1));
+        }
+
+        for index in 0..rest.len() {
+            let previous = match index {
+                0 => current.as_ref(),
+                _ => rest.get(index - 1),
+            };
+            let Some(previous) = previous.filter(|previous| conflicts(previous, &rest[index])) else {
+                continue;
+            };
+            if let Some(offset) = rest[index + 1..]
+                .iter()
+                .position(|candidate| !conflicts(previous, candidate))
+            {
+                rest.swap(index, index + 1 + offset);
+            }
+        }
+
+        self.current = current.as_ref().map(|_| 0);
+        self.entries = current.into_iter().chain(rest).collect();
+        self.shuffle = Some(Shuffle { seed, original });
+    }
+
+    /// Puts the entries back in the order they had before shuffling. Entries
+    /// the original order doesn't know stay at the end.
+    pub fn unshuffle(&mut self) {
+        let Some(shuffle) = self.shuffle.take() else {
+            return;
+        };
+        let current_id = self.current().map(|entry| entry.id);
+        let ranks = original_ranks(&shuffle.original);
+        self.entries.sort_by_key(|entry| rank_of(&ranks, entry));
+        if let Some(id) = current_id {
+            self.current = self.entries.iter().position(|entry| entry.id == id);
+        }
+    }
+}
+
+/// Position of every id in the order before shuffling.
+fn original_ranks(original: &[u64]) -> HashMap<u64, usize> {
+    original.iter().enumerate().map(|(rank, &id)| (id, rank)).collect()
+}
+
+/// Rank of `entry` in `ranks`; entries it doesn't know sort last.
+fn rank_of(ranks: &HashMap<u64, usize>, entry: &QueueEntry) -> usize {
+    ranks.get(&entry.id).copied().unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(count: usize) -> PlayQueue {
+        let mut queue = PlayQueue::default();
+        let paths = (1..count).map(|index| format!("{}.flac", index)).collect();
+        queue.replace("0.flac".to_string(), paths);
+        queue
+    }
+
+    fn paths(queue: &PlayQueue) -> Vec<String> {
+        queue.snapshot().entries.into_iter().map(|entry| entry.file_path).collect()
+    }
+
+    fn number(path: &str) -> usize {
+        path.trim_end_matches(".flac").parse().unwrap()
+    }
+
+    #[test]
+    fn remove_id_keeps_the_current_entry() {
+        let mut queue = queue(3);
+        let current = queue.current().unwrap().id;
+        let last = queue.snapshot().entries[2].id;
+
+        assert!(!queue.remove_id(current));
+        assert!(queue.remove_id(last));
+        assert_eq!(paths(&queue), ["0.flac", "1.flac"]);
+        assert_eq!(queue.current().unwrap().id, current);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_order_after_reshuffling() {
+        let mut once = queue(20);
+        once.shuffle(7, |_, _| false);
+
+        let mut twice = queue(20);
+        twice.shuffle(3, |_, _| false);
+        let current = twice.current().unwrap().id;
+        twice.shuffle(7, |_, _| false);
+
+        assert_eq!(twice.current().unwrap().id, current);
+        assert_eq!(paths(&once), paths(&twice));
+    }
+
+    #[test]
+    fn shuffle_moves_the_current_entry_to_the_front() {
+        let mut queue = queue(10);
+        let id = queue.snapshot().entries[4].id;
+        queue.select(id);
+
+        queue.shuffle(11, |_, _| false);
+
+        let mut shuffled = paths(&queue);
+        assert_eq!(queue.current().unwrap().id, id);
+        assert_eq!(shuffled[0], "4.flac");
+        assert_ne!(shuffled, paths(&self::queue(10)));
+        shuffled.sort_by_key(|path| number(path));
+        assert_eq!(shuffled, paths(&self::queue(10)));
+    }
+
+    #[test]
+    fn shuffle_pulls_conflicting_neighbours_apart() {
+        let mut queue = queue(12);
+        // Two "artists", told apart by the parity of the file name.
+        let artist = |entry: &QueueEntry| number(&entry.file_path) % 2;
+
+        queue.shuffle(5, |a, b| artist(a) == artist(b));
+
+        let entries = queue.snapshot().entries;
+        for pair in entries.windows(2) {
+            assert_ne!(artist(&pair[0]), artist(&pair[1]), "{:?}", paths(&queue));
+        }
+    }
+
+    #[test]
+    fn unshuffle_restores_the_order_and_keeps_edits() {
+        let mut queue = queue(8);
+        let id = queue.snapshot().entries[3].id;
+        queue.select(id);
+        queue.shuffle(42, |_, _| false);
+        let removed = queue.snapshot().entries[1].clone();
+        assert!(queue.remove_id(removed.id));
+        queue.push(vec!["new.flac".to_string()]);
+
+        queue.unshuffle();
+
+        let mut expected = paths(&self::queue(8));
+        expected.retain(|path| *path != removed.file_path);
+        expected.push("new.flac".to_string());
+        assert_eq!(paths(&queue), expected);
+        assert_eq!(queue.current().unwrap().id, id);
+        assert_eq!(queue.shuffle_seed(), None);
+    }
+
+    #[test]
+    fn unshuffle_keeps_moved_entries_after_their_new_neighbour() {
+        let mut queue = queue(6);
+        queue.shuffle(9, |_, _| false);
+        let moved = paths(&queue)[5].clone();
+
+        // Right after the current entry, which shuffling put at the front.
+        queue.move_entry(5, 1).unwrap();
+        queue.unshuffle();
+
+        let mut expected = paths(&self::queue(6));
+        expected.retain(|path| *path != moved);
+        expected.insert(1, moved);
+        assert_eq!(paths(&queue), expected);
+    }
+}
@@ -0,0 +1,45 @@
+use std::{fs::File, path::PathBuf};
+
+use crate::{queue::StoredQueue, storage};
+
+/// Playback kept across restarts in Brick's data directory: the queue, where
+/// the current entry was and the volume.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StoredSession {
+    pub queue: StoredQueue,
+    /// Position in the current entry, in seconds.
+    pub position: f32,
+    pub volume: f32,
+}
+
+impl Default for StoredSession {
+    fn default() -> Self {
+        Self {
+            queue: StoredQueue::default(),
+            position: 0.0,
+            volume: 1.0,
+        }
+    }
+}
+
+impl StoredSession {
+    fn path() -> Option<PathBuf> {
+        let mut path = storage::app_data_dir()?;
+        path.push("session.json");
+        Some(path)
+    }
+
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Self::path().ok_or_else(|| "No data directory".to_string())?;
+        let contents = serde_json::to_vec(self).map_err(|e| format!("Session error: {}", e))?;
+        storage::write_atomic(&path, &contents).map_err(|e| format!("Session error: {}", e))
+    }
+}
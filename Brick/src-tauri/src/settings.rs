@@ -0,0 +1,33 @@
+use std::{fs::File, path::PathBuf};
+
+use crate::{dsp::channels::ChannelSettings, storage};
+
+/// Settings kept across sessions in Brick's data directory. Missing fields
+/// fall back to their defaults, so older files keep loading.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StoredSettings {
+    pub channels: ChannelSettings,
+}
+
+impl StoredSettings {
+    fn path() -> Option<PathBuf> {
+        let mut path = storage::app_data_dir()?;
+        path.push("settings.json");
+        Some(path)
+    }
+
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Self::path().ok_or_else(|| "No data directory".to_string())?;
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| format!("Settings error: {}", e))?;
+        storage::write_atomic(&path, &contents).map_err(|e| format!("Settings error: {}", e))
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+use crate::crossfade::FadeCurve;
+
+// Longest fade-out accepted before the timer stops playback.
+const MAX_FADE: Duration = Duration::from_secs(600);
+
+/// When a sleep timer stops playback.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SleepMode {
+    /// After a number of minutes, counted in wall-clock time.
+    Minutes,
+    EndOfTrack,
+    /// When the current album stops being played in order.
+    EndOfAlbum,
+    EndOfQueue,
+}
+
+pub struct SleepTimer {
+    pub mode: SleepMode,
+    // Only set for `SleepMode::Minutes`.
+    deadline: Option<Instant>,
+    pub fade: Duration,
+    /// Volume factor of the fade-out, applied on top of the user's volume.
+    pub gain: f32,
+    /// What plays after the current track before an end-of-album or
+    /// end-of-queue timer stops, worked out without holding the lock.
+    pub following: Option<Following>,
+}
+
+/// Entries that play after the current one before the timer stops, for the
+/// queue as it was when they were worked out.
+pub struct Following {
+    /// Ids of the then current entry and every entry after it.
+    pub ids: Vec<u64>,
+    /// Playing time of each entry after the current one up to the last that
+    /// plays before the timer stops.
+    pub durations: Vec<Duration>,
+}
+
+impl Following {
+    /// Playing time after the current entry, given the ids of the current
+    /// entry and those after it. `None` once the queue changed other than by
+    /// moving on to a later entry.
+    pub fn remaining(&self, ids: &[u64]) -> Option<Duration> {
+        let skipped = self.ids.len().checked_sub(ids.len())?;
+        if self.ids[skipped..] != *ids {
+            return None;
+        }
+        Some(self.durations.iter().skip(skipped).sum())
+    }
+}
+
+impl SleepTimer {
+    pub fn new(mode: SleepMode, minutes: Option<f32>, fade: Duration) -> Result<Self, String> {
+        let deadline = match (mode, minutes) {
+            (SleepMode::Minutes, Some(minutes)) if minutes.is_finite() && minutes > 0.0 => {
+                let deadline = Duration::try_from_secs_f32(minutes * 60.0)
+                    .ok()
+                    .and_then(|length| Instant::now().checked_add(length))
+                    .ok_or_else(|| format!("Sleep timer is too long: {} minutes", minutes))?;
+                Some(deadline)
+            }
+            (SleepMode::Minutes, _) => {
+                return Err("Sleep timer needs a positive number of minutes".to_string())
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            mode,
+            deadline,
+            fade: fade.min(MAX_FADE),
+            gain: 1.0,
+            following: None,
+        })
+    }
+
+    /// Time left before the deadline of a `Minutes` timer.
+    pub fn deadline_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Updates `gain` for `remaining` time before playback stops. The fade is
+    /// linear in decibels, which sounds even down to silence.
+    pub fn update_gain(&mut self, remaining: Duration) {
+        self.gain = if remaining >= self.fade {
+            1.0
+        } else {
+            let progress = 1.0 - remaining.as_secs_f32() / self.fade.as_secs_f32();
+            FadeCurve::Logarithmic.fade_out_gain(progress)
+        };
+    }
+}
+
+/// Payload of `native-audio://sleep-timer`.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepTimerPayload {
+    /// `None` when no timer is set.
+    pub mode: Option<SleepMode>,
+    /// Estimated time before playback stops, `None` while it can't be told.
+    pub remaining_seconds: Option<f32>,
+    pub fade_seconds: f32,
+    /// Set once, when the timer stopped playback.
+    pub fired: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn following() -> Following {
+        Following {
+            ids: vec![1, 2, 3, 4],
+            durations: vec![Duration::from_secs(20), Duration::from_secs(30)],
+        }
+    }
+
+    #[test]
+    fn remaining_drops_the_entries_played_since() {
+        let following = following();
+        assert_eq!(following.remaining(&[1, 2, 3, 4]), Some(Duration::from_secs(50)));
+        assert_eq!(following.remaining(&[2, 3, 4]), Some(Duration::from_secs(30)));
+        assert_eq!(following.remaining(&[4]), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn remaining_is_unknown_once_the_queue_changed() {
+        let following = following();
+        assert_eq!(following.remaining(&[1, 3, 4]), None);
+        assert_eq!(following.remaining(&[2, 3, 4, 5]), None);
+        assert_eq!(following.remaining(&[0, 1, 2, 3, 4]), None);
+    }
+}
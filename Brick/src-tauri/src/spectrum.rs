@@ -0,0 +1,260 @@
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc, time::Duration};
+
+pub const MIN_FFT_SIZE: usize = 256;
+pub const MAX_FFT_SIZE: usize = 16384;
+const MAX_BANDS: usize = 256;
+// Range the bands are spread over, logarithmically.
+const LOWEST_FREQUENCY: f32 = 20.0;
+const HIGHEST_FREQUENCY: f32 = 20_000.0;
+// Level reported for bands without any energy.
+const FLOOR_DB: f32 = -120.0;
+
+/// Window applied to each block before the FFT.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SpectrumWindow {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+impl SpectrumWindow {
+    fn coefficients(self, size: usize) -> Vec<f32> {
+        let n = (size.max(2) - 1) as f32;
+        (0..size)
+            .map(|i| {
+                let x = 2.0 * PI * i as f32 / n;
+                match self {
+                    SpectrumWindow::Rectangular => 1.0,
+                    SpectrumWindow::Hann => 0.5 - 0.5 * x.cos(),
+                    SpectrumWindow::Hamming => 0.54 - 0.46 * x.cos(),
+                    SpectrumWindow::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectrumSettings {
+    /// Samples per FFT, a power of two.
+    pub fft_size: usize,
+    pub window: SpectrumWindow,
+    pub bands: usize,
+    /// Spectrum events per second.
+    pub frame_rate: u32,
+}
+
+impl Default for SpectrumSettings {
+    fn default() -> Self {
+        Self {
+            fft_size: 2048,
+            window: SpectrumWindow::Hann,
+            bands: 64,
+            frame_rate: 30,
+        }
+    }
+}
+
+impl SpectrumSettings {
+    /// Brings every field into its supported range.
+    pub fn normalized(mut self) -> Self {
+        self.fft_size = self
+            .fft_size
+            .clamp(MIN_FFT_SIZE, MAX_FFT_SIZE)
+            .next_power_of_two()
+            .min(MAX_FFT_SIZE);
+        self.bands = self.bands.clamp(1, MAX_BANDS);
+        self.frame_rate = self.frame_rate.clamp(1, 120);
+        self
+    }
+
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.frame_rate.max(1) as f32)
+    }
+}
+
+/// Payload of `native-audio://spectrum`.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectrumPayload {
+    /// Level of each band in dBFS, lowest band first.
+    pub magnitudes: Vec<f32>,
+    /// Upper edge of each band in Hz.
+    pub frequencies: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// Turns the latest samples of the output into band levels.
+pub struct SpectrumAnalyzer {
+    settings: SpectrumSettings,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    // Amplitude of a full-scale sine after windowing, for dBFS levels.
+    reference: f32,
+    // Most recent mono samples, at most `fft_size` of them.
+    history: VecDeque<f32>,
+    buffer: Vec<Complex<f32>>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(settings: SpectrumSettings) -> Self {
+        let size = settings.fft_size;
+        let window = settings.window.coefficients(size);
+        let reference = window.iter().sum::<f32>() / 2.0;
+
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window,
+            reference,
+            history: VecDeque::with_capacity(size),
+            buffer: Vec::with_capacity(size),
+            settings,
+        }
+    }
+
+    pub fn settings(&self) -> &SpectrumSettings {
+        &self.settings
+    }
+
+    /// Adds interleaved samples, mixed down to mono.
+    pub fn push(&mut self, samples: &[f32], channels: u16) {
+        let channels = channels.max(1) as usize;
+        for frame in samples.chunks_exact(channels) {
+            if self.history.len() == self.settings.fft_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(frame.iter().sum::<f32>() / channels as f32);
+        }
+    }
+
+    /// Spectrum of the last `fft_size` samples, `None` until there are enough.
+    pub fn analyze(&mut self, sample_rate: u32) -> Option<SpectrumPayload> {
+        let size = self.settings.fft_size;
+        if self.history.len() < size || sample_rate == 0 {
+            return None;
+        }
+
+        self.buffer.clear();
+        self.buffer.extend(
+            self.history
+                .iter()
+                .zip(&self.window)
+                .map(|(sample, weight)| Complex::new(sample * weight, 0.0)),
+        );
+        self.fft.process(&mut self.buffer);
+
+        let bin_width = sample_rate as f32 / size as f32;
+        let nyquist = sample_rate as f32 / 2.0;
+        let highest = HIGHEST_FREQUENCY.min(nyquist);
+        let ratio = (highest / LOWEST_FREQUENCY).max(1.0);
+        let bands = self.settings.bands;
+
+        let mut magnitudes = Vec::with_capacity(bands);
+        let mut frequencies = Vec::with_capacity(bands);
+        let mut low = LOWEST_FREQUENCY;
+        for band in 1..=bands {
+            let high = LOWEST_FREQUENCY * ratio.powf(band as f32 / bands as f32);
+            let first = ((low / bin_width).floor() as usize).clamp(1, size / 2);
+            // Narrow low bands still get the bin they fall into.
+            let last = ((high / bin_width).ceil() as usize).clamp(first + 1, size / 2 + 1);
+            let peak = self.buffer[first..last]
+                .iter()
+                .map(|bin| bin.norm())
+                .fold(0.0, f32::max);
+
+            let level = peak / self.reference;
+            magnitudes.push(if level > 0.0 {
+                (20.0 * level.log10()).max(FLOOR_DB)
+            } else {
+                FLOOR_DB
+            });
+            frequencies.push(high);
+            low = high;
+        }
+
+        Some(SpectrumPayload {
+            magnitudes,
+            frequencies,
+            sample_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// Frames of a full-scale sine at `frequency`, the same on both channels
+    /// or inverted on the right.
+    fn stereo_sine(frequency: f32, frames: usize, inverted: bool) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| (2.0 * PI * frequency * frame as f32 / RATE as f32).sin())
+            .flat_map(|sample| [sample, if inverted { -sample } else { sample }])
+            .collect()
+    }
+
+    #[test]
+    fn settings_are_brought_into_range() {
+        let settings = SpectrumSettings {
+            fft_size: 1000,
+            window: SpectrumWindow::Hann,
+            bands: 0,
+            frame_rate: 500,
+        }
+        .normalized();
+        assert_eq!((settings.fft_size, settings.bands, settings.frame_rate), (1024, 1, 120));
+
+        let huge = SpectrumSettings {
+            fft_size: 100_000,
+            bands: 1000,
+            ..settings
+        }
+        .normalized();
+        assert_eq!((huge.fft_size, huge.bands), (MAX_FFT_SIZE, MAX_BANDS));
+    }
+
+    #[test]
+    fn waits_for_a_full_block() {
+        let mut analyzer = SpectrumAnalyzer::new(SpectrumSettings::default());
+
+        analyzer.push(&stereo_sine(1000.0, 2047, false), 2);
+        assert!(analyzer.analyze(RATE).is_none());
+        analyzer.push(&stereo_sine(1000.0, 1, false), 2);
+        assert!(analyzer.analyze(RATE).is_some());
+    }
+
+    #[test]
+    fn full_scale_sine_peaks_at_zero_dbfs_in_its_band() {
+        let mut analyzer = SpectrumAnalyzer::new(SpectrumSettings::default());
+        // Centred on bin 43 of a 2048-point FFT.
+        let frequency = 43.0 * RATE as f32 / 2048.0;
+        analyzer.push(&stereo_sine(frequency, 4096, false), 2);
+
+        let spectrum = analyzer.analyze(RATE).unwrap();
+
+        assert_eq!(spectrum.magnitudes.len(), 64);
+        let loudest = (0..64)
+            .max_by(|&a, &b| spectrum.magnitudes[a].total_cmp(&spectrum.magnitudes[b]))
+            .unwrap();
+        assert!(spectrum.frequencies[loudest] >= frequency);
+        assert!(loudest == 0 || spectrum.frequencies[loudest - 1] <= frequency);
+        assert!(spectrum.magnitudes[loudest].abs() < 0.5, "{} dB", spectrum.magnitudes[loudest]);
+        assert!(spectrum.frequencies.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn channels_are_mixed_down_before_analysis() {
+        let mut analyzer = SpectrumAnalyzer::new(SpectrumSettings::default());
+        analyzer.push(&stereo_sine(1000.0, 2048, true), 2);
+
+        let spectrum = analyzer.analyze(RATE).unwrap();
+
+        assert!(spectrum.magnitudes.iter().all(|&level| level == FLOOR_DB));
+    }
+}
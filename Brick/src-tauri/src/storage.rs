@@ -0,0 +1,59 @@
+//! Where Brick keeps its state between runs.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Same as `identifier` in `tauri.conf.json`, so this is the directory Tauri
+// reports as the app data dir; it's also needed before the app is built.
+const APP_IDENTIFIER: &str = "art.thebrickwall.brick";
+
+/// Brick's own directory inside the user's data directory, created on demand.
+pub fn app_data_dir() -> Option<PathBuf> {
+    let mut dir = dirs::data_dir()?;
+    dir.push(APP_IDENTIFIER);
+    std::fs::create_dir_all(&dir).ok()?;
+    Some(dir)
+}
+
+/// Replaces the file at `path` with `contents`. They go to a temporary file
+/// next to it first, which is then renamed over it, so a crash or a full disk
+/// leaves either the old file or the new one but never a cut-off one.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let written = File::create(&temporary).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| std::fs::rename(&temporary, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temporary);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("brick-storage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        write_atomic(&path, b"a much longer first version").unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+//! Tracks played straight from HTTP(S) URLs. A fetcher thread downloads ahead
+//! of the decoder with range requests; a seek outside what it holds restarts
+//! the download at the new offset instead of reading up to it.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+use symphonia::core::io::MediaSource;
+
+// Bytes downloaded ahead of the decoder before the fetcher waits.
+const READ_AHEAD: u64 = 2 << 20;
+// Bytes behind the decoder kept for the short backward seeks of demuxers.
+const KEEP_BEHIND: u64 = 256 << 10;
+// A seek this far past the downloaded data waits for it instead of issuing a
+// new request.
+const SKIP_AHEAD: u64 = 512 << 10;
+const CHUNK_BYTES: usize = 64 << 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether `file_path` names a stream rather than a local file.
+pub fn is_url(file_path: &str) -> bool {
+    let lower = file_path.get(..8).unwrap_or(file_path).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Extension of the file a URL points to, for the format probe.
+pub fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next()?;
+    let name = path.rsplit('/').next()?;
+    name.rsplit_once('.').map(|(_, extension)| extension)
+}
+
+/// Shared between the worker decoding a stream and the track playing it.
+#[derive(Default)]
+pub struct StreamStatus {
+    /// The output ran out of decoded audio and plays silence.
+    buffering: AtomicBool,
+    /// Why the stream stopped before its end.
+    error: Mutex<Option<String>>,
+}
+
+impl StreamStatus {
+    pub fn is_buffering(&self) -> bool {
+        self.buffering.load(Ordering::Relaxed)
+    }
+
+    pub fn set_buffering(&self, buffering: bool) {
+        self.buffering.store(buffering, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().ok().and_then(|error| error.clone())
+    }
+
+    pub fn set_error(&self, error: String) {
+        if let Ok(mut slot) = self.error.lock() {
+            *slot = Some(error);
+        }
+    }
+}
+
+/// Downloaded bytes and the requests between the reader and the fetcher.
+struct Window {
+    // Offset of `data[0]` in the file.
+    start: u64,
+    data: VecDeque<u8>,
+    // Where the decoder reads, which the fetcher stays `READ_AHEAD` ahead of.
+    read_position: u64,
+    // Bumped when the reader asks the fetcher to restart at `start`.
+    generation: u64,
+    finished: bool,
+    error: Option<String>,
+    closed: bool,
+}
+
+impl Window {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+struct Shared {
+    window: Mutex<Window>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> io::Result<MutexGuard<'_, Window>> {
+        self.window
+            .lock()
+            .map_err(|e| io::Error::other(format!("Mutex lock error: {}", e)))
+    }
+}
+
+/// Seekable reader over an HTTP(S) resource. Reads block until the data is
+/// downloaded, so it must not be read from the audio thread.
+pub struct HttpReader {
+    shared: Arc<Shared>,
+    position: u64,
+    length: Option<u64>,
+    seekable: bool,
+}
+
+impl HttpReader {
+    /// Connects to `url` and starts downloading. Servers without range
+    /// support still play, but can't seek.
+    pub fn open(url: &str) -> Result<Self, String> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .build();
+        let response = request(&agent, url, 0)?;
+
+        let (length, seekable) = if response.status() == 206 {
+            let total = response
+                .header("Content-Range")
+                .and_then(|range| range.rsplit_once('/'))
+                .and_then(|(_, total)| total.trim().parse().ok());
+            (total, true)
+        } else {
+            let length = response
+                .header("Content-Length")
+                .and_then(|length| length.trim().parse().ok());
+            let ranges = response
+                .header("Accept-Ranges")
+                .is_some_and(|ranges| ranges.eq_ignore_ascii_case("bytes"));
+            (length, ranges)
+        };
+
+        let shared = Arc::new(Shared {
+            window: Mutex::new(Window {
+                start: 0,
+                data: VecDeque::new(),
+                read_position: 0,
+                generation: 0,
+                finished: false,
+                error: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let fetcher = Fetcher {
+            agent,
+            url: url.to_string(),
+            shared: shared.clone(),
+        };
+        thread::spawn(move || fetcher.run(response));
+
+        Ok(Self {
+            shared,
+            position: 0,
+            length,
+            seekable,
+        })
+    }
+}
+
+impl Drop for HttpReader {
+    fn drop(&mut self) {
+        if let Ok(mut window) = self.shared.window.lock() {
+            window.closed = true;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.length.is_some_and(|length| self.position >= length) {
+            return Ok(0);
+        }
+
+        let mut window = self.shared.lock()?;
+        window.read_position = self.position;
+        let outside = self.position < window.start || self.position > window.end() + SKIP_AHEAD;
+        if outside && self.seekable {
+            window.start = self.position;
+            window.data.clear();
+            window.finished = false;
+            window.error = None;
+            window.generation += 1;
+            self.shared.changed.notify_all();
+        }
+
+        loop {
+            if self.position >= window.start && self.position < window.end() {
+                break;
+            }
+            // What was downloaded before an error still plays.
+            if let Some(error) = &window.error {
+                return Err(io::Error::other(error.clone()));
+            }
+            if window.finished && self.position >= window.end() {
+                return Ok(0);
+            }
+            if self.position < window.start {
+                return Err(io::Error::other("HTTP stream error: server doesn't support seeking"));
+            }
+            window = self
+                .shared
+                .changed
+                .wait(window)
+                .map_err(|e| io::Error::other(format!("Mutex lock error: {}", e)))?;
+        }
+
+        let offset = (self.position - window.start) as usize;
+        let (front, back) = window.data.as_slices();
+        let available = if offset < front.len() {
+            &front[offset..]
+        } else {
+            &back[offset - front.len()..]
+        };
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read as u64;
+
+        // Drop what lies well behind the decoder and wake the fetcher.
+        window.read_position = self.position;
+        let behind = self.position.saturating_sub(window.start);
+        if behind > KEEP_BEHIND {
+            let drop = (behind - KEEP_BEHIND) as usize;
+            window.data.drain(..drop);
+            window.start += drop as u64;
+        }
+        self.shared.changed.notify_all();
+        Ok(read)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let position = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self
+                .length
+                .ok_or_else(|| io::Error::other("HTTP stream error: length unknown"))?
+                .checked_add_signed(delta),
+        };
+        self.position =
+            position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for HttpReader {
+    fn is_seekable(&self) -> bool {
+        self.seekable && self.length.is_some()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+fn request(agent: &ureq::Agent, url: &str, offset: u64) -> Result<ureq::Response, String> {
+    agent
+        .get(url)
+        .set("Range", &format!("bytes={}-", offset))
+        // Byte offsets have to match the file, not a compressed body.
+        .set("Accept-Encoding", "identity")
+        .call()
+        .map_err(|e| format!("HTTP stream error: {}", e))
+}
+
+struct Fetcher {
+    agent: ureq::Agent,
+    url: String,
+    shared: Arc<Shared>,
+}
+
+impl Fetcher {
+    /// Downloads into the window, starting over wherever the reader asks.
+    fn run(self, first: ureq::Response) {
+        let mut first = Some(first);
+        loop {
+            let (offset, generation) = match self.shared.window.lock() {
+                Ok(window) if !window.closed => (window.start, window.generation),
+                _ => return,
+            };
+            // The response `open` got starts at 0 and is only of use there.
+            let response = first.take().filter(|_| offset == 0);
+            let body = match response.map_or_else(|| request(&self.agent, &self.url, offset), Ok) {
+                Ok(response) => {
+                    // A server ignoring the range sends the file from the top.
+                    let skip = if response.status() == 206 { 0 } else { offset };
+                    let mut body = response.into_reader();
+                    match io::copy(&mut body.by_ref().take(skip), &mut io::sink()) {
+                        Ok(_) => Ok(body),
+                        Err(e) => Err(format!("HTTP stream error: {}", e)),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            let outcome = match body {
+                Ok(body) => self.fill(body, generation),
+                Err(e) => Err(e),
+            };
+            let Ok(mut window) = self.shared.window.lock() else {
+                return;
+            };
+            if window.generation == generation {
+                match outcome {
+                    Ok(()) => window.finished = true,
+                    Err(e) => window.error = Some(e),
+                }
+                self.shared.changed.notify_all();
+                // Idle until the reader seeks elsewhere or goes away.
+                while window.generation == generation && !window.closed {
+                    window = match self.shared.changed.wait(window) {
+                        Ok(window) => window,
+                        Err(_) => return,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Copies the body into the window until it ends or the reader restarts
+    /// the download.
+    fn fill(&self, mut body: impl Read, generation: u64) -> Result<(), String> {
+        let mut chunk = vec![0; CHUNK_BYTES];
+        loop {
+            {
+                let mut window = self.shared.lock().map_err(|e| e.to_string())?;
+                while window.generation == generation
+                    && !window.closed
+                    && window.end() >= window.read_position + READ_AHEAD
+                {
+                    window = self
+                        .shared
+                        .changed
+                        .wait(window)
+                        .map_err(|e| format!("Mutex lock error: {}", e))?;
+                }
+                if window.generation != generation || window.closed {
+                    return Ok(());
+                }
+            }
+
+            let read = body
+                .read(&mut chunk)
+                .map_err(|e| format!("HTTP stream error: {}", e))?;
+            if read == 0 {
+                return Ok(());
+            }
+
+            let mut window = self.shared.lock().map_err(|e| e.to_string())?;
+            if window.generation != generation {
+                return Ok(());
+            }
+            window.data.extend(&chunk[..read]);
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decoder::SymphoniaDecoder,
+        preload::{Preload, PreloadSettings, PreloadStatus},
+    };
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        time::Instant,
+    };
+
+    /// Serves `body` with range support on a local port. With `cut`, every
+    /// response stops after that many bytes and holds the connection for the
+    /// given time before dropping it. Returns the URL and the requested offsets.
+    fn serve(body: Vec<u8>, cut: Option<(usize, Duration)>) -> (String, Arc<Mutex<Vec<u64>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/track.wav", listener.local_addr().unwrap());
+        let offsets = Arc::new(Mutex::new(Vec::new()));
+        let seen = offsets.clone();
+        let body = Arc::new(body);
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let Ok(mut connection) = connection else {
+                    return;
+                };
+                let (body, seen) = (body.clone(), seen.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(connection.try_clone().unwrap());
+                    let mut offset = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let line = line.trim_end().to_ascii_lowercase();
+                        if line.is_empty() {
+                            break;
+                        }
+                        if let Some(range) = line.strip_prefix("range: bytes=") {
+                            offset = range.trim_end_matches('-').parse().unwrap();
+                        }
+                    }
+                    seen.lock().unwrap().push(offset);
+
+                    let part = &body[offset as usize..];
+                    let header = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                        part.len(),
+                        offset,
+                        body.len() - 1,
+                        body.len()
+                    );
+                    let _ = connection.write_all(header.as_bytes());
+                    match cut {
+                        Some((length, hold)) => {
+                            let _ = connection.write_all(&part[..length.min(part.len())]);
+                            thread::sleep(hold);
+                        }
+                        None => {
+                            let _ = connection.write_all(part);
+                        }
+                    }
+                });
+            }
+        });
+        (url, offsets)
+    }
+
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn sample(index: usize) -> i16 {
+        ((index * 37) % 2000) as i16 - 1000
+    }
+
+    /// Stereo 16-bit WAV at 8 kHz.
+    fn wav(frames: usize) -> Vec<u8> {
+        let data_len = (frames * 4) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(8000u32.to_le_bytes());
+        out.extend(32000u32.to_le_bytes());
+        out.extend(4u16.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for index in 0..frames * 2 {
+            out.extend(sample(index).to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn seeking_far_requests_only_the_needed_range() {
+        let body = pattern(6 << 20);
+        let (url, offsets) = serve(body.clone(), None);
+        let mut reader = HttpReader::open(&url).unwrap();
+        assert!(reader.is_seekable());
+        assert_eq!(reader.byte_len(), Some(body.len() as u64));
+
+        let mut start = vec![0; 1000];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(start, body[..1000]);
+
+        let target = 5 << 20;
+        reader.seek(SeekFrom::Start(target as u64)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, body[target..]);
+        assert_eq!(*offsets.lock().unwrap(), vec![0, target as u64]);
+    }
+
+    #[test]
+    fn dropped_connection_is_an_error() {
+        let (url, _) = serve(pattern(1 << 20), Some((100_000, Duration::ZERO)));
+        let mut reader = HttpReader::open(&url).unwrap();
+        let mut body = Vec::new();
+        assert!(reader.read_to_end(&mut body).is_err());
+    }
+
+    #[test]
+    fn decodes_a_stream() {
+        let frames = 8000;
+        let (url, _) = serve(wav(frames), None);
+        let mut decoder = SymphoniaDecoder::open(&url).unwrap();
+        assert!(decoder.stream_status().is_some());
+
+        let samples: Vec<f32> = decoder.by_ref().collect();
+        assert_eq!(samples.len(), frames * 2);
+        for (index, value) in samples.iter().enumerate() {
+            assert!((value - sample(index) as f32 / 32768.0).abs() < 1e-4);
+        }
+        assert!(decoder.error().is_none());
+    }
+
+    #[test]
+    fn truncated_stream_reports_an_error() {
+        let (url, _) = serve(wav(8000), Some((10_000, Duration::ZERO)));
+        let mut decoder = SymphoniaDecoder::open(&url).unwrap();
+        let decoded = decoder.by_ref().count();
+        assert!(decoded < 8000 * 2);
+        assert!(decoder.error().is_some());
+    }
+
+    #[test]
+    fn stream_error_reaches_the_status() {
+        let (url, _) = serve(wav(8000), Some((10_000, Duration::ZERO)));
+        let preload = Preload::start(1, url, &PreloadSettings::default());
+        let opened = Instant::now();
+        let mut decoder = loop {
+            if let Some(decoder) = preload.claim() {
+                break decoder;
+            }
+            assert!(opened.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(5));
+        };
+        let status = decoder.stream_status().unwrap();
+
+        let started = Instant::now();
+        while decoder.next().is_some() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+        assert!(status.error().is_some());
+    }
+
+    #[test]
+    fn stalled_stream_plays_silence_without_blocking() {
+        let (url, _) = serve(wav(8000), Some((10_000, Duration::from_secs(3))));
+        let preload = Preload::start(1, url, &PreloadSettings::default());
+        let opened = Instant::now();
+        while preload.status() == PreloadStatus::Opening {
+            assert!(opened.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut decoder = preload.claim().unwrap();
+        let status = decoder.stream_status().unwrap();
+
+        // More than the server sent before stalling.
+        let pulled = Instant::now();
+        let samples: Vec<f32> = decoder.by_ref().take(40_000).collect();
+        assert_eq!(samples.len(), 40_000);
+        assert!(pulled.elapsed() < Duration::from_secs(1));
+        assert!(status.is_buffering());
+        assert_eq!(samples.last(), Some(&0.0));
+    }
+}
@@ -0,0 +1,107 @@
+use lofty::{Accessor, AudioFile, ItemKey, Probe, Tag, TaggedFileExt};
+use std::time::Duration;
+
+use crate::cue;
+
+/// Tag fields the engine uses to reason about what plays next.
+#[derive(Clone, Default)]
+pub struct TrackTags {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub duration: Option<Duration>,
+    pub replay_gain: ReplayGainTags,
+}
+
+/// `REPLAYGAIN_*` values; gains in dB, peaks as linear sample amplitude.
+#[derive(Clone, Copy, Default)]
+pub struct ReplayGainTags {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl TrackTags {
+    /// Reads the tags of `file_path`; unreadable files yield empty tags. The
+    /// tracks of a cue sheet take their artist, album and number from it.
+    pub fn read(file_path: &str) -> Self {
+        let (base, range) = cue::split_range(file_path);
+        let Some(tagged_file) = Probe::open(base).ok().and_then(|probe| probe.read().ok()) else {
+            return Self::default();
+        };
+        let duration = Some(tagged_file.properties().duration());
+        let tag = tagged_file.primary_tag().or_else(|| tagged_file.first_tag());
+        let mut tags = match tag {
+            Some(tag) => Self {
+                artist: tag.artist().map(|s| s.to_string()),
+                album: tag.album().map(|s| s.to_string()),
+                album_artist: tag.get_string(&ItemKey::AlbumArtist).map(|s| s.to_string()),
+                track: tag.track(),
+                disc: tag.disk(),
+                duration,
+                replay_gain: ReplayGainTags {
+                    track_gain: parse_number(tag.get_string(&ItemKey::ReplayGainTrackGain)),
+                    track_peak: parse_number(tag.get_string(&ItemKey::ReplayGainTrackPeak)),
+                    album_gain: parse_number(tag.get_string(&ItemKey::ReplayGainAlbumGain)),
+                    album_peak: parse_number(tag.get_string(&ItemKey::ReplayGainAlbumPeak)),
+                },
+            },
+            None => Self {
+                duration,
+                ..Self::default()
+            },
+        };
+
+        if let Some(range) = range {
+            tags.duration = range.length(tags.duration);
+            let virtual_track = cue::tracks_for(base, tag.and_then(embedded_cue_sheet))
+                .into_iter()
+                .find(|track| track.file_path == file_path);
+            if let Some(track) = virtual_track {
+                tags.artist = track.performer.or(tags.artist);
+                tags.album = track.album.or(tags.album);
+                tags.track = Some(track.number);
+            }
+        }
+        tags
+    }
+
+    /// Whether `next` is the track that directly follows this one on the same
+    /// album, including the jump from the last track of a disc to the next disc.
+    pub fn precedes_on_album(&self, next: &TrackTags) -> bool {
+        let same_album = self.album.is_some() && self.album == next.album;
+        let same_artist = match (&self.album_artist, &next.album_artist) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        if !same_album || !same_artist {
+            return false;
+        }
+
+        let (Some(track), Some(next_track)) = (self.track, next.track) else {
+            return false;
+        };
+        let disc = self.disc.unwrap_or(1);
+        let next_disc = next.disc.unwrap_or(1);
+
+        (next_disc == disc && next_track == track + 1) || (next_disc == disc + 1 && next_track == 1)
+    }
+}
+
+/// Text of a cue sheet stored in the file's tags, as FLAC and APE images do.
+pub fn embedded_cue_sheet(tag: &Tag) -> Option<&str> {
+    tag.get_string(&ItemKey::Unknown("CUESHEET".to_string()))
+}
+
+/// Parses values such as `-6.54 dB` or `0.988525`.
+fn parse_number(value: Option<&str>) -> Option<f32> {
+    let value = value?.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().parse().ok()
+}
@@ -0,0 +1,634 @@
+use rodio::{Sample, Source};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{crossfade::FadeCurve, decoder::SourceFormat, stream::StreamStatus};
+
+static NEXT_TRACK_ID: AtomicU64 = AtomicU64::new(1);
+
+// Sentinel for the frame/time atomics in `TrackControl` meaning "nothing requested".
+const UNSET: u64 = u64::MAX;
+
+/// Sources that can jump to a position in place, without being rebuilt.
+pub trait Seekable {
+    /// Returns the position actually reached.
+    fn seek(&mut self, position: Duration) -> Result<Duration, String>;
+}
+
+/// Notifications sent from the audio thread about tracks appended to the sink.
+pub enum TrackEvent {
+    /// The first sample of the track has been pulled by the output.
+    Started { id: u64 },
+    /// The decoder ran out of samples.
+    Finished { id: u64 },
+    /// Playback reached the point armed with `TrackControl::arm_tail`.
+    Tail { id: u64 },
+}
+
+/// Handle shared between a `TrackSource` living inside the sink and `AudioState`.
+pub struct TrackControl {
+    id: u64,
+    cancelled: AtomicBool,
+    frames_played: AtomicU64,
+    sample_rate: AtomicU32,
+    duration: Option<Duration>,
+    source_format: SourceFormat,
+    stream: Option<Arc<StreamStatus>>,
+    // Target position in microseconds, picked up by the audio thread.
+    seek_request: AtomicU64,
+    // Number of seeks applied so far, so later stages can drop stale buffers.
+    seeks: AtomicU64,
+    // Frame at which a `TrackEvent::Tail` is sent.
+    tail_at: AtomicU64,
+    // A-B loop in frames; playback jumps back to the start on reaching the end.
+    loop_start: AtomicU64,
+    loop_end: AtomicU64,
+    // Length in frames of a requested fade-out, and its curve.
+    fade_out_frames: AtomicU64,
+    fade_out_curve: AtomicU8,
+    // Whether the declick stage lets the track through or ramps it to silence.
+    gate_open: AtomicBool,
+    // Seek in microseconds that waits for the declick stage to ramp down.
+    smooth_seek: AtomicU64,
+}
+
+impl TrackControl {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Elapsed time measured from the samples handed to the output.
+    pub fn position(&self) -> Duration {
+        let rate = self.sample_rate.load(Ordering::Relaxed).max(1);
+        let frames = self.frames_played.load(Ordering::Relaxed);
+        Duration::from_secs_f64(frames as f64 / rate as f64)
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Format of the file, as decoded.
+    pub fn source_format(&self) -> &SourceFormat {
+        &self.source_format
+    }
+
+    /// Whether the track is streamed and waiting for its download.
+    pub fn is_buffering(&self) -> bool {
+        self.stream.as_ref().is_some_and(|stream| stream.is_buffering())
+    }
+
+    /// Why a streamed track stopped before its end.
+    pub fn stream_error(&self) -> Option<String> {
+        self.stream.as_ref().and_then(|stream| stream.error())
+    }
+
+    /// Asks the source to seek the next time the output pulls a sample. The
+    /// reported position jumps right away so progress events don't lag.
+    pub fn request_seek(&self, position: Duration) {
+        let rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        self.frames_played
+            .store((position.as_secs_f64() * rate) as u64, Ordering::Relaxed);
+        self.seek_request
+            .store(position.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Seeks behind a short ramp down and up, so the jump doesn't click.
+    pub fn seek_smoothly(&self, position: Duration) {
+        let rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        self.frames_played
+            .store((position.as_secs_f64() * rate) as u64, Ordering::Relaxed);
+        self.smooth_seek
+            .store(position.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Takes the seek left by `seek_smoothly`, if any.
+    pub(crate) fn take_smooth_seek(&self) -> Option<Duration> {
+        let micros = self.smooth_seek.swap(UNSET, Ordering::SeqCst);
+        (micros != UNSET).then(|| Duration::from_micros(micros))
+    }
+
+    pub(crate) fn has_smooth_seek(&self) -> bool {
+        self.smooth_seek.load(Ordering::Relaxed) != UNSET
+    }
+
+    /// Ramps the track down to silence and holds it there, or brings it back.
+    pub fn set_gate(&self, open: bool) {
+        self.gate_open.store(open, Ordering::SeqCst);
+    }
+
+    pub fn is_gate_open(&self) -> bool {
+        self.gate_open.load(Ordering::Relaxed)
+    }
+
+    pub fn seek_count(&self) -> u64 {
+        self.seeks.load(Ordering::SeqCst)
+    }
+
+    /// Arms a `TrackEvent::Tail` for when `lead`, cut by `tail_lead`, is left
+    /// before the end of the track. Returns `false` if the length of the track
+    /// isn't known.
+    pub fn arm_tail(&self, lead: Duration) -> bool {
+        let Some(duration) = self.duration else {
+            return false;
+        };
+        let rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        let at = duration.saturating_sub(self.tail_lead(lead)).as_secs_f64() * rate;
+        self.tail_at.store(at as u64, Ordering::SeqCst);
+        true
+    }
+
+    /// `lead` cut to half the track, so a track shorter than a crossfade
+    /// still plays on its own before it fades.
+    pub fn tail_lead(&self, lead: Duration) -> Duration {
+        self.duration.map_or(lead, |duration| lead.min(duration / 2))
+    }
+
+    pub fn disarm_tail(&self) {
+        self.tail_at.store(UNSET, Ordering::SeqCst);
+    }
+
+    /// Repeats the section between `start` and `end` until cleared. The end
+    /// is cleared first so the audio thread never sees a half-written loop.
+    pub fn set_loop(&self, start: Duration, end: Duration) {
+        let rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        self.loop_end.store(UNSET, Ordering::SeqCst);
+        self.loop_start
+            .store((start.as_secs_f64() * rate) as u64, Ordering::SeqCst);
+        self.loop_end
+            .store((end.as_secs_f64() * rate) as u64, Ordering::SeqCst);
+    }
+
+    pub fn clear_loop(&self) {
+        self.loop_end.store(UNSET, Ordering::SeqCst);
+    }
+
+    pub fn loop_region(&self) -> Option<(Duration, Duration)> {
+        let end = self.loop_end.load(Ordering::SeqCst);
+        if end == UNSET {
+            return None;
+        }
+        let rate = self.sample_rate.load(Ordering::Relaxed).max(1) as f64;
+        let start = self.loop_start.load(Ordering::SeqCst);
+        Some((
+            Duration::from_secs_f64(start as f64 / rate),
+            Duration::from_secs_f64(end as f64 / rate),
+        ))
+    }
+
+    /// Fades the track out from wherever it currently is.
+    pub fn fade_out(&self, length: Duration, curve: FadeCurve) {
+        let rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        let frames = (length.as_secs_f64() * rate).max(1.0) as u64;
+        self.fade_out_curve.store(curve.to_u8(), Ordering::SeqCst);
+        self.fade_out_frames.store(frames, Ordering::SeqCst);
+    }
+
+    /// Makes the track end immediately without ever reporting that it started.
+    /// Used to drop a preloaded track that is no longer next in the queue.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a decoded track so `AudioState` can follow it once it is queued in the sink.
+pub struct TrackSource<S> {
+    inner: S,
+    control: Arc<TrackControl>,
+    events: Sender<TrackEvent>,
+    started: bool,
+    finished: bool,
+    frames: u64,
+    sample_in_frame: u16,
+    fade: Option<Fade>,
+    gain: f32,
+}
+
+struct Fade {
+    start: u64,
+    length: u64,
+    curve: FadeCurve,
+    fading_in: bool,
+}
+
+impl Fade {
+    fn gain_at(&self, frame: u64) -> f32 {
+        let progress = frame.saturating_sub(self.start) as f32 / self.length as f32;
+        if self.fading_in {
+            self.curve.fade_in_gain(progress)
+        } else {
+            self.curve.fade_out_gain(progress)
+        }
+    }
+}
+
+impl<S: Source> TrackSource<S>
+where
+    S::Item: Sample,
+{
+    pub fn new(
+        inner: S,
+        events: Sender<TrackEvent>,
+        duration: Option<Duration>,
+        source_format: SourceFormat,
+        stream: Option<Arc<StreamStatus>>,
+    ) -> Self {
+        let control = Arc::new(TrackControl {
+            id: NEXT_TRACK_ID.fetch_add(1, Ordering::Relaxed),
+            cancelled: AtomicBool::new(false),
+            frames_played: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(inner.sample_rate()),
+            duration,
+            source_format,
+            stream,
+            seek_request: AtomicU64::new(UNSET),
+            seeks: AtomicU64::new(0),
+            tail_at: AtomicU64::new(UNSET),
+            loop_start: AtomicU64::new(UNSET),
+            loop_end: AtomicU64::new(UNSET),
+            fade_out_frames: AtomicU64::new(UNSET),
+            fade_out_curve: AtomicU8::new(0),
+            gate_open: AtomicBool::new(true),
+            smooth_seek: AtomicU64::new(UNSET),
+        });
+
+        Self {
+            inner,
+            control,
+            events,
+            started: false,
+            finished: false,
+            frames: 0,
+            sample_in_frame: 0,
+            fade: None,
+            gain: 1.0,
+        }
+    }
+
+    /// Starts the track silent and brings it up over `length`.
+    pub fn with_fade_in(mut self, length: Duration, curve: FadeCurve) -> Self {
+        let frames = (length.as_secs_f64() * self.inner.sample_rate() as f64).max(1.0) as u64;
+        self.fade = Some(Fade {
+            start: 0,
+            length: frames,
+            curve,
+            fading_in: true,
+        });
+        self.gain = 0.0;
+        self
+    }
+
+    pub fn control(&self) -> Arc<TrackControl> {
+        self.control.clone()
+    }
+}
+
+impl<S> TrackSource<S>
+where
+    S: Source + Seekable,
+    S::Item: Sample,
+{
+    /// Moves the decoder to `position`; returns whether it got there.
+    fn jump(&mut self, position: Duration) -> bool {
+        let Ok(reached) = self.inner.seek(position) else {
+            return false;
+        };
+        self.frames = (reached.as_secs_f64() * self.inner.sample_rate() as f64) as u64;
+        self.sample_in_frame = 0;
+        self.control.frames_played.store(self.frames, Ordering::Relaxed);
+        true
+    }
+
+    fn apply_seek(&mut self, micros: u64) {
+        if self.jump(Duration::from_micros(micros)) {
+            self.control.seeks.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Jumps back to the loop start once the loop end is reached. Unlike a
+    /// seek this doesn't count as a discontinuity for later stages, so the
+    /// loop stays seamless through them.
+    fn apply_loop(&mut self) -> bool {
+        let end = self.control.loop_end.load(Ordering::Relaxed);
+        if end == UNSET {
+            return false;
+        }
+        if self.frames >= end {
+            let start = self.control.loop_start.load(Ordering::Relaxed);
+            let rate = self.inner.sample_rate().max(1) as f64;
+            if !self.jump(Duration::from_secs_f64(start as f64 / rate)) {
+                self.control.clear_loop();
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Picks up requests from `TrackControl`; runs between frames.
+    fn poll_control(&mut self) {
+        let seek = self.control.seek_request.swap(UNSET, Ordering::SeqCst);
+        if seek != UNSET {
+            self.apply_seek(seek);
+        }
+
+        let fade_out = self.control.fade_out_frames.swap(UNSET, Ordering::SeqCst);
+        if fade_out != UNSET {
+            self.fade = Some(Fade {
+                start: self.frames,
+                length: fade_out,
+                curve: FadeCurve::from_u8(self.control.fade_out_curve.load(Ordering::SeqCst)),
+                fading_in: false,
+            });
+        }
+
+        // A looping track never reaches its tail.
+        let looping = self.apply_loop();
+        if !looping
+            && self.frames >= self.control.tail_at.load(Ordering::Relaxed)
+            && self.control.tail_at.swap(UNSET, Ordering::SeqCst) != UNSET
+        {
+            let _ = self.events.send(TrackEvent::Tail {
+                id: self.control.id,
+            });
+        }
+
+        if let Some(fade) = &self.fade {
+            self.gain = fade.gain_at(self.frames);
+            if fade.fading_in && self.frames >= fade.start + fade.length {
+                self.fade = None;
+                self.gain = 1.0;
+            }
+        }
+    }
+}
+
+impl<S> Iterator for TrackSource<S>
+where
+    S: Source + Seekable,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.control.is_cancelled() {
+            return None;
+        }
+
+        // Only act between frames so the channels stay aligned.
+        if self.sample_in_frame == 0 {
+            self.poll_control();
+        }
+
+        if !self.started {
+            self.started = true;
+            let _ = self.events.send(TrackEvent::Started {
+                id: self.control.id,
+            });
+        }
+
+        let Some(sample) = self.inner.next() else {
+            if !self.finished {
+                self.finished = true;
+                let _ = self.events.send(TrackEvent::Finished {
+                    id: self.control.id,
+                });
+            }
+            return None;
+        };
+
+        self.sample_in_frame += 1;
+        if self.sample_in_frame >= self.inner.channels() {
+            self.sample_in_frame = 0;
+            self.frames += 1;
+            self.control.frames_played.store(self.frames, Ordering::Relaxed);
+            self.control
+                .sample_rate
+                .store(self.inner.sample_rate(), Ordering::Relaxed);
+        }
+
+        if self.gain == 1.0 {
+            Some(sample)
+        } else {
+            Some(sample.amplify(self.gain))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for TrackSource<S>
+where
+    S: Source + Seekable,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.control.is_cancelled() {
+            return Some(0);
+        }
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+
+    const RATE: u32 = 100;
+
+    /// Mono source counting up a frame at a time, seeking to whole frames.
+    struct Ramp {
+        len: usize,
+        position: usize,
+    }
+
+    impl Iterator for Ramp {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            let sample = (self.position < self.len).then_some(self.position as f32);
+            self.position = (self.position + 1).min(self.len);
+            sample
+        }
+    }
+
+    impl Source for Ramp {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    impl Seekable for Ramp {
+        fn seek(&mut self, position: Duration) -> Result<Duration, String> {
+            self.position = ((position.as_secs_f64() * RATE as f64).round() as usize).min(self.len);
+            Ok(Duration::from_secs_f64(self.position as f64 / RATE as f64))
+        }
+    }
+
+    /// A track of `frames` frames and the events it sends.
+    fn track(frames: usize) -> (TrackSource<Ramp>, Receiver<TrackEvent>) {
+        let (events, received) = mpsc::channel();
+        let format = SourceFormat {
+            codec: "pcm".to_string(),
+            sample_rate: RATE,
+            bits_per_sample: Some(16),
+            channels: 1,
+        };
+        let duration = Duration::from_secs_f64(frames as f64 / RATE as f64);
+        let source = TrackSource::new(
+            Ramp {
+                len: frames,
+                position: 0,
+            },
+            events,
+            Some(duration),
+            format,
+            None,
+        );
+        (source, received)
+    }
+
+    /// Names of the events sent so far.
+    fn events(received: &Receiver<TrackEvent>) -> Vec<&'static str> {
+        received
+            .try_iter()
+            .map(|event| match event {
+                TrackEvent::Started { .. } => "started",
+                TrackEvent::Finished { .. } => "finished",
+                TrackEvent::Tail { .. } => "tail",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn start_and_end_are_reported_once() {
+        let (mut source, received) = track(10);
+
+        assert_eq!(source.by_ref().count(), 10);
+        assert_eq!(source.next(), None);
+
+        assert_eq!(events(&received), ["started", "finished"]);
+        assert_eq!(source.control().position(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn cancelled_tracks_end_without_starting() {
+        let (mut source, received) = track(10);
+        source.control().cancel();
+
+        assert_eq!(source.current_frame_len(), Some(0));
+        assert_eq!(source.next(), None);
+        assert!(events(&received).is_empty());
+    }
+
+    #[test]
+    fn loop_repeats_its_region_until_cleared() {
+        let (mut source, _received) = track(30);
+        let control = source.control();
+        control.set_loop(Duration::from_millis(100), Duration::from_millis(200));
+
+        let looped: Vec<f32> = source.by_ref().take(25).collect();
+        assert_eq!(looped[..20], (0..20).map(|frame| frame as f32).collect::<Vec<_>>());
+        assert_eq!(looped[20..], [10.0, 11.0, 12.0, 13.0, 14.0]);
+        assert_eq!(
+            control.loop_region(),
+            Some((Duration::from_millis(100), Duration::from_millis(200)))
+        );
+
+        control.clear_loop();
+        assert_eq!(source.count(), 15);
+        assert_eq!(control.loop_region(), None);
+    }
+
+    #[test]
+    fn looping_tracks_never_reach_their_tail() {
+        let (mut source, received) = track(30);
+        let control = source.control();
+        control.set_loop(Duration::ZERO, Duration::from_millis(200));
+        assert!(control.arm_tail(Duration::from_millis(150)));
+
+        source.by_ref().take(100).for_each(drop);
+
+        assert_eq!(events(&received), ["started"]);
+        // Loops don't count as seeks, so later stages keep their buffers.
+        assert_eq!(control.seek_count(), 0);
+    }
+
+    #[test]
+    fn tail_is_sent_once_the_lead_is_left() {
+        let (mut source, received) = track(100);
+        assert!(source.control().arm_tail(Duration::from_millis(300)));
+
+        source.by_ref().take(70).for_each(drop);
+        assert_eq!(events(&received), ["started"]);
+        source.next();
+        assert_eq!(events(&received), ["tail"]);
+        source.by_ref().for_each(drop);
+        assert_eq!(events(&received), ["finished"]);
+    }
+
+    #[test]
+    fn tail_lead_is_cut_to_half_the_track() {
+        let (source, _received) = track(100);
+        let control = source.control();
+
+        assert_eq!(control.tail_lead(Duration::from_secs(6)), Duration::from_millis(500));
+        assert_eq!(control.tail_lead(Duration::from_millis(200)), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn fades_ramp_the_gain_along_their_curve() {
+        let (source, _received) = track(100);
+        let control = source.control();
+        let mut source = source.with_fade_in(Duration::from_millis(100), FadeCurve::Linear);
+
+        let faded_in: Vec<f32> = source.by_ref().take(20).collect();
+        // The ramp's samples count the frames, so each one over its frame is the gain.
+        let gains: Vec<f32> = (1..20).map(|frame| faded_in[frame] / frame as f32).collect();
+        assert_eq!(faded_in[0], 0.0);
+        assert!((gains[4] - 0.5).abs() < 1e-6, "{}", gains[4]);
+        assert!(gains[9..].iter().all(|&gain| gain == 1.0));
+
+        control.fade_out(Duration::from_millis(100), FadeCurve::Linear);
+        let faded_out: Vec<f32> = source.take(15).collect();
+        assert_eq!(faded_out[0], 20.0);
+        assert!((faded_out[5] / 25.0 - 0.5).abs() < 1e-6, "{}", faded_out[5]);
+        assert!(faded_out[10..].iter().all(|&sample| sample == 0.0));
+    }
+}
@@ -0,0 +1,318 @@
+use rodio::Source;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use crate::{cue, decoder::SymphoniaDecoder, storage};
+
+pub const MAX_BUCKETS: usize = 8192;
+// Length of the blocks measured while decoding, merged into buckets at the end
+// since the length of a file isn't always known up front.
+const BLOCKS_PER_SECOND: u32 = 200;
+// Samples decoded between two checks of the cancel flag.
+const CANCEL_CHECK_SAMPLES: usize = 1 << 14;
+
+/// Overview of a whole file, one value of each kind per bucket. Channels are
+/// combined: `min` and `max` are the extremes over all of them.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Waveform {
+    pub duration: f32,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    pub rms: Vec<f32>,
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    min: f32,
+    max: f32,
+    square_sum: f64,
+    samples: usize,
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self {
+            min: f32::MAX,
+            max: f32::MIN,
+            square_sum: 0.0,
+            samples: 0,
+        }
+    }
+}
+
+impl Block {
+    fn merge(&mut self, other: &Block) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.square_sum += other.square_sum;
+        self.samples += other.samples;
+    }
+}
+
+/// Waveforms stored in Brick's data directory under the SHA-256 of the file,
+/// like cover art, so renamed or duplicated files share one entry.
+#[derive(Default)]
+pub struct WaveformCache {
+    // Content hashes by path, with the modification time and size they are
+    // valid for, so unchanged files aren't read twice.
+    hashes: Mutex<HashMap<String, (u64, u64, String)>>,
+}
+
+impl WaveformCache {
+    /// The waveform of `file_path` with `buckets` buckets, from the cache or
+    /// decoded and then cached. Returns `Ok(None)` if cancelled.
+    pub fn get(
+        &self,
+        file_path: &str,
+        buckets: usize,
+        cancelled: &AtomicBool,
+    ) -> Result<Option<Waveform>, String> {
+        let buckets = buckets.clamp(1, MAX_BUCKETS);
+        // A track of a cue sheet is cached under its file and its range.
+        let (base, range) = cue::split_range(file_path);
+        let mut key = self.content_hash(base)?;
+        if let Some(range) = range {
+            key.push_str(&format!("-{}", range.start.as_millis()));
+            if let Some(end) = range.end {
+                key.push_str(&format!("-{}", end.as_millis()));
+            }
+        }
+        let path = cache_path(&key, buckets);
+
+        if let Some(path) = &path {
+            if let Ok(file) = File::open(path) {
+                match serde_json::from_reader(BufReader::new(file)) {
+                    Ok(waveform) => return Ok(Some(waveform)),
+                    // Unreadable, e.g. from an older version; decoded afresh below.
+                    Err(_) => {
+                        let _ = std::fs::remove_file(path);
+                    }
+                }
+            }
+        }
+
+        let Some(waveform) = compute(file_path, buckets, cancelled)? else {
+            return Ok(None);
+        };
+        // A waveform that can't be cached is only decoded again next time.
+        if let (Some(path), Ok(contents)) = (path, serde_json::to_vec(&waveform)) {
+            let _ = storage::write_atomic(&path, &contents);
+        }
+        Ok(Some(waveform))
+    }
+
+    fn content_hash(&self, file_path: &str) -> Result<String, String> {
+        let metadata = std::fs::metadata(file_path).map_err(|e| format!("File opening error: {}", e))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_secs());
+        let size = metadata.len();
+
+        if let Some((_, _, hash)) = self.hashes.lock().ok().and_then(|hashes| {
+            hashes
+                .get(file_path)
+                .filter(|(known_modified, known_size, _)| *known_modified == modified && *known_size == size)
+                .cloned()
+        }) {
+            return Ok(hash);
+        }
+
+        let mut file = File::open(file_path).map_err(|e| format!("File opening error: {}", e))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let read = file
+                .read(&mut buffer)
+                .map_err(|e| format!("File read error: {}", e))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let hash = format!("{:x}", hasher.finalize());
+
+        if let Ok(mut hashes) = self.hashes.lock() {
+            hashes.insert(file_path.to_string(), (modified, size, hash.clone()));
+        }
+        Ok(hash)
+    }
+}
+
+fn cache_path(key: &str, buckets: usize) -> Option<PathBuf> {
+    let mut waveforms_dir = dirs::data_dir()?;
+    waveforms_dir.push("waveforms");
+    std::fs::create_dir_all(&waveforms_dir).ok()?;
+    Some(waveforms_dir.join(format!("{key}-{buckets}.json")))
+}
+
+/// Decodes the whole file once. Returns `Ok(None)` if cancelled.
+fn compute(
+    file_path: &str,
+    buckets: usize,
+    cancelled: &AtomicBool,
+) -> Result<Option<Waveform>, String> {
+    let mut decoder = SymphoniaDecoder::open(file_path)?;
+    let channels = decoder.channels().max(1) as usize;
+    let sample_rate = decoder.sample_rate().max(1);
+    let block_samples = (sample_rate / BLOCKS_PER_SECOND).max(1) as usize * channels;
+
+    let mut blocks = Vec::new();
+    let mut block = Block::default();
+    let mut samples = 0usize;
+    for sample in decoder.by_ref() {
+        block.min = block.min.min(sample);
+        block.max = block.max.max(sample);
+        block.square_sum += (sample as f64) * (sample as f64);
+        block.samples += 1;
+        if block.samples == block_samples {
+            blocks.push(std::mem::take(&mut block));
+        }
+
+        samples += 1;
+        if samples.is_multiple_of(CANCEL_CHECK_SAMPLES) && cancelled.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+    }
+    if block.samples > 0 {
+        blocks.push(block);
+    }
+
+    let mut merged = vec![Block::default(); buckets];
+    for (index, block) in blocks.iter().enumerate() {
+        merged[index * buckets / blocks.len()].merge(block);
+    }
+
+    // Buckets left empty by a file shorter than the bucket count stay silent.
+    let level = |value: f32, block: &Block| if block.samples == 0 { 0.0 } else { value };
+    Ok(Some(Waveform {
+        duration: (samples / channels) as f32 / sample_rate as f32,
+        min: merged.iter().map(|block| level(block.min, block)).collect(),
+        max: merged.iter().map(|block| level(block.max, block)).collect(),
+        rms: merged
+            .iter()
+            .map(|block| level((block.square_sum / block.samples.max(1) as f64).sqrt() as f32, block))
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a mono 16-bit WAV file of `samples` at 48 kHz.
+    fn write_wav(name: &str, samples: &[i16]) -> String {
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = Vec::new();
+        out.extend(b"RIFF");
+        out.extend((36 + data_len).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(48_000u32.to_le_bytes());
+        out.extend(96_000u32.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(16u16.to_le_bytes());
+        out.extend(b"data");
+        out.extend(data_len.to_le_bytes());
+        for sample in samples {
+            out.extend(sample.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("brick-waveform-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Half a second at +0.5, then half a second of a ±0.25 square wave.
+    fn two_halves(name: &str) -> String {
+        let samples: Vec<i16> = (0..48_000)
+            .map(|index| match index {
+                0..24_000 => 16_384,
+                _ if index % 2 == 0 => 8192,
+                _ => -8192,
+            })
+            .collect();
+        write_wav(name, &samples)
+    }
+
+    #[test]
+    fn buckets_hold_the_extremes_and_rms_of_their_part() {
+        let file_path = two_halves("halves");
+
+        let waveform = compute(&file_path, 2, &AtomicBool::new(false)).unwrap().unwrap();
+
+        assert_eq!(waveform.duration, 1.0);
+        assert_eq!(waveform.min, [0.5, -0.25]);
+        assert_eq!(waveform.max, [0.5, 0.25]);
+        assert_eq!(waveform.rms, [0.5, 0.25]);
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn short_files_leave_the_other_buckets_silent() {
+        // Two blocks of 5 ms.
+        let file_path = write_wav("short", &[16_384; 480]);
+
+        let waveform = compute(&file_path, 8, &AtomicBool::new(false)).unwrap().unwrap();
+
+        assert_eq!(waveform.max, [0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0]);
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn cancelled_decoding_yields_nothing() {
+        let file_path = two_halves("cancelled");
+
+        assert!(compute(&file_path, 2, &AtomicBool::new(true)).unwrap().is_none());
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn copies_share_a_content_hash_until_changed() {
+        let cache = WaveformCache::default();
+        let file_path = write_wav("original", &[1, 2, 3]);
+        let copy = write_wav("copy", &[1, 2, 3]);
+        let hash = cache.content_hash(&file_path).unwrap();
+
+        assert_eq!(cache.content_hash(&copy).unwrap(), hash);
+        write_wav("copy", &[1, 2, 3, 4]);
+        assert_ne!(cache.content_hash(&copy).unwrap(), hash);
+        std::fs::remove_file(file_path).unwrap();
+        std::fs::remove_file(copy).unwrap();
+    }
+
+    #[test]
+    fn unreadable_cache_entries_are_decoded_again() {
+        let cache = WaveformCache::default();
+        let file_path = two_halves("cached");
+        let key = cache.content_hash(&file_path).unwrap();
+        let Some(cached) = cache_path(&key, 2) else {
+            // Without a data directory nothing is cached.
+            return;
+        };
+        std::fs::write(&cached, b"not json").unwrap();
+
+        let waveform = cache.get(&file_path, 2, &AtomicBool::new(false)).unwrap().unwrap();
+
+        assert_eq!(waveform.max, [0.5, 0.25]);
+        let stored: Waveform = serde_json::from_slice(&std::fs::read(&cached).unwrap()).unwrap();
+        assert_eq!(stored.rms, waveform.rms);
+        std::fs::remove_file(cached).unwrap();
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
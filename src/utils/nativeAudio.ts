@@ -3,13 +3,77 @@ import { listen, type UnlistenFn } from "@tauri-apps/api/event";
 
 type TauriAwareWindow = Window & { __TAURI__?: unknown };
 
-export type NativeAudioStatus = "playing" | "paused" | "stopped" | "seeking" | "volume";
+export type NativeAudioStatus =
+  | "playing"
+  | "paused"
+  | "stopped"
+  | "ended"
+  | "seeking"
+  | "volume"
+  | "playback-rate"
+  | "pitch"
+  | "loop"
+  | "progress-interval"
+  | "crossfade"
+  | "skipped"
+  | "error"
+  | "no-output";
+
+/** Active A-B loop of the current track, in seconds. */
+export interface NativeLoopRegion {
+  start: number;
+  end: number;
+}
+
+export interface NativeSourceFormat {
+  codec: string;
+  sampleRate: number;
+  /** Lossy codecs have none. */
+  bitsPerSample: number | null;
+  channels: number;
+}
+
+export interface NativeOutputFormat {
+  /** Headless outputs have none. */
+  device: string | null;
+  sampleRate: number;
+  channels: number;
+  sampleFormat: string;
+}
+
+/** What the current track is decoded as and what the output plays. */
+export interface NativePlaybackFormat {
+  source: NativeSourceFormat | null;
+  output: NativeOutputFormat | null;
+  resampling: boolean;
+  channelConversion: boolean;
+}
 
 export interface NativeAudioEventPayload {
   status: NativeAudioStatus;
-  filePath?: string;
-  position?: number;
-  volume?: number;
+  filePath: string | null;
+  position: number | null;
+  volume: number | null;
+  playbackRate: number;
+  pitchSemitones: number;
+  loopRegion: NativeLoopRegion | null;
+  format: NativePlaybackFormat | null;
+  /** What went wrong, with the `error`, `skipped` and `no-output` statuses. */
+  error: string | null;
+}
+
+/** A song found by `scanMusicFile`. Tracks of a cue sheet share their file. */
+export interface NativeSongMetadata {
+  title: string | null;
+  artist: string | null;
+  album: string | null;
+  duration: number;
+  /** Plays only the track's part of the file for tracks of a cue sheet. */
+  filePath: string;
+  coverArtPath: string | null;
+  /** Where a cue sheet track starts and ends in its file, in seconds. */
+  start: number | null;
+  end: number | null;
 }
 
 const hasTauriRuntime = () => typeof window !== "undefined" && Boolean((window as TauriAwareWindow).__TAURI__);
@@ -20,9 +84,9 @@ const requireRuntime = () => {
   }
 };
 
-export const playSong = async (filePath: string) => {
+export const playSong = async (filePath: string, upNext?: string[]) => {
   requireRuntime();
-  return invoke("playSong", { filePath });
+  return invoke("playSong", { filePath, upNext });
 };
 
 export const pauseSong = async () => {
@@ -50,6 +114,12 @@ export const seekTo = async (positionSeconds: number) => {
   return invoke("seekTo", { positionSeconds });
 };
 
+/** Reads a music file's tags. An album image with a cue sheet yields one song per track. */
+export const scanMusicFile = async (filePath: string) => {
+  requireRuntime();
+  return invoke<NativeSongMetadata[]>("scan_music_file", { filePath });
+};
+
 export const listenNativeAudioState = async (
   handler: (payload: NativeAudioEventPayload) => void,
 ): Promise<UnlistenFn> => {